@@ -1,5 +1,6 @@
 import React, {useState, useCallback} from 'react';
 import ImageResult from "./ImageResult";
+import { apiFetch } from "./apiKey";
 
 function App() {
   const [results, setResults] = useState({});
@@ -10,7 +11,7 @@ function App() {
   };
 
   const search = useCallback(async () => {
-      const res = await fetch(`https://localhost/near_text?text=${searchTerm}`);
+      const res = await apiFetch(`https://localhost/near_text?text=${searchTerm}`);
       if (res.ok) {
           const response_json = await res.json();
           setResults(response_json.ids);
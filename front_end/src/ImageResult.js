@@ -1,21 +1,60 @@
 import React from 'react';
 import { ReactComponent as DownloadIcon } from './save-file.svg';
+import { apiFetch } from './apiKey';
+
+// Prefers the UTF-8 `filename*` parameter (RFC 6266) over the plain `filename`
+function parseFilename(header) {
+    if (!header) {
+        return null;
+    }
+    const extended = header.match(/filename\*\s*=\s*([^']*)'[^']*'([^;]+)/i);
+    if (extended) {
+        try {
+            return decodeURIComponent(extended[2].trim());
+        } catch (e) {
+            // Fall back to the plain filename
+        }
+    }
+    const plain = header.match(/filename\s*=\s*("((?:\\.|[^"\\])*)"|[^;]+)/i);
+    if (plain) {
+        return plain[2] !== undefined ? plain[2].replace(/\\(.)/g, '$1') : plain[1].trim();
+    }
+    return null;
+}
 
 class ImageResult extends React.Component {
     constructor(props) {
         super(props);
+        this.state = {src: null};
     // This binding is necessary to make `this` work in the callback
         this.handleClick = this.handleClick.bind(this);
     }
 
+    // Images are fetched with the API key in a header and shown from object URLs, since keys in
+    // image URLs would end up in access logs and browser history
+    componentDidMount() {
+        const link = `https://localhost/images/${this.props.id}/rendition?mode=fit&width=1200&height=1200`;
+        apiFetch(link).then(async response => {
+            if (!response.ok || this.unmounted) {
+                return;
+            }
+            this.setState({src: window.URL.createObjectURL(await response.blob())});
+        });
+    }
+
+    componentWillUnmount() {
+        this.unmounted = true;
+        if (this.state.src) {
+            URL.revokeObjectURL(this.state.src);
+        }
+    }
+
     handleClick() {
         const link = `https://localhost/fetch_raw?id=${this.props.id}`;
         console.log(link)
-        fetch(link).then(async response => {
+        apiFetch(link).then(async response => {
             const url = window.URL.createObjectURL(new Blob([await response.blob()]));
-            const header = response.headers.get('Content-Disposition');
-            const parts = header.split(';');
-            const filename = parts[1].split('=')[1];
+            const filename = parseFilename(response.headers.get('Content-Disposition')) || this.props.id;
             const link = document.createElement("a");
             link.href = url;
 
@@ -32,11 +71,11 @@ class ImageResult extends React.Component {
         return (
             <div>
                 <div style= {{ marginBottom: "10px", display: "flex", justifyContent: "flex-end" }}><DownloadIcon height="25px" width="25px" onClick={this.handleClick}/></div>
-                <img
+                {this.state.src && <img
                     width="100%"
                     alt="Search Result"
-                    src={`https://localhost/fetch_jpg?id=${this.props.id}&width=1200&height=800&quality=75`}
-                />
+                    src={this.state.src}
+                />}
             </div>
         )
             ;
@@ -0,0 +1,21 @@
+// The API key is asked for once, and kept in local storage
+export function getApiKey() {
+    let key = window.localStorage.getItem('apiKey');
+    if (!key) {
+        // Cancelling the prompt, or entering nothing, asks again on the next request
+        key = (window.prompt('API key') || '').trim();
+        if (key) {
+            window.localStorage.setItem('apiKey', key);
+        }
+    }
+    return key;
+}
+
+// Sends the API key with a request, and asks for another key if it was rejected
+export async function apiFetch(url) {
+    const response = await fetch(url, {headers: {'Authorization': `Bearer ${getApiKey()}`}});
+    if (response.status === 401) {
+        window.localStorage.removeItem('apiKey');
+    }
+    return response;
+}
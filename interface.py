@@ -8,6 +8,8 @@ os.environ["REQUESTS_CA_BUNDLE"] = './certs/test.pem'
 os.environ["SSL_CERT_FILE"] = './certs/test.pem'
 
 DB_URL = "https://localhost"
+# An API key issued through /admin/users/{name}/keys, or ADMIN_API_KEY
+HEADERS = {"Authorization": f"Bearer {os.environ['API_KEY']}"}
 
 # TODO: https://weaviate.io/developers/weaviate/current/data-schema/datatypes.html#datatype-geocoordinates
 # {
@@ -22,9 +24,9 @@ if __name__ == '__main__':
 
     start = time.time()
     images = [(image.name, image.read_bytes()) for image in Path("./sample_images").iterdir() if image.is_file()]
-    ids = requests.post(f"{DB_URL}/upload_raw", files=images).json()
+    ids = requests.post(f"{DB_URL}/upload_raw", files=images, headers=HEADERS).json()
     print(ids)
     end = time.time()
     print(f"Images uploaded in {end - start}s")
 
-    print("response:", requests.get(f"{DB_URL}/near_text?text=cat").text)
+    print("response:", requests.get(f"{DB_URL}/near_text?text=cat", headers=HEADERS).text)
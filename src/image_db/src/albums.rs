@@ -0,0 +1,238 @@
+use std::sync::Arc;
+
+use serde::{Deserialize, Deserializer, Serialize};
+
+use actix_web::web::{self, Data, Json};
+use actix_web::HttpResponse;
+
+use crate::auth::User;
+use crate::db::Id;
+use crate::share::{Shared, Viewer};
+use crate::visibility;
+use crate::weaviate_graphql::WeaviateWhere;
+use crate::SQLiteDatabase;
+
+/// `ClipImage` property which lists the albums an image is in
+pub(crate) const ALBUMS_PROPERTY: &str = "albums";
+
+/// Matches the images in `album`
+pub(crate) fn search_filter(album: &str) -> WeaviateWhere {
+    visibility::equals(ALBUMS_PROPERTY, album)
+}
+
+/// An album, without its images
+#[derive(Serialize)]
+pub struct AlbumSummary {
+    pub(crate) id: Id,
+    pub(crate) name: String,
+    pub(crate) cover: Option<Id>,
+    pub(crate) images: i64,
+    pub(crate) created_at: i64,
+}
+
+/// An album, with its images in order. Only images which the owner may share are listed, so
+/// that images which were made private since they were added are not shown through the album.
+#[derive(Serialize)]
+pub struct Album {
+    pub(crate) id: Id,
+    #[serde(skip)]
+    pub(crate) owner: String,
+    pub(crate) name: String,
+    pub(crate) cover: Option<Id>,
+    pub(crate) images: Vec<Id>,
+}
+
+#[derive(Deserialize)]
+pub struct NewAlbum {
+    name: String,
+}
+
+/// Tells a field which is `null` apart from one which is left out, which `default` turns into
+/// `None`
+fn present<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Option<Id>>, D::Error> {
+    Option::deserialize(deserializer).map(Some)
+}
+
+#[derive(Deserialize)]
+pub struct AlbumChanges {
+    name: Option<String>,
+    /// An image in the album, or `null` to remove the cover
+    #[serde(default, deserialize_with = "present")]
+    cover: Option<Option<Id>>,
+}
+
+#[derive(Deserialize)]
+pub struct AlbumImages {
+    images: Vec<Id>,
+}
+
+#[derive(Serialize)]
+pub struct AddedImages {
+    /// The images which were added, leaving out those which were already in the album and those
+    /// which the user may not add
+    added: Vec<Id>,
+}
+
+fn internal_error(e: impl std::fmt::Debug) -> HttpResponse {
+    log::warn!("{:?}", e);
+    HttpResponse::InternalServerError().body("")
+}
+
+fn album_not_found(id: &str) -> HttpResponse {
+    HttpResponse::NotFound().body(format!("album with id {} not found", id))
+}
+
+/// The album `id`, if it belongs to `user`. Albums of other users are not found, so that their
+/// ids do not reveal that they exist.
+async fn owned_album(data: &SQLiteDatabase, user: &User, id: &str) -> Result<Album, HttpResponse> {
+    match data.album(id).await {
+        Ok(Some(album)) if album.owner == user.name => Ok(album),
+        Ok(_) => Err(album_not_found(id)),
+        Err(e) => Err(internal_error(e)),
+    }
+}
+
+pub async fn list_albums(data: Data<Arc<SQLiteDatabase>>, user: User) -> HttpResponse {
+    match data.albums(&user.name).await {
+        Ok(albums) => HttpResponse::Ok().json(albums),
+        Err(e) => internal_error(e),
+    }
+}
+
+pub async fn create_album(
+    data: Data<Arc<SQLiteDatabase>>,
+    user: User,
+    album: Json<NewAlbum>,
+) -> HttpResponse {
+    if album.name.is_empty() {
+        return HttpResponse::BadRequest().body("name must not be empty");
+    }
+    match data.create_album(&user.name, &album.name).await {
+        Ok(album) => HttpResponse::Created().json(album),
+        Err(e) => internal_error(e),
+    }
+}
+
+/// Lists the images of an album, for its owner or through a share link of the album
+pub async fn get_album(
+    data: Data<Arc<SQLiteDatabase>>,
+    viewer: Viewer,
+    id: web::Path<Id>,
+) -> HttpResponse {
+    match &viewer {
+        Viewer::User(user) => match owned_album(&data, user, &id).await {
+            Ok(album) => HttpResponse::Ok().json(album),
+            Err(response) => response,
+        },
+        Viewer::Share(grant) if grant.shared == Shared::Album(id.to_string()) => {
+            match data.album(&id).await {
+                Ok(Some(album)) if album.owner == grant.signer => HttpResponse::Ok().json(album),
+                Ok(_) => album_not_found(&id),
+                Err(e) => internal_error(e),
+            }
+        }
+        Viewer::Share(_) => album_not_found(&id),
+    }
+}
+
+/// Renames the album, or changes or removes its cover
+pub async fn update_album(
+    data: Data<Arc<SQLiteDatabase>>,
+    user: User,
+    id: web::Path<Id>,
+    changes: Json<AlbumChanges>,
+) -> HttpResponse {
+    let album = match owned_album(&data, &user, &id).await {
+        Ok(album) => album,
+        Err(response) => return response,
+    };
+    if changes.name.as_deref() == Some("") {
+        return HttpResponse::BadRequest().body("name must not be empty");
+    }
+    if let Some(Some(cover)) = &changes.cover {
+        if !album.images.contains(cover) {
+            return HttpResponse::BadRequest().body("the cover must be an image in the album");
+        }
+    }
+    match data
+        .update_album(
+            &id,
+            changes.name.as_deref(),
+            changes.cover.as_ref().map(Option::as_deref),
+        )
+        .await
+    {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(e) => internal_error(e),
+    }
+}
+
+pub async fn delete_album(
+    data: Data<Arc<SQLiteDatabase>>,
+    user: User,
+    id: web::Path<Id>,
+) -> HttpResponse {
+    match data.delete_album(&id, &user.name).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => album_not_found(&id),
+        Err(e) => internal_error(e),
+    }
+}
+
+/// Appends images which the user may share to the album
+pub async fn add_album_images(
+    data: Data<Arc<SQLiteDatabase>>,
+    user: User,
+    id: web::Path<Id>,
+    images: Json<AlbumImages>,
+) -> HttpResponse {
+    if let Err(response) = owned_album(&data, &user, &id).await {
+        return response;
+    }
+    match data.add_to_album(&id, &user, &images.images).await {
+        Ok(added) => HttpResponse::Ok().json(AddedImages { added }),
+        Err(e) => internal_error(e),
+    }
+}
+
+/// Moves the given images of the album to its start, in the given order. Images which are not
+/// given keep their order after them.
+pub async fn order_album_images(
+    data: Data<Arc<SQLiteDatabase>>,
+    user: User,
+    id: web::Path<Id>,
+    images: Json<AlbumImages>,
+) -> HttpResponse {
+    let album = match owned_album(&data, &user, &id).await {
+        Ok(album) => album,
+        Err(response) => return response,
+    };
+    for (index, image) in images.images.iter().enumerate() {
+        if !album.images.contains(image) {
+            return HttpResponse::BadRequest().body(format!("image {} is not in the album", image));
+        }
+        if images.images[..index].contains(image) {
+            return HttpResponse::BadRequest().body(format!("image {} is given twice", image));
+        }
+    }
+    match data.order_album(&id, &images.images).await {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(e) => internal_error(e),
+    }
+}
+
+pub async fn remove_album_image(
+    data: Data<Arc<SQLiteDatabase>>,
+    user: User,
+    path: web::Path<(Id, Id)>,
+) -> HttpResponse {
+    let (id, image) = path.into_inner();
+    if let Err(response) = owned_album(&data, &user, &id).await {
+        return response;
+    }
+    match data.remove_from_album(&id, &image).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().body(format!("image {} is not in the album", image)),
+        Err(e) => internal_error(e),
+    }
+}
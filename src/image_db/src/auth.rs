@@ -0,0 +1,465 @@
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::error::ErrorUnauthorized;
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::http::Method;
+use actix_web::web::{self, Data, Json};
+use actix_web::{FromRequest, HttpMessage, HttpRequest, HttpResponse};
+use futures::future::{ready, LocalBoxFuture, Ready};
+
+use crate::share::ShareLinks;
+use crate::SQLiteDatabase;
+
+/// Endpoints which can be used without an API key
+const PUBLIC_PATHS: &[&str] = &["/health", "/supported_ext"];
+
+/// Name of the user which `ADMIN_API_KEY` authenticates
+const ADMIN_USER: &str = "admin";
+/// Shortest `ADMIN_API_KEY` which is accepted, since it is chosen by hand rather than generated
+const MIN_ADMIN_KEY_LEN: usize = 32;
+
+/// A user who authenticated with an API key
+#[derive(Clone)]
+pub struct User {
+    pub(crate) id: i64,
+    pub(crate) name: String,
+    pub(crate) is_admin: bool,
+    /// The group whose members see the images which the user shares with their group
+    pub(crate) group_name: Option<String>,
+    /// Most bytes of images the user may store
+    pub(crate) max_bytes: Option<i64>,
+    /// Most images the user may store
+    pub(crate) max_images: Option<i64>,
+}
+
+impl User {
+    pub(crate) fn require_admin(&self) -> Result<(), HttpResponse> {
+        if self.is_admin {
+            Ok(())
+        } else {
+            Err(HttpResponse::Forbidden().body("only admins may manage users"))
+        }
+    }
+}
+
+/// A user, and what they have stored
+#[derive(Serialize)]
+pub struct UserSummary {
+    pub(crate) name: String,
+    pub(crate) is_admin: bool,
+    pub(crate) group_name: Option<String>,
+    pub(crate) max_bytes: Option<i64>,
+    pub(crate) max_images: Option<i64>,
+    pub(crate) images: i64,
+    pub(crate) bytes: i64,
+}
+
+/// What a user has stored
+#[derive(Clone, Copy, Default)]
+pub struct Usage {
+    pub(crate) images: i64,
+    pub(crate) bytes: i64,
+}
+
+impl Usage {
+    /// Whether storing another image of `bytes` stays within the quotas of `user`
+    pub(crate) fn allows(&self, user: &User, bytes: i64) -> bool {
+        user.max_images.map_or(true, |max| self.images < max)
+            && user.max_bytes.map_or(true, |max| self.bytes + bytes <= max)
+    }
+}
+
+/// API keys are 256 random bits, so a fast hash is enough to keep stored keys from being usable
+fn hash_key(key: &str) -> Vec<u8> {
+    blake3::hash(key.as_bytes()).as_bytes().to_vec()
+}
+
+fn generate_key() -> Result<String, getrandom::Error> {
+    let mut key = [0u8; 32];
+    getrandom::getrandom(&mut key)?;
+    Ok(key.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/// The API key of the request, from `Authorization: Bearer` or `X-Api-Key`. Keys are never
+/// read from the query string, which ends up in access logs, browser history and `Referer`.
+fn api_key(req: &ServiceRequest) -> Option<String> {
+    let headers = req.headers();
+    if let Some(bearer) = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+    {
+        return non_empty(bearer);
+    }
+    if let Some(key) = headers
+        .get("X-Api-Key")
+        .and_then(|value| value.to_str().ok())
+    {
+        return non_empty(key);
+    }
+    None
+}
+
+/// Blank keys are treated as no key at all
+fn non_empty(key: &str) -> Option<String> {
+    let key = key.trim();
+    (!key.is_empty()).then(|| key.to_string())
+}
+
+#[derive(Deserialize)]
+struct ShareQuery {
+    share: Option<String>,
+}
+
+/// The share link token of the request, from the `share` query parameter
+fn share_token(req: &ServiceRequest) -> Option<String> {
+    web::Query::<ShareQuery>::from_query(req.query_string())
+        .ok()?
+        .into_inner()
+        .share
+}
+
+impl FromRequest for User {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            req.extensions()
+                .get::<User>()
+                .cloned()
+                .ok_or_else(|| ErrorUnauthorized("missing API key")),
+        )
+    }
+}
+
+/// Rejects requests without a valid API key or share link, except for public endpoints and the
+/// front end. Handlers receive the user through the `User` extractor, and the grant of a share
+/// link through the `Viewer` extractor, so that share links only work for handlers which take
+/// a `Viewer`.
+pub struct Authentication {
+    db: Arc<SQLiteDatabase>,
+    share_links: Data<ShareLinks>,
+    static_dir: &'static str,
+}
+
+impl Authentication {
+    pub(crate) fn new(
+        db: Arc<SQLiteDatabase>,
+        share_links: Data<ShareLinks>,
+        static_dir: &'static str,
+    ) -> Self {
+        Authentication {
+            db,
+            share_links,
+            static_dir,
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for Authentication
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = AuthenticationMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AuthenticationMiddleware {
+            service: Rc::new(service),
+            db: self.db.clone(),
+            share_links: self.share_links.clone(),
+            static_dir: self.static_dir,
+        }))
+    }
+}
+
+pub struct AuthenticationMiddleware<S> {
+    service: Rc<S>,
+    db: Arc<SQLiteDatabase>,
+    share_links: Data<ShareLinks>,
+    static_dir: &'static str,
+}
+
+impl<S> AuthenticationMiddleware<S> {
+    fn is_public(&self, req: &ServiceRequest) -> bool {
+        let path = req.path();
+        if PUBLIC_PATHS.contains(&path) {
+            return true;
+        }
+        // The front end, which asks for a key itself
+        req.method() == Method::GET
+            && (path == "/"
+                || (!path.contains("..")
+                    && Path::new(self.static_dir)
+                        .join(path.trim_start_matches('/'))
+                        .is_file()))
+    }
+}
+
+impl<S, B> Service<ServiceRequest> for AuthenticationMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let db = self.db.clone();
+        let public = self.is_public(&req);
+        let grant = match api_key(&req) {
+            Some(_) => None,
+            None => share_token(&req).and_then(|token| self.share_links.verify(&token)),
+        };
+        Box::pin(async move {
+            if let Some(grant) = grant {
+                req.extensions_mut().insert(grant);
+            } else if !public {
+                let user = match api_key(&req) {
+                    Some(key) => db.user_for_key(&hash_key(&key)).await,
+                    None => Ok(None),
+                };
+                match user {
+                    Ok(Some(user)) => {
+                        req.extensions_mut().insert(user);
+                    }
+                    Ok(None) => {
+                        let response = HttpResponse::Unauthorized()
+                            .insert_header(("WWW-Authenticate", "Bearer"))
+                            .body("a valid API key or share link is required");
+                        return Ok(req.into_response(response).map_into_right_body());
+                    }
+                    Err(e) => {
+                        log::warn!("{:?}", e);
+                        let response = HttpResponse::InternalServerError().body("");
+                        return Ok(req.into_response(response).map_into_right_body());
+                    }
+                }
+            }
+            service
+                .call(req)
+                .await
+                .map(ServiceResponse::map_into_left_body)
+        })
+    }
+}
+
+/// `ADMIN_API_KEY`, if it is set and long enough. docker-compose passes unset variables as empty
+/// strings, which must not become a key that anyone can send.
+pub(crate) fn admin_key_from_env() -> Option<String> {
+    let key = std::env::var("ADMIN_API_KEY").ok()?.trim().to_string();
+    if key.is_empty() {
+        return None;
+    }
+    if key.len() < MIN_ADMIN_KEY_LEN {
+        log::warn!(
+            "ADMIN_API_KEY is shorter than {} characters and is ignored",
+            MIN_ADMIN_KEY_LEN
+        );
+        return None;
+    }
+    Some(key)
+}
+
+/// Makes `key` an API key of the admin user, creating the user if needed, so that the first
+/// users and keys can be created
+pub(crate) async fn bootstrap_admin(db: &SQLiteDatabase, key: &str) -> sqlx::Result<()> {
+    let hash = hash_key(key);
+    if db.user_for_key(&hash).await?.is_some() {
+        return Ok(());
+    }
+    let admin = match db.user(ADMIN_USER).await? {
+        Some(admin) => admin,
+        None => db
+            .create_user(ADMIN_USER, true, None, None, None)
+            .await?
+            .ok_or(sqlx::Error::RowNotFound)?,
+    };
+    db.issue_key(admin.id, &hash).await.map(|_| ())
+}
+
+#[derive(Deserialize)]
+pub struct NewUser {
+    name: String,
+    #[serde(default)]
+    is_admin: bool,
+    group: Option<String>,
+    max_bytes: Option<i64>,
+    max_images: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct Quota {
+    /// Most bytes of images the user may store, or unlimited if not given
+    max_bytes: Option<i64>,
+    /// Most images the user may store, or unlimited if not given
+    max_images: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct Group {
+    /// The group to move the user to, or none to leave their group
+    group: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct IssuedKey {
+    /// Identifies the key, so that it can be revoked
+    id: String,
+    /// The key itself, which is only shown once
+    key: String,
+}
+
+/// Checks a user or group name. Names are matched against weaviate `string` properties, which
+/// are split into words, so a name with whitespace would match every name made of its words.
+fn validate_name(kind: &str, name: &str) -> Result<(), HttpResponse> {
+    if name.is_empty() {
+        Err(HttpResponse::BadRequest().body(format!("{} must not be empty", kind)))
+    } else if name.chars().any(char::is_whitespace) {
+        Err(HttpResponse::BadRequest().body(format!("{} must not contain whitespace", kind)))
+    } else {
+        Ok(())
+    }
+}
+
+fn internal_error(e: impl std::fmt::Debug) -> HttpResponse {
+    log::warn!("{:?}", e);
+    HttpResponse::InternalServerError().body("")
+}
+
+pub async fn list_users(data: Data<Arc<SQLiteDatabase>>, user: User) -> HttpResponse {
+    if let Err(response) = user.require_admin() {
+        return response;
+    }
+    match data.users().await {
+        Ok(users) => HttpResponse::Ok().json(users),
+        Err(e) => internal_error(e),
+    }
+}
+
+pub async fn create_user(
+    data: Data<Arc<SQLiteDatabase>>,
+    user: User,
+    new_user: Json<NewUser>,
+) -> HttpResponse {
+    if let Err(response) = user.require_admin() {
+        return response;
+    }
+    let new_user = new_user.into_inner();
+    if let Err(response) = validate_name("name", &new_user.name) {
+        return response;
+    }
+    if let Some(group) = &new_user.group {
+        if let Err(response) = validate_name("group", group) {
+            return response;
+        }
+    }
+    match data
+        .create_user(
+            &new_user.name,
+            new_user.is_admin,
+            new_user.group.as_deref(),
+            new_user.max_bytes,
+            new_user.max_images,
+        )
+        .await
+    {
+        Ok(Some(_)) => HttpResponse::Created().finish(),
+        Ok(None) => HttpResponse::Conflict().body(format!("user {} exists", new_user.name)),
+        Err(e) => internal_error(e),
+    }
+}
+
+pub async fn set_quota(
+    data: Data<Arc<SQLiteDatabase>>,
+    user: User,
+    name: web::Path<String>,
+    quota: Json<Quota>,
+) -> HttpResponse {
+    if let Err(response) = user.require_admin() {
+        return response;
+    }
+    match data
+        .set_quota(&name, quota.max_bytes, quota.max_images)
+        .await
+    {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().body(format!("user {} not found", name)),
+        Err(e) => internal_error(e),
+    }
+}
+
+/// Moves the user to another group, along with the images they share with their group
+pub async fn set_group(
+    data: Data<Arc<SQLiteDatabase>>,
+    user: User,
+    name: web::Path<String>,
+    group: Json<Group>,
+) -> HttpResponse {
+    if let Err(response) = user.require_admin() {
+        return response;
+    }
+    if let Some(group) = &group.group {
+        if let Err(response) = validate_name("group", group) {
+            return response;
+        }
+    }
+    match data.set_group(&name, group.group.as_deref()).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().body(format!("user {} not found", name)),
+        Err(e) => internal_error(e),
+    }
+}
+
+pub async fn issue_key(
+    data: Data<Arc<SQLiteDatabase>>,
+    user: User,
+    name: web::Path<String>,
+) -> HttpResponse {
+    if let Err(response) = user.require_admin() {
+        return response;
+    }
+    let owner = match data.user(&name).await {
+        Ok(Some(owner)) => owner,
+        Ok(None) => return HttpResponse::NotFound().body(format!("user {} not found", name)),
+        Err(e) => return internal_error(e),
+    };
+    let key = match generate_key() {
+        Ok(key) => key,
+        Err(e) => return internal_error(e),
+    };
+    match data.issue_key(owner.id, &hash_key(&key)).await {
+        Ok(id) => HttpResponse::Created().json(IssuedKey { id, key }),
+        Err(e) => internal_error(e),
+    }
+}
+
+pub async fn revoke_key(
+    data: Data<Arc<SQLiteDatabase>>,
+    user: User,
+    id: web::Path<String>,
+) -> HttpResponse {
+    if let Err(response) = user.require_admin() {
+        return response;
+    }
+    match data.revoke_key(&id).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().body(format!("key {} not found", id)),
+        Err(e) => internal_error(e),
+    }
+}
@@ -0,0 +1,177 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use lru::LruCache;
+use serde::Serialize;
+use tempfile::NamedTempFile;
+
+use actix_web::web::Data;
+use actix_web::HttpResponse;
+
+use crate::auth::User;
+
+/// An encoded rendition, along with its dimensions
+pub(crate) struct Rendition {
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+    pub(crate) bytes: Vec<u8>,
+}
+
+/// Content-addressed store of encoded renditions. Each entry is stored in `dir` under the md5 of
+/// its key, as the little-endian width and height followed by the encoded bytes, and the least
+/// recently used entries are evicted once the total size of all entries exceeds `capacity` bytes.
+pub struct RenditionCache {
+    dir: PathBuf,
+    capacity: u64,
+    entries: Mutex<Entries>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+struct Entries {
+    /// File name and size in bytes of each entry, in order of use
+    lru: LruCache<String, u64>,
+    size: u64,
+}
+
+impl Entries {
+    fn evict(&mut self, dir: &Path, capacity: u64) {
+        while self.size > capacity {
+            match self.lru.pop_lru() {
+                Some((name, len)) => {
+                    let _ = std::fs::remove_file(dir.join(name));
+                    self.size -= len;
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct CacheStats {
+    hits: u64,
+    misses: u64,
+    entries: usize,
+    size_bytes: u64,
+    capacity_bytes: u64,
+}
+
+impl RenditionCache {
+    /// Opens the cache in `dir`, picking up any renditions left by a previous run. Existing
+    /// entries are ordered by modification time, so the oldest are evicted first.
+    pub(crate) fn open(dir: PathBuf, capacity: u64) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+
+        let mut files = vec![];
+        for entry in std::fs::read_dir(&dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            // Skip directories and temporary files from interrupted writes
+            if !metadata.is_file() || name.starts_with('.') {
+                continue;
+            }
+            files.push((metadata.modified()?, name, metadata.len()));
+        }
+        files.sort();
+
+        let mut entries = Entries {
+            lru: LruCache::unbounded(),
+            size: 0,
+        };
+        for (_, name, len) in files {
+            entries.lru.put(name, len);
+            entries.size += len;
+        }
+        entries.evict(&dir, capacity);
+
+        Ok(Self {
+            dir,
+            capacity,
+            entries: Mutex::new(entries),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        })
+    }
+
+    fn file_name(key: &str) -> String {
+        format!("{:x}", md5::compute(key))
+    }
+
+    /// Returns the rendition stored under `key`, if present, and marks it as recently used.
+    pub(crate) fn get(&self, key: &str) -> Option<Rendition> {
+        let name = Self::file_name(key);
+        let cached = self.entries.lock().unwrap().lru.get(&name).is_some();
+        if cached {
+            match std::fs::read(self.dir.join(&name)) {
+                Ok(mut bytes) if bytes.len() >= 8 => {
+                    self.hits.fetch_add(1, Ordering::Relaxed);
+                    let width = u32::from_le_bytes(bytes[0..4].try_into().unwrap());
+                    let height = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+                    bytes.drain(..8);
+                    return Some(Rendition {
+                        width,
+                        height,
+                        bytes,
+                    });
+                }
+                _ => {
+                    // Entry was removed from disk behind our back - forget about it
+                    let mut entries = self.entries.lock().unwrap();
+                    if let Some(len) = entries.lru.pop(&name) {
+                        entries.size -= len;
+                    }
+                }
+            }
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        None
+    }
+
+    /// Stores `rendition` under `key`, evicting the least recently used entries if the cache is
+    /// full.
+    pub(crate) fn insert(&self, key: &str, rendition: &Rendition) -> std::io::Result<()> {
+        let name = Self::file_name(key);
+        let len = rendition.bytes.len() as u64 + 8;
+        if len > self.capacity {
+            return Ok(());
+        }
+
+        // Write to a temporary file first so that partially written renditions are never served
+        let mut file = NamedTempFile::new_in(&self.dir)?;
+        file.write_all(&rendition.width.to_le_bytes())?;
+        file.write_all(&rendition.height.to_le_bytes())?;
+        file.write_all(&rendition.bytes)?;
+        file.persist(self.dir.join(&name)).map_err(|e| e.error)?;
+
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(old_len) = entries.lru.put(name, len) {
+            entries.size -= old_len;
+        }
+        entries.size += len;
+        entries.evict(&self.dir, self.capacity);
+        Ok(())
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        let entries = self.entries.lock().unwrap();
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: entries.lru.len(),
+            size_bytes: entries.size,
+            capacity_bytes: self.capacity,
+        }
+    }
+}
+
+/// Reports how well the rendition cache is doing, for admins
+pub async fn cache_stats(cache: Data<RenditionCache>, user: User) -> HttpResponse {
+    if let Err(response) = user.require_admin() {
+        return response;
+    }
+    HttpResponse::Ok().json(cache.stats())
+}
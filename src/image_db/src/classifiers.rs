@@ -0,0 +1,496 @@
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use actix_web::web::{self, Data, Json};
+use actix_web::HttpResponse;
+
+use crate::auth::User;
+use crate::db::{Error, Id};
+use crate::tags;
+use crate::visibility;
+use crate::vocabulary::normalized;
+use crate::SQLiteDatabase;
+
+/// Passes of gradient descent over the examples, which is enough for the few hundred examples
+/// that users label
+const ITERATIONS: usize = 300;
+const LEARNING_RATE: f32 = 1.0;
+const L2_PENALTY: f32 = 1e-3;
+/// Every this many examples of each class, one is held out to validate the classifier
+const VALIDATION_STRIDE: usize = 5;
+
+/// A logistic regression over CLIP vectors
+pub(crate) struct LinearProbe {
+    pub(crate) weights: Vec<f32>,
+    pub(crate) bias: f32,
+}
+
+fn sigmoid(x: f32) -> f32 {
+    1. / (1. + (-x).exp())
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(a, b)| a * b).sum()
+}
+
+impl LinearProbe {
+    /// Fits a probe which separates `positives` from `negatives`. CLIP vectors of unrelated images
+    /// still point in similar directions, so the examples are centered and scaled for gradient
+    /// descent to converge, which is folded into the weights and bias afterwards. The classes are
+    /// weighted equally however many examples each has.
+    pub(crate) fn train(positives: &[Vec<f32>], negatives: &[Vec<f32>]) -> Self {
+        let examples: Vec<(Vec<f32>, f32)> = positives
+            .iter()
+            .map(|vector| (normalized(vector.clone()), 1.))
+            .chain(
+                negatives
+                    .iter()
+                    .map(|vector| (normalized(vector.clone()), 0.)),
+            )
+            .collect();
+        let dims = examples.first().map_or(0, |(vector, _)| vector.len());
+        let count = examples.len() as f32;
+        let scale = (dims as f32).sqrt();
+
+        let mut mean = vec![0.; dims];
+        for (vector, _) in &examples {
+            mean.iter_mut()
+                .zip(vector)
+                .for_each(|(mean, x)| *mean += x / count);
+        }
+        let features: Vec<(Vec<f32>, f32)> = examples
+            .into_iter()
+            .map(|(vector, label)| {
+                let centered = vector.iter().zip(&mean).map(|(x, m)| (x - m) * scale);
+                (centered.collect(), label)
+            })
+            .collect();
+        let class_weight = |label: f32| {
+            let class_count = if label > 0.5 {
+                positives.len()
+            } else {
+                negatives.len()
+            };
+            count / (2. * class_count.max(1) as f32)
+        };
+
+        let mut weights = vec![0.; dims];
+        let mut bias = 0.;
+        for _ in 0..ITERATIONS {
+            let mut weight_gradient = vec![0.; dims];
+            let mut bias_gradient = 0.;
+            for (feature, label) in &features {
+                let error = (sigmoid(dot(&weights, feature) + bias) - label) * class_weight(*label);
+                weight_gradient
+                    .iter_mut()
+                    .zip(feature)
+                    .for_each(|(gradient, x)| *gradient += error * x);
+                bias_gradient += error;
+            }
+            weights
+                .iter_mut()
+                .zip(&weight_gradient)
+                .for_each(|(weight, gradient)| {
+                    *weight -= LEARNING_RATE * (gradient / count + L2_PENALTY * *weight)
+                });
+            bias -= LEARNING_RATE * bias_gradient / count;
+        }
+
+        let weights: Vec<f32> = weights.iter().map(|weight| weight * scale).collect();
+        let bias = bias - dot(&weights, &mean);
+        LinearProbe { weights, bias }
+    }
+
+    /// How likely the image with `vector` is to show the concept
+    pub(crate) fn probability(&self, vector: &[f32]) -> f32 {
+        sigmoid(dot(&self.weights, &normalized(vector.to_vec())) + self.bias)
+    }
+
+    /// The weights as little-endian bytes, for storage
+    pub(crate) fn weight_bytes(&self) -> Vec<u8> {
+        self.weights
+            .iter()
+            .flat_map(|weight| weight.to_le_bytes())
+            .collect()
+    }
+
+    pub(crate) fn from_bytes(weights: &[u8], bias: f64) -> Self {
+        LinearProbe {
+            weights: weights
+                .chunks_exact(4)
+                .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+                .collect(),
+            bias: bias as f32,
+        }
+    }
+}
+
+/// A trained probe, which tags the images of its owner with its name if `auto_tag` is set
+pub(crate) struct Classifier {
+    pub(crate) id: Id,
+    pub(crate) owner: String,
+    pub(crate) name: String,
+    pub(crate) auto_tag: bool,
+    pub(crate) probe: LinearProbe,
+}
+
+impl Classifier {
+    /// The tag which the image with `vector` receives, and how likely it is to apply
+    pub(crate) fn score(&self, vector: &[f32]) -> Vec<(String, f32)> {
+        let probability = self.probe.probability(vector);
+        if probability >= 0.5 {
+            vec![(self.name.clone(), probability)]
+        } else {
+            Vec::new()
+        }
+    }
+}
+
+/// How well a classifier did on the examples which were held out of its training
+#[derive(Serialize)]
+pub struct Validation {
+    /// Share of held out images classified as positive which are positive, if any were
+    pub(crate) precision: Option<f64>,
+    /// Share of held out positive images which were classified as positive
+    pub(crate) recall: Option<f64>,
+}
+
+/// Splits `examples` into those which are trained on and those which are held out
+fn split(examples: &[Vec<f32>]) -> (Vec<Vec<f32>>, Vec<Vec<f32>>) {
+    let (held_out, trained): (Vec<_>, Vec<_>) = examples
+        .iter()
+        .enumerate()
+        .partition(|(index, _)| index % VALIDATION_STRIDE == VALIDATION_STRIDE - 1);
+    let mut trained: Vec<_> = trained
+        .into_iter()
+        .map(|(_, vector)| vector.clone())
+        .collect();
+    let mut held_out: Vec<_> = held_out
+        .into_iter()
+        .map(|(_, vector)| vector.clone())
+        .collect();
+    // Hold out at least one example, while keeping one to train on
+    if held_out.is_empty() && trained.len() > 1 {
+        held_out.extend(trained.pop());
+    }
+    (trained, held_out)
+}
+
+fn validate(positives: &[Vec<f32>], negatives: &[Vec<f32>]) -> Validation {
+    let (train_positives, test_positives) = split(positives);
+    let (train_negatives, test_negatives) = split(negatives);
+    let probe = LinearProbe::train(&train_positives, &train_negatives);
+    let predicted = |vector: &Vec<f32>| probe.probability(vector) >= 0.5;
+
+    let true_positives = test_positives.iter().filter(|v| predicted(v)).count();
+    let false_positives = test_negatives.iter().filter(|v| predicted(v)).count();
+    let ratio = |numerator: usize, denominator: usize| {
+        (denominator > 0).then(|| numerator as f64 / denominator as f64)
+    };
+    Validation {
+        precision: ratio(true_positives, true_positives + false_positives),
+        recall: ratio(true_positives, test_positives.len()),
+    }
+}
+
+/// A classifier, without its weights
+#[derive(Serialize)]
+pub struct ClassifierSummary {
+    pub(crate) id: Id,
+    pub(crate) name: String,
+    pub(crate) auto_tag: bool,
+    pub(crate) positives: i64,
+    pub(crate) negatives: i64,
+    pub(crate) precision: Option<f64>,
+    pub(crate) recall: Option<f64>,
+    pub(crate) created_at: i64,
+}
+
+#[derive(Deserialize)]
+pub struct TrainingRequest {
+    /// The concept, which is also the tag which the classifier assigns
+    name: String,
+    positives: Vec<Id>,
+    negatives: Vec<Id>,
+    /// Whether to tag the user's images, present and future, with the concept
+    #[serde(default)]
+    auto_tag: bool,
+}
+
+#[derive(Deserialize)]
+pub struct ClassifierSearch {
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct ClassifierSearchOutput {
+    ids: Vec<Id>,
+}
+
+fn internal_error(e: impl std::fmt::Debug) -> HttpResponse {
+    log::warn!("{:?}", e);
+    HttpResponse::InternalServerError().body("")
+}
+
+fn classifier_not_found(id: &str) -> HttpResponse {
+    HttpResponse::NotFound().body(format!("classifier with id {} not found", id))
+}
+
+pub(crate) enum Missing {
+    /// Examples which the user may not see, or which were never vectorized
+    Images(Vec<Id>),
+    Error(Error),
+}
+
+/// The vectors of the example images, in order, which must all be images the user may see
+pub(crate) async fn example_vectors(
+    data: &SQLiteDatabase,
+    user: &User,
+    ids: &[Id],
+) -> Result<Vec<Vec<f32>>, Missing> {
+    let mut vectors = Vec::with_capacity(ids.len());
+    let mut missing = Vec::new();
+    for id in ids {
+        let vector = match data.visible_file(id, user).await {
+            Ok(_) => data.image_vector(id).await.map_err(Missing::Error)?,
+            Err(_) => None,
+        };
+        match vector {
+            Some(vector) => vectors.push(vector),
+            None => missing.push(id.clone()),
+        }
+    }
+    if missing.is_empty() {
+        Ok(vectors)
+    } else {
+        Err(Missing::Images(missing))
+    }
+}
+
+pub(crate) fn missing_examples(missing: &[Id]) -> HttpResponse {
+    HttpResponse::BadRequest().body(format!(
+        "images not found or not searchable: {}",
+        missing.join(", ")
+    ))
+}
+
+/// Re-tags the images of the classifier's owner with it in the background, which removes its
+/// tags if it does not auto-tag
+fn retag(data: &Data<Arc<SQLiteDatabase>>, classifier: Classifier) {
+    let db = data.get_ref().clone();
+    actix_web::rt::spawn(async move {
+        match db.tag_with_classifier(&classifier).await {
+            Ok(count) => log::info!("Classifier {} tagged {} images", classifier.name, count),
+            Err(e) => log::warn!(
+                "Tagging with classifier {} failed: {:?}",
+                classifier.name,
+                e
+            ),
+        }
+    });
+}
+
+/// Trains a classifier from examples which the user may see, replacing the user's classifier
+/// with the same name
+pub async fn train_classifier(
+    data: Data<Arc<SQLiteDatabase>>,
+    user: User,
+    request: Json<TrainingRequest>,
+) -> HttpResponse {
+    let request = request.into_inner();
+    let name = match tags::normalize(&request.name) {
+        Ok(name) => name,
+        Err(reason) => return HttpResponse::BadRequest().body(reason),
+    };
+    if request.positives.len() < 2 || request.negatives.len() < 2 {
+        return HttpResponse::BadRequest()
+            .body("at least 2 positive and 2 negative examples are required");
+    }
+    if request
+        .positives
+        .iter()
+        .any(|id| request.negatives.contains(id))
+    {
+        return HttpResponse::BadRequest().body("examples can not be both positive and negative");
+    }
+
+    let (positives, negatives) = match (
+        example_vectors(&data, &user, &request.positives).await,
+        example_vectors(&data, &user, &request.negatives).await,
+    ) {
+        (Ok(positives), Ok(negatives)) => (positives, negatives),
+        (Err(Missing::Images(mut missing)), Err(Missing::Images(more))) => {
+            missing.extend(more);
+            return missing_examples(&missing);
+        }
+        (Err(Missing::Images(missing)), _) | (_, Err(Missing::Images(missing))) => {
+            return missing_examples(&missing)
+        }
+        (Err(Missing::Error(e)), _) | (_, Err(Missing::Error(e))) => return internal_error(e),
+    };
+
+    let examples = (positives.len(), negatives.len());
+    // Training runs gradient descent twice over every example, which would hold up the other
+    // requests of this worker
+    let (validation, probe) = match web::block(move || {
+        let validation = validate(&positives, &negatives);
+        (validation, LinearProbe::train(&positives, &negatives))
+    })
+    .await
+    {
+        Ok(trained) => trained,
+        Err(e) => return internal_error(e),
+    };
+    let summary = match data
+        .save_classifier(
+            &user.name,
+            &name,
+            &probe,
+            request.auto_tag,
+            examples,
+            &validation,
+        )
+        .await
+    {
+        Ok(summary) => summary,
+        Err(e) => return internal_error(e),
+    };
+    retag(
+        &data,
+        Classifier {
+            id: summary.id.clone(),
+            owner: user.name.clone(),
+            name,
+            auto_tag: request.auto_tag,
+            probe,
+        },
+    );
+    HttpResponse::Created().json(summary)
+}
+
+pub async fn list_classifiers(data: Data<Arc<SQLiteDatabase>>, user: User) -> HttpResponse {
+    match data.classifiers(&user.name).await {
+        Ok(classifiers) => HttpResponse::Ok().json(classifiers),
+        Err(e) => internal_error(e),
+    }
+}
+
+/// Deletes the classifier, along with the tags it assigned
+pub async fn delete_classifier(
+    data: Data<Arc<SQLiteDatabase>>,
+    user: User,
+    id: web::Path<Id>,
+) -> HttpResponse {
+    match data.delete_classifier(&id, &user.name).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => classifier_not_found(&id),
+        Err(e) => internal_error(e),
+    }
+}
+
+/// The images which the user may see which the classifier is most confident show its concept.
+/// Images are ranked by their cosine similarity to the weights, which orders them like the
+/// classifier's probability does, since CLIP vectors are normalized.
+pub async fn classifier_search(
+    data: Data<Arc<SQLiteDatabase>>,
+    user: User,
+    id: web::Path<Id>,
+    params: web::Query<ClassifierSearch>,
+) -> HttpResponse {
+    let classifier = match data.classifier(&id).await {
+        Ok(Some(classifier)) if classifier.owner == user.name => classifier,
+        Ok(_) => return classifier_not_found(&id),
+        Err(e) => return internal_error(e),
+    };
+    let limit = params.limit.unwrap_or(20).min(100);
+    let direction = normalized(classifier.probe.weights);
+    match data
+        .near_vector(&direction, limit, &visibility::search_filter(&user))
+        .await
+    {
+        Ok(ids) => HttpResponse::Ok().json(ClassifierSearchOutput { ids }),
+        Err(e) => internal_error(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Vectors which share a large component, like CLIP vectors do, and are separated by their
+    /// second and third components
+    fn examples() -> (Vec<Vec<f32>>, Vec<Vec<f32>>) {
+        let positives = (0..10).map(|i| vec![4., 1., 0.1 * i as f32, 0.]).collect();
+        let negatives = (0..10).map(|i| vec![4., 0., 1., 0.1 * i as f32]).collect();
+        (positives, negatives)
+    }
+
+    #[test]
+    fn separates_examples() {
+        let (positives, negatives) = examples();
+        let probe = LinearProbe::train(&positives, &negatives);
+        for positive in &positives {
+            assert!(probe.probability(positive) > 0.5);
+        }
+        for negative in &negatives {
+            assert!(probe.probability(negative) < 0.5);
+        }
+    }
+
+    #[test]
+    fn ignores_vector_length() {
+        let (positives, negatives) = examples();
+        let probe = LinearProbe::train(&positives, &negatives);
+        let scaled: Vec<f32> = positives[0].iter().map(|x| x * 10.).collect();
+        let difference = probe.probability(&positives[0]) - probe.probability(&scaled);
+        assert!(difference.abs() < 1e-5);
+    }
+
+    #[test]
+    fn weights_round_trip() {
+        let probe = LinearProbe {
+            weights: vec![0.5, -1.25, 3e-7, f32::MAX],
+            bias: -0.75,
+        };
+        let restored = LinearProbe::from_bytes(&probe.weight_bytes(), probe.bias as f64);
+        assert_eq!(restored.weights, probe.weights);
+        assert_eq!(restored.bias, probe.bias);
+    }
+
+    #[test]
+    fn holds_out_examples() {
+        let examples: Vec<Vec<f32>> = (0..10).map(|i| vec![i as f32]).collect();
+        let (trained, held_out) = split(&examples);
+        assert_eq!(trained.len(), 8);
+        assert_eq!(held_out, vec![vec![4.], vec![9.]]);
+
+        // Small sets still hold out one example
+        let (trained, held_out) = split(&examples[..2]);
+        assert_eq!((trained.len(), held_out.len()), (1, 1));
+    }
+
+    #[test]
+    fn validates_separable_examples() {
+        let (positives, negatives) = examples();
+        let validation = validate(&positives, &negatives);
+        assert_eq!(validation.precision, Some(1.));
+        assert_eq!(validation.recall, Some(1.));
+    }
+
+    #[test]
+    fn scores_with_name() {
+        let (positives, negatives) = examples();
+        let classifier = Classifier {
+            id: String::from("id"),
+            owner: String::from("owner"),
+            name: String::from("dog"),
+            auto_tag: true,
+            probe: LinearProbe::train(&positives, &negatives),
+        };
+        let scores = classifier.score(&positives[0]);
+        assert_eq!(scores.len(), 1);
+        assert_eq!(scores[0].0, "dog");
+        assert!(classifier.score(&negatives[0]).is_empty());
+    }
+}
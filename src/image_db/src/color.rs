@@ -0,0 +1,369 @@
+use std::io::{Cursor, Read, Write};
+
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::{Compression, Crc};
+use serde::Deserialize;
+
+use exif::{Exif, In, Tag};
+use image::imageops::{flip_horizontal, flip_vertical, rotate180, rotate270, rotate90};
+use image::RgbImage;
+use lcms2::{CIExyY, CIExyYTRIPLE, Intent, PixelFormat, Profile, ToneCurve, Transform};
+
+/// Color profiles which renditions can be converted into
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum ColorProfile {
+    #[default]
+    Srgb,
+    DisplayP3,
+    AdobeRgb,
+}
+
+const fn xy(x: f64, y: f64) -> CIExyY {
+    CIExyY { x, y, Y: 1. }
+}
+
+const D65: CIExyY = xy(0.3127, 0.3290);
+
+impl ColorProfile {
+    pub(crate) fn profile(self) -> Option<Profile> {
+        match self {
+            ColorProfile::Srgb => Some(Profile::new_srgb()),
+            ColorProfile::DisplayP3 => {
+                // Display P3 shares the transfer function of sRGB
+                let curve = ToneCurve::new_parametric(
+                    4,
+                    &[2.4, 1. / 1.055, 0.055 / 1.055, 1. / 12.92, 0.04045],
+                )
+                .ok()?;
+                let primaries = CIExyYTRIPLE {
+                    Red: xy(0.680, 0.320),
+                    Green: xy(0.265, 0.690),
+                    Blue: xy(0.150, 0.060),
+                };
+                Profile::new_rgb(&D65, &primaries, &[&curve, &curve, &curve]).ok()
+            }
+            ColorProfile::AdobeRgb => {
+                let curve = ToneCurve::new(563. / 256.);
+                let primaries = CIExyYTRIPLE {
+                    Red: xy(0.64, 0.33),
+                    Green: xy(0.21, 0.71),
+                    Blue: xy(0.15, 0.06),
+                };
+                Profile::new_rgb(&D65, &primaries, &[&curve, &curve, &curve]).ok()
+            }
+        }
+    }
+
+    /// The ICC profile to embed in renditions
+    pub(crate) fn icc(self) -> Option<Vec<u8>> {
+        self.profile()?.icc().ok()
+    }
+}
+
+pub(crate) fn read_exif(buf: &[u8]) -> Option<Exif> {
+    exif::Reader::new()
+        .read_from_container(&mut Cursor::new(buf))
+        .ok()
+}
+
+/// The EXIF orientation, from 1 (upright) to 8
+pub(crate) fn orientation(exif: &Exif) -> Option<u32> {
+    exif.get_field(Tag::Orientation, In::PRIMARY)?
+        .value
+        .get_uint(0)
+        .filter(|orientation| (1..=8).contains(orientation))
+}
+
+/// Whether images with the given EXIF orientation are stored on their side
+pub(crate) fn swaps_dimensions(orientation: u32) -> bool {
+    (5..=8).contains(&orientation)
+}
+
+/// Rotates and flips `image` so that it is upright
+pub(crate) fn orient(image: RgbImage, orientation: u32) -> RgbImage {
+    match orientation {
+        2 => flip_horizontal(&image),
+        3 => rotate180(&image),
+        4 => flip_vertical(&image),
+        5 => flip_horizontal(&rotate90(&image)),
+        6 => rotate90(&image),
+        7 => flip_horizontal(&rotate270(&image)),
+        8 => rotate270(&image),
+        _ => image,
+    }
+}
+
+/// Cameras mark Adobe RGB images as uncalibrated, with an interoperability index of "R03",
+/// rather than embedding a profile.
+fn is_exif_adobe_rgb(exif: &Exif) -> bool {
+    let uncalibrated = exif
+        .get_field(Tag::ColorSpace, In::PRIMARY)
+        .and_then(|field| field.value.get_uint(0))
+        == Some(0xffff);
+    let r03 = match exif.get_field(Tag::InteroperabilityIndex, In::PRIMARY) {
+        Some(exif::Field {
+            value: exif::Value::Ascii(values),
+            ..
+        }) => values.iter().any(|value| value == b"R03"),
+        _ => false,
+    };
+    uncalibrated && r03
+}
+
+/// Returns the color profile of an encoded image, or `None` if it is sRGB.
+pub(crate) fn source_profile(buf: &[u8], exif: Option<&Exif>) -> Option<Profile> {
+    if let Some(icc) = embedded_icc(buf) {
+        match Profile::new_icc(&icc) {
+            Ok(profile) => return Some(profile),
+            Err(e) => log::warn!("Ignoring invalid embedded ICC profile: {:?}", e),
+        }
+    }
+    if exif.map_or(false, is_exif_adobe_rgb) {
+        return ColorProfile::AdobeRgb.profile();
+    }
+    None
+}
+
+/// Converts `image` from `source` (sRGB if `None`) into `target`
+pub(crate) fn convert(
+    image: &mut RgbImage,
+    source: Option<&Profile>,
+    target: ColorProfile,
+) -> Option<()> {
+    let srgb;
+    let source = match source {
+        Some(source) => source,
+        None if target == ColorProfile::Srgb => return Some(()),
+        None => {
+            srgb = Profile::new_srgb();
+            &srgb
+        }
+    };
+    let target = target.profile()?;
+    let transform: Transform<[u8; 3], [u8; 3]> = Transform::new(
+        source,
+        PixelFormat::RGB_8,
+        &target,
+        PixelFormat::RGB_8,
+        Intent::Perceptual,
+    )
+    .ok()?;
+    transform.transform_in_place(bytemuck::cast_slice_mut(&mut **image));
+    Some(())
+}
+
+/// Returns the ICC profile embedded in a JPEG, PNG or WebP image
+fn embedded_icc(buf: &[u8]) -> Option<Vec<u8>> {
+    if buf.starts_with(&[0xff, 0xd8]) {
+        jpeg_icc(buf)
+    } else if buf.starts_with(b"\x89PNG\r\n\x1a\n") {
+        png_icc(buf)
+    } else if buf.starts_with(b"RIFF") && buf.get(8..12) == Some(&b"WEBP"[..]) {
+        webp_icc(buf)
+    } else {
+        None
+    }
+}
+
+const JPEG_ICC_MARKER: &[u8] = b"ICC_PROFILE\0";
+/// Space left for the profile in each APP2 segment, after the length, marker and sequence numbers
+const JPEG_ICC_CHUNK_SIZE: usize = 65535 - 2 - JPEG_ICC_MARKER.len() - 2;
+
+/// Reassembles the profile, which may be split across several APP2 segments
+fn jpeg_icc(buf: &[u8]) -> Option<Vec<u8>> {
+    let mut chunks = vec![];
+    let mut pos = 2;
+    while pos + 4 <= buf.len() && buf[pos] == 0xff {
+        let marker = buf[pos + 1];
+        // Image data starts at SOS, and metadata comes before it
+        if marker == 0xda || marker == 0xd9 {
+            break;
+        }
+        let len = u16::from_be_bytes([buf[pos + 2], buf[pos + 3]]) as usize;
+        let segment = buf.get(pos + 4..pos + 2 + len)?;
+        if marker == 0xe2 && segment.starts_with(JPEG_ICC_MARKER) {
+            // Segments which are too short for their sequence numbers are skipped
+            let sequence = segment.get(JPEG_ICC_MARKER.len());
+            let chunk = segment.get(JPEG_ICC_MARKER.len() + 2..);
+            if let (Some(&sequence), Some(chunk)) = (sequence, chunk) {
+                chunks.push((sequence, chunk));
+            }
+        }
+        pos += 2 + len;
+    }
+
+    if chunks.is_empty() {
+        return None;
+    }
+    chunks.sort_by_key(|(sequence, _)| *sequence);
+    Some(
+        chunks
+            .into_iter()
+            .flat_map(|(_, chunk)| chunk)
+            .copied()
+            .collect(),
+    )
+}
+
+/// Iterates over the type and data of each chunk of a PNG
+fn png_chunks(buf: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    let mut pos = 8;
+    std::iter::from_fn(move || {
+        let len = u32::from_be_bytes(buf.get(pos..pos + 4)?.try_into().ok()?) as usize;
+        let kind = buf.get(pos + 4..pos + 8)?;
+        let data = buf.get(pos + 8..pos + 8 + len)?;
+        pos += 12 + len;
+        Some((kind, data))
+    })
+}
+
+fn png_icc(buf: &[u8]) -> Option<Vec<u8>> {
+    let (_, data) = png_chunks(buf)
+        .take_while(|(kind, _)| *kind != b"IDAT")
+        .find(|(kind, _)| *kind == b"iCCP")?;
+    // Profile name, null separator and compression method precede the zlib stream
+    let name_end = data.iter().position(|&byte| byte == 0)?;
+    let mut icc = vec![];
+    ZlibDecoder::new(data.get(name_end + 2..)?)
+        .read_to_end(&mut icc)
+        .ok()?;
+    Some(icc)
+}
+
+/// Iterates over the FourCC and data of each chunk of a WebP
+fn webp_chunks(buf: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    let mut pos = 12;
+    std::iter::from_fn(move || {
+        let kind = buf.get(pos..pos + 4)?;
+        let len = u32::from_le_bytes(buf.get(pos + 4..pos + 8)?.try_into().ok()?) as usize;
+        let data = buf.get(pos + 8..pos + 8 + len)?;
+        // Chunks are padded to an even length
+        pos += 8 + len + (len & 1);
+        Some((kind, data))
+    })
+}
+
+fn webp_icc(buf: &[u8]) -> Option<Vec<u8>> {
+    webp_chunks(buf)
+        .find(|(kind, _)| *kind == b"ICCP")
+        .map(|(_, data)| data.to_vec())
+}
+
+/// Inserts `icc` into a JPEG as APP2 segments, after the JFIF header
+pub(crate) fn embed_icc_jpeg(jpeg: &[u8], icc: &[u8]) -> Option<Vec<u8>> {
+    let mut pos = 2;
+    if jpeg.get(2..4)? == [0xff, 0xe0] {
+        pos += 2 + u16::from_be_bytes([*jpeg.get(4)?, *jpeg.get(5)?]) as usize;
+    }
+
+    let chunks: Vec<_> = icc.chunks(JPEG_ICC_CHUNK_SIZE).collect();
+    let count = u8::try_from(chunks.len()).ok()?;
+    let mut out = Vec::with_capacity(jpeg.len() + icc.len() + 18 * chunks.len());
+    out.extend_from_slice(jpeg.get(..pos)?);
+    for (sequence, chunk) in chunks.into_iter().enumerate() {
+        let len = (2 + JPEG_ICC_MARKER.len() + 2 + chunk.len()) as u16;
+        out.extend_from_slice(&[0xff, 0xe2]);
+        out.extend_from_slice(&len.to_be_bytes());
+        out.extend_from_slice(JPEG_ICC_MARKER);
+        out.extend_from_slice(&[sequence as u8 + 1, count]);
+        out.extend_from_slice(chunk);
+    }
+    out.extend_from_slice(jpeg.get(pos..)?);
+    Some(out)
+}
+
+/// Inserts `icc` into a PNG as an iCCP chunk, after the IHDR chunk
+pub(crate) fn embed_icc_png(png: &[u8], icc: &[u8]) -> Option<Vec<u8>> {
+    // Signature, then the length, type, 13 bytes of data and CRC of IHDR
+    let ihdr_end = 8 + 4 + 4 + 13 + 4;
+    if png.get(12..16)? != b"IHDR" {
+        return None;
+    }
+
+    let mut data = b"ICC profile\0\0".to_vec();
+    let mut encoder = ZlibEncoder::new(&mut data, Compression::default());
+    encoder.write_all(icc).ok()?;
+    encoder.finish().ok()?;
+
+    let mut crc = Crc::new();
+    crc.update(b"iCCP");
+    crc.update(&data);
+
+    let mut out = Vec::with_capacity(png.len() + data.len() + 12);
+    out.extend_from_slice(png.get(..ihdr_end)?);
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    out.extend_from_slice(b"iCCP");
+    out.extend_from_slice(&data);
+    out.extend_from_slice(&crc.sum().to_be_bytes());
+    out.extend_from_slice(png.get(ihdr_end..)?);
+    Some(out)
+}
+
+/// Converts a simple (lossy or lossless) WebP into the extended format, so that `icc` can be
+/// stored alongside the image data
+pub(crate) fn embed_icc_webp(webp: &[u8], width: u32, height: u32, icc: &[u8]) -> Option<Vec<u8>> {
+    let image_chunks = webp.get(12..)?;
+    if !matches!(image_chunks.get(..4)?, b"VP8 " | b"VP8L") {
+        return None;
+    }
+
+    // Flags (ICC profile present), reserved bytes, then the 24-bit canvas width and height - 1
+    let mut vp8x = vec![0x20, 0, 0, 0];
+    vp8x.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+    vp8x.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+
+    let padding = icc.len() & 1;
+    let riff_len = 4 + (8 + vp8x.len()) + (8 + icc.len() + padding) + image_chunks.len();
+
+    let mut out = Vec::with_capacity(8 + riff_len);
+    out.extend_from_slice(b"RIFF");
+    out.extend_from_slice(&u32::try_from(riff_len).ok()?.to_le_bytes());
+    out.extend_from_slice(b"WEBP");
+    out.extend_from_slice(b"VP8X");
+    out.extend_from_slice(&(vp8x.len() as u32).to_le_bytes());
+    out.extend_from_slice(&vp8x);
+    out.extend_from_slice(b"ICCP");
+    out.extend_from_slice(&(icc.len() as u32).to_le_bytes());
+    out.extend_from_slice(icc);
+    if padding == 1 {
+        out.push(0);
+    }
+    out.extend_from_slice(image_chunks);
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A JPEG with an APP2 segment for each of `payloads`, which follow the ICC marker
+    fn jpeg(payloads: &[&[u8]]) -> Vec<u8> {
+        let mut buf = vec![0xff, 0xd8];
+        for payload in payloads {
+            let len = (2 + JPEG_ICC_MARKER.len() + payload.len()) as u16;
+            buf.extend_from_slice(&[0xff, 0xe2]);
+            buf.extend_from_slice(&len.to_be_bytes());
+            buf.extend_from_slice(JPEG_ICC_MARKER);
+            buf.extend_from_slice(payload);
+        }
+        buf.extend_from_slice(&[0xff, 0xda, 0x00, 0x02]);
+        buf
+    }
+
+    #[test]
+    fn reassembles_profile_in_order() {
+        let buf = jpeg(&[b"\x02\x02def", b"\x01\x02abc"]);
+        assert_eq!(jpeg_icc(&buf), Some(b"abcdef".to_vec()));
+    }
+
+    #[test]
+    fn skips_truncated_segments() {
+        let buf = jpeg(&[b"\x01"]);
+        assert_eq!(jpeg_icc(&buf), None);
+
+        let buf = jpeg(&[b"\x01", b"\x01\x01abc"]);
+        assert_eq!(jpeg_icc(&buf), Some(b"abc".to_vec()));
+    }
+}
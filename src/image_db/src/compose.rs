@@ -0,0 +1,313 @@
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use actix_web::web::{Data, Json};
+use actix_web::HttpResponse;
+
+use crate::auth::User;
+use crate::classifiers::{example_vectors, missing_examples, Missing};
+use crate::db::{search_filter, Id, SearchScope};
+use crate::vocabulary::normalized;
+use crate::weaviate_graphql::VectorizerInput;
+use crate::SQLiteDatabase;
+
+/// How many components a query may have
+const MAX_COMPONENTS: usize = 16;
+
+fn default_weight() -> f32 {
+    1.0
+}
+
+#[derive(Deserialize, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Prompt {
+    Text(String),
+    /// An image which the user may see
+    Image(Id),
+}
+
+/// A part of a query, such as `{"text": "beach"}` or `{"image": "<id>", "weight": 0.7}`
+#[derive(Deserialize, PartialEq, Debug)]
+pub struct Component {
+    #[serde(flatten)]
+    prompt: Prompt,
+    /// How strongly results are steered towards the prompt, or away from it if negative
+    #[serde(default = "default_weight")]
+    weight: f32,
+}
+
+#[derive(Deserialize)]
+pub struct ComposedQuery {
+    /// The components as objects. Either these or `query` must be given.
+    #[serde(default)]
+    components: Vec<Component>,
+    /// The components written as in `+"beach" 1.0 -"people" 0.5 +image:<id> 0.7`
+    query: Option<String>,
+    #[serde(flatten)]
+    scope: SearchScope,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct ComposedQueryOutput {
+    ids: Vec<Id>,
+}
+
+/// Parses the components of a query such as `+"beach" 1.0 -"people" 0.5 +image:<id> 0.7`. Each
+/// component is a sign, which is `+` if it is left out, a quoted text or `image:` followed by the
+/// id of an image, and a weight, which is 1 if it is left out. Quotes and backslashes in texts are
+/// escaped with a backslash.
+fn parse_query(query: &str) -> Result<Vec<Component>, String> {
+    let mut components = Vec::new();
+    let mut rest = query.trim_start();
+    while !rest.is_empty() {
+        let sign = match rest.chars().next() {
+            Some('-') => -1.,
+            _ => 1.,
+        };
+        rest = rest.strip_prefix(&['+', '-'][..]).unwrap_or(rest);
+
+        let prompt = if let Some(quoted) = rest.strip_prefix('"') {
+            let mut text = String::new();
+            let mut chars = quoted.char_indices();
+            let end = loop {
+                match chars.next() {
+                    Some((end, '"')) => break end,
+                    Some((_, '\\')) => match chars.next() {
+                        Some((_, escaped)) => text.push(escaped),
+                        None => return Err(String::from("unterminated quote")),
+                    },
+                    Some((_, c)) => text.push(c),
+                    None => return Err(String::from("unterminated quote")),
+                }
+            };
+            rest = &quoted[end + 1..];
+            Prompt::Text(text)
+        } else if let Some(image) = rest.strip_prefix("image:") {
+            let end = image.find(char::is_whitespace).unwrap_or(image.len());
+            if end == 0 {
+                return Err(String::from(
+                    "image: must be followed by the id of an image",
+                ));
+            }
+            rest = &image[end..];
+            Prompt::Image(image[..end].to_string())
+        } else {
+            let found = rest.split_whitespace().next().unwrap_or(rest);
+            return Err(format!(
+                "expected a quoted text or image:<id>, found `{}`",
+                found
+            ));
+        };
+
+        rest = rest.trim_start();
+        let weight = if rest.starts_with(|c: char| c.is_ascii_digit() || c == '.') {
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            let weight = rest[..end]
+                .parse::<f32>()
+                .map_err(|_| format!("invalid weight `{}`", &rest[..end]))?;
+            rest = rest[end..].trim_start();
+            weight
+        } else {
+            1.
+        };
+        components.push(Component {
+            prompt,
+            weight: sign * weight,
+        });
+    }
+    Ok(components)
+}
+
+/// The weighted sum of the vectors, each normalized first so that only the weights decide how
+/// much it counts, or `None` if they cancel each other out
+fn combine(vectors: impl IntoIterator<Item = (f32, Vec<f32>)>) -> Option<Vec<f32>> {
+    let mut combined: Vec<f32> = Vec::new();
+    for (weight, vector) in vectors {
+        let vector = normalized(vector);
+        combined.resize(combined.len().max(vector.len()), 0.);
+        combined
+            .iter_mut()
+            .zip(vector)
+            .for_each(|(combined, x)| *combined += weight * x);
+    }
+    if combined.iter().all(|x| *x == 0.) {
+        return None;
+    }
+    Some(normalized(combined))
+}
+
+fn internal_error(e: impl std::fmt::Debug) -> HttpResponse {
+    log::warn!("{:?}", e);
+    HttpResponse::InternalServerError().body("")
+}
+
+/// Searches with the weighted sum of the vectors of several text and image prompts, such as a
+/// photo and "at night", minus "people". Each prompt is normalized first, so that the weights
+/// alone decide how much it counts.
+pub async fn near_composed(
+    data: Data<Arc<SQLiteDatabase>>,
+    user: User,
+    query: Json<ComposedQuery>,
+) -> HttpResponse {
+    let mut query = query.into_inner();
+    match (query.components.is_empty(), query.query.take()) {
+        (true, Some(text)) => match parse_query(&text) {
+            Ok(components) => query.components = components,
+            Err(reason) => return HttpResponse::BadRequest().body(reason),
+        },
+        (false, Some(_)) => {
+            return HttpResponse::BadRequest().body("either components or query must be given")
+        }
+        (_, None) => {}
+    }
+    if query.components.is_empty() || query.components.len() > MAX_COMPONENTS {
+        return HttpResponse::BadRequest().body(format!(
+            "between 1 and {} components must be given",
+            MAX_COMPONENTS
+        ));
+    }
+    if !query
+        .components
+        .iter()
+        .all(|component| component.weight.is_finite())
+    {
+        return HttpResponse::BadRequest().body("weights must be finite");
+    }
+    if !query
+        .components
+        .iter()
+        .any(|component| component.weight > 0.)
+    {
+        return HttpResponse::BadRequest().body("at least one weight must be positive");
+    }
+    let filter = match search_filter(&data, &user, &query.scope).await {
+        Ok(filter) => filter,
+        Err(response) => return response,
+    };
+
+    let (mut texts, mut images) = (Vec::new(), Vec::new());
+    for component in &query.components {
+        match &component.prompt {
+            Prompt::Text(text) => texts.push(text.clone()),
+            Prompt::Image(id) => images.push(id.clone()),
+        }
+    }
+    let image_vectors = match example_vectors(&data, &user, &images).await {
+        Ok(vectors) => vectors,
+        Err(Missing::Images(missing)) => return missing_examples(&missing),
+        Err(Missing::Error(e)) => return internal_error(e),
+    };
+    let text_vectors = if texts.is_empty() {
+        Vec::new()
+    } else {
+        match data
+            .vectorize(VectorizerInput {
+                texts,
+                images: vec![],
+            })
+            .await
+        {
+            Ok(output) => output.text_vectors,
+            Err(e) => return internal_error(e),
+        }
+    };
+
+    // The vectors are in the order of the components of their kind
+    let (mut text_vectors, mut image_vectors) =
+        (text_vectors.into_iter(), image_vectors.into_iter());
+    let mut weighted = Vec::with_capacity(query.components.len());
+    for component in &query.components {
+        let vector = match component.prompt {
+            Prompt::Text(_) => text_vectors.next(),
+            Prompt::Image(_) => image_vectors.next(),
+        };
+        match vector {
+            Some(vector) => weighted.push((component.weight, vector)),
+            None => return internal_error("the vectorizer returned too few vectors"),
+        }
+    }
+    let combined = match combine(weighted) {
+        Some(combined) => combined,
+        None => return HttpResponse::BadRequest().body("the components cancel each other out"),
+    };
+
+    let limit = query.limit.unwrap_or(5).min(100);
+    match data.near_vector(&combined, limit, &filter).await {
+        Ok(ids) => HttpResponse::Ok().json(ComposedQueryOutput { ids }),
+        Err(e) => internal_error(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(text: &str, weight: f32) -> Component {
+        Component {
+            prompt: Prompt::Text(String::from(text)),
+            weight,
+        }
+    }
+
+    #[test]
+    fn parses_components() {
+        let components = parse_query(r#"+"beach" 1.0 -"people" 0.5 +image:abc-123 0.7"#).unwrap();
+        assert_eq!(
+            components,
+            vec![
+                text("beach", 1.),
+                text("people", -0.5),
+                Component {
+                    prompt: Prompt::Image(String::from("abc-123")),
+                    weight: 0.7,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn defaults_sign_and_weight() {
+        let components = parse_query(r#"  "at night"   -"people"  "#).unwrap();
+        assert_eq!(components, vec![text("at night", 1.), text("people", -1.)]);
+    }
+
+    #[test]
+    fn unescapes_texts() {
+        let components = parse_query(r#"+"a \"quoted\" \\ text" 2"#).unwrap();
+        assert_eq!(components, vec![text(r#"a "quoted" \ text"#, 2.)]);
+    }
+
+    #[test]
+    fn rejects_malformed_queries() {
+        for query in [
+            r#"+"beach"#,
+            "+image: 0.5",
+            "+beach 1.0",
+            r#"+"beach" 1.0x"#,
+            r#"+"beach" --1"#,
+        ] {
+            assert!(parse_query(query).is_err(), "{} was parsed", query);
+        }
+        assert_eq!(parse_query("   ").unwrap(), vec![]);
+    }
+
+    #[test]
+    fn normalizes_before_weighting() {
+        let combined = combine([(1., vec![10., 0.]), (1., vec![0., 0.1])]).unwrap();
+        let expected = std::f32::consts::FRAC_1_SQRT_2;
+        assert!((combined[0] - expected).abs() < 1e-6);
+        assert!((combined[1] - expected).abs() < 1e-6);
+
+        let combined = combine([(3., vec![1., 0.]), (-1., vec![0., 2.])]).unwrap();
+        let norm = 10f32.sqrt();
+        assert!((combined[0] - 3. / norm).abs() < 1e-6);
+        assert!((combined[1] + 1. / norm).abs() < 1e-6);
+    }
+
+    #[test]
+    fn detects_cancelled_components() {
+        assert_eq!(combine([(1., vec![1., 0.]), (-1., vec![3., 0.])]), None);
+    }
+}
@@ -1,10 +1,11 @@
 use std::borrow::Cow;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::ffi::OsString;
 use std::io::{Read, Seek, SeekFrom};
 use std::os::unix::ffi::OsStrExt;
-use std::path::PathBuf;
-use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use itertools::Itertools;
 
@@ -14,34 +15,72 @@ use tempfile::NamedTempFile;
 
 use actix_files::NamedFile;
 use actix_multipart::Multipart;
+use actix_web::http::header::{
+    self, Charset, ContentDisposition, DispositionParam, DispositionType, ExtendedValue,
+};
 use actix_web::web::{Data, Json};
-use actix_web::{web, Either, HttpResponse};
+use actix_web::{web, CustomizeResponder, Either, HttpRequest, HttpResponse, Responder};
 use md5::Digest;
 
 use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
 use sqlx::SqlitePool;
 
+use crate::albums::{self, Album, AlbumSummary, ALBUMS_PROPERTY};
+use crate::auth::{Usage, User, UserSummary};
+use crate::classifiers::{Classifier, ClassifierSummary, LinearProbe, Validation};
+use crate::db::files::{UploadError, UploadLimits};
+use crate::develop::Development;
+use crate::duplicates;
+use crate::formats;
+use crate::http_cache;
 use crate::images::preview;
+use crate::share::Viewer;
+use crate::tags::{ImageTag, TagCount, TagFilter, TAGS_PROPERTY};
+use crate::visibility::{self, Access, Visibility};
+use crate::vocabulary::LabelVectors;
 use crate::weaviate_graphql::{
     QueryResult, VectorizerInput, VectorizerOutput, WeaviateBatchDelete, WeaviateBatchInput,
-    WeaviateInput, WeaviateMatch,
+    WeaviateBatchResult, WeaviateInput, WeaviateMatch, WeaviateObject,
 };
 use crate::{MultiOperator, Operator, WeaviateWhere, WhereValue};
 
 const VECTOR_SEARCH_URL: &str = "http://weaviate:8080/v1/graphql";
 const BATCH_VECTOR_INSERT_URL: &str = "http://weaviate:8080/v1/batch/objects";
 const VECTORIZER_URL: &str = "http://multi2vec-clip:8080/vectorize/";
+const OBJECTS_URL: &str = "http://weaviate:8080/v1/objects";
 
 pub(crate) type Id = String;
 
 #[derive(Deserialize)]
 pub struct Image {
     id: Id,
+    /// The version of the image, from the `X-Image-Version` header. Responses to requests which
+    /// give the current version are cached forever.
+    v: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct Upload {
+    /// Who may see the uploaded images, which is only the uploader by default
+    visibility: Option<Visibility>,
+}
+
+/// What a search is limited to, besides the images which the user may see
+#[derive(Deserialize, Clone)]
+pub struct SearchScope {
+    /// Only searches the images in this album
+    album: Option<Id>,
+    /// Comma separated tags which every result must have
+    tags: Option<String>,
+    /// Comma separated tags which no result may have
+    exclude_tags: Option<String>,
 }
 
 #[derive(Deserialize)]
 pub struct NearText {
     text: String,
+    #[serde(flatten)]
+    scope: SearchScope,
 }
 
 #[derive(Serialize)]
@@ -49,78 +88,57 @@ pub struct NearTextOutput {
     ids: Vec<Id>,
 }
 
+/// Matches the images in `scope` which `user` may see
+pub(crate) async fn search_filter(
+    data: &SQLiteDatabase,
+    user: &User,
+    scope: &SearchScope,
+) -> Result<WeaviateWhere, HttpResponse> {
+    let mut operands = vec![visibility::search_filter(user)];
+    match TagFilter::parse(scope.tags.as_deref(), scope.exclude_tags.as_deref()) {
+        Ok(tags) => operands.extend(tags.search_filter()),
+        Err(reason) => return Err(HttpResponse::BadRequest().body(reason)),
+    }
+    if let Some(album) = &scope.album {
+        match data.album(album).await {
+            Ok(Some(found)) if found.owner == user.name => {
+                operands.push(albums::search_filter(album))
+            }
+            Ok(_) => {
+                return Err(
+                    HttpResponse::NotFound().body(format!("album with id {} not found", album))
+                )
+            }
+            Err(e) => {
+                log::warn!("{:?}", e);
+                return Err(HttpResponse::InternalServerError().body(""));
+            }
+        }
+    }
+    Ok(WeaviateWhere::Multiple {
+        operator: MultiOperator::And,
+        operands,
+    })
+}
+
 pub async fn near_text(
     data: Data<Arc<SQLiteDatabase>>,
+    user: User,
     params: web::Query<NearText>,
 ) -> HttpResponse {
-    async fn inner(
-        data: Data<Arc<SQLiteDatabase>>,
-        params: web::Query<NearText>,
-    ) -> Result<QueryResult> {
-        let text_vec = data
-            .vectorize(VectorizerInput {
-                texts: vec![params.into_inner().text],
-                images: vec![],
-            })
-            .await?
-            .text_vectors
-            .remove(0)
-            .iter()
-            .map(f32::to_string)
-            .join(", ");
-        let mut weaviate_request = HashMap::new();
-
-        let query = format!(
-            "{{
-    Get{{
-      ClipImage(
-        limit: 5,
-        nearVector: {{
-          vector: [{text_vec}]
-        }}
-      ){{
-        _additional {{
-          id
-          certainty
-        }}
-      }}
-    }}
-  }}"
-        );
-        log::info!("sending query: {}", query);
-        weaviate_request.insert("query", query);
-        Ok(data
-            .client
-            .post(VECTOR_SEARCH_URL)
-            .json(&weaviate_request)
-            .send()
-            .await?
-            .json()
-            .await?)
+    async fn inner(data: &SQLiteDatabase, text: String, filter: &WeaviateWhere) -> Result<Vec<Id>> {
+        let text_vec = data.text_vector(text).await?;
+        data.near_vector(&text_vec, 5, filter).await
     }
 
     log::info!("Received request!");
-    match inner(data, params).await {
-        Ok(mut resp) => {
-            log::info!("{:?}", resp);
-            let ids = resp
-                .data
-                .get
-                .remove("ClipImage")
-                .unwrap()
-                .into_iter()
-                .map(|info| {
-                    info.additional
-                        .unwrap()
-                        .remove("id")
-                        .unwrap()
-                        .as_str()
-                        .unwrap()
-                        .to_string()
-                })
-                .collect();
-            HttpResponse::Ok().json(NearTextOutput { ids })
-        }
+    let params = params.into_inner();
+    let filter = match search_filter(&data, &user, &params.scope).await {
+        Ok(filter) => filter,
+        Err(response) => return response,
+    };
+    match inner(&data, params.text, &filter).await {
+        Ok(ids) => HttpResponse::Ok().json(NearTextOutput { ids }),
         Err(e) => {
             log::warn!("{:?}", e);
             HttpResponse::InternalServerError().body("")
@@ -128,15 +146,32 @@ pub async fn near_text(
     }
 }
 
+/// Serves the original file. `NamedFile` answers conditional and `Range` requests itself, so
+/// that interrupted downloads of large RAWs can be resumed.
 pub async fn fetch_raw(
     data: Data<Arc<SQLiteDatabase>>,
+    viewer: Viewer,
     params: web::Query<Image>,
-) -> actix_web::Result<Either<NamedFile, HttpResponse>> {
+) -> actix_web::Result<Either<CustomizeResponder<NamedFile>, HttpResponse>> {
     let image = params.into_inner();
-    match data.get_path(&image.id).await {
-        Ok(path) => {
+    match viewer.file(&data, &image.id).await {
+        Ok(_) if !viewer.may_download_original() => Ok(Either::Right(
+            HttpResponse::Forbidden()
+                .body("the share link does not allow downloading the original"),
+        )),
+        Ok(file) => {
             println!("Successfully serving image with id {}", image.id);
-            Ok(Either::Left(NamedFile::open_async(path).await?))
+            let mut named_file = NamedFile::open_async(&file.path).await?;
+            if let Some(name) = &file.original_name {
+                named_file = named_file.set_content_disposition(attachment(name));
+            }
+            let named_file = named_file
+                .use_etag(true)
+                .use_last_modified(true)
+                .customize()
+                .insert_header(http_cache::cache_control(image.v.as_deref(), &file.md5))
+                .insert_header((http_cache::VERSION_HEADER, http_cache::version(&file.md5)));
+            Ok(Either::Left(named_file))
         }
         Err(_) => Ok(Either::Right(
             HttpResponse::NotFound().body(format!("image with id {} not found", image.id)),
@@ -144,24 +179,157 @@ pub async fn fetch_raw(
     }
 }
 
+/// `Content-Disposition` for downloading a file as `name`. Clients which do not support
+/// RFC 6266's `filename*` fall back to `filename`, with non-ASCII characters replaced.
+fn attachment(name: &str) -> ContentDisposition {
+    let mut parameters = vec![DispositionParam::Filename(
+        name.chars()
+            .map(|c| {
+                if c.is_ascii() && !c.is_ascii_control() {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect(),
+    )];
+    if !name.is_ascii() {
+        parameters.push(DispositionParam::FilenameExt(ExtendedValue {
+            charset: Charset::Ext(String::from("UTF-8")),
+            language_tag: None,
+            value: name.as_bytes().to_vec(),
+        }));
+    }
+    ContentDisposition {
+        disposition: DispositionType::Attachment,
+        parameters,
+    }
+}
+
+/// Whether a stored file still has the contents it was stored with
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Integrity {
+    /// The file matches its recorded hash
+    Intact,
+    /// The file was modified or replaced since it was stored
+    Mismatch,
+    /// The file can no longer be read
+    Missing,
+    /// No hash had been recorded for the file yet, so its current hash was recorded
+    Recorded,
+}
+
+#[derive(Serialize)]
+pub struct VerifyResponse {
+    id: Id,
+    integrity: Integrity,
+    /// Hex encoded BLAKE3 of the file as it is now
+    blake3: Option<String>,
+}
+
+/// Checks the stored file against the hash which was recorded when it was stored
+pub async fn verify(
+    data: Data<Arc<SQLiteDatabase>>,
+    user: User,
+    id: web::Path<Id>,
+) -> HttpResponse {
+    let id = id.into_inner();
+    let file = match data.visible_file(&id, &user).await {
+        Ok(file) => file,
+        Err(sqlx::Error::RowNotFound) => {
+            return HttpResponse::NotFound().body(format!("image with id {} not found", id))
+        }
+        Err(e) => {
+            log::warn!("{:?}", e);
+            return HttpResponse::InternalServerError().body("");
+        }
+    };
+
+    let current = std::fs::File::open(&file.path).and_then(|mut file| content_hash(&mut file));
+    let (integrity, current) = match (current, &file.blake3) {
+        (Err(_), _) => (Integrity::Missing, None),
+        (Ok(current), Some(recorded)) if current.as_bytes().as_slice() == recorded.as_slice() => {
+            (Integrity::Intact, Some(current))
+        }
+        (Ok(current), Some(_)) => (Integrity::Mismatch, Some(current)),
+        (Ok(current), None) => match data.set_content_hash(&id, &current).await {
+            Ok(()) => (Integrity::Recorded, Some(current)),
+            Err(e) => {
+                log::warn!("{:?}", e);
+                return HttpResponse::InternalServerError().body("");
+            }
+        },
+    };
+
+    HttpResponse::Ok().json(VerifyResponse {
+        id,
+        integrity,
+        blake3: current.map(|current| current.to_hex().to_string()),
+    })
+}
+
+/// What happened to an uploaded file
+#[derive(Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum UploadStatus {
+    /// The image was stored and added to the search index
+    Created { id: Id },
+    /// An image with identical contents is already stored
+    Duplicate { existing_id: Id },
+    /// Not a camera RAW, JPEG, PNG, TIFF or WebP image
+    UnsupportedFormat,
+    /// Storing the image would exceed the uploader's quota of images or bytes
+    QuotaExceeded,
+    /// The file looks like a supported format, but could not be decoded
+    DecodeError,
+    /// Identical contents were stored by someone else at the same time. Uploading the file again
+    /// stores it.
+    Conflict,
+    /// The image was stored, but could not be added to the search index, so it will not appear
+    /// in search results until it is re-indexed on the next startup
+    VectorizationFailed { id: Id },
+}
+
+#[derive(Serialize)]
+pub struct UploadResult {
+    /// The file name given by the client
+    name: String,
+    #[serde(flatten)]
+    status: UploadStatus,
+}
+
 #[derive(Serialize)]
 pub struct UploadRawResponse {
-    /// The path and corresponding id, if successfully generated
-    path_ids: HashMap<String, Option<Id>>,
+    /// The outcome of each uploaded file, in the order they were uploaded
+    files: Vec<UploadResult>,
 }
 
-// TODO: File size limits
-// TODO: Auth with file size limits
 // TODO: Want to report exif information for use elsewhere
 pub async fn upload_raw(
     data: Data<Arc<SQLiteDatabase>>,
+    limits: Data<UploadLimits>,
+    user: User,
+    params: web::Query<Upload>,
+    req: HttpRequest,
     payload: Multipart,
 ) -> Either<HttpResponse, Json<UploadRawResponse>> {
-    match files::save_payload(payload).await {
+    // Reject requests which announce that they are too large before reading any of them
+    let content_length = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse::<u64>().ok());
+    if matches!(content_length, Some(length) if length > limits.max_request_bytes) {
+        return Either::Left(upload_error(UploadError::RequestTooLarge, &limits));
+    }
+
+    match files::save_payload(payload, &limits).await {
         Ok(files) => {
             // TODO: time between read and use error
-            match data.store_images(files).await {
-                Ok(Some(path_ids)) => Either::Right(Json(UploadRawResponse { path_ids })),
+            let visibility = params.visibility.unwrap_or(Visibility::Private);
+            match data.store_images(files, &user, visibility).await {
+                Ok(Some(response)) => Either::Right(Json(response)),
                 _ => Either::Left(
                     HttpResponse::InternalServerError()
                         .content_type("text/plain")
@@ -169,40 +337,218 @@ pub async fn upload_raw(
                 ),
             }
         }
-        _ => Either::Left(
-            HttpResponse::BadRequest()
-                .content_type("text/plain")
-                .body("upload failed"),
-        ),
+        Err(e) => Either::Left(upload_error(e, &limits)),
     }
 }
 
+fn upload_error(e: UploadError, limits: &UploadLimits) -> HttpResponse {
+    let (mut response, message) = match e {
+        UploadError::FileTooLarge(name) => (
+            HttpResponse::PayloadTooLarge(),
+            format!(
+                "{} is larger than the limit of {} KB per file",
+                name,
+                limits.max_file_bytes / 1024
+            ),
+        ),
+        UploadError::RequestTooLarge => (
+            HttpResponse::PayloadTooLarge(),
+            format!(
+                "upload is larger than the limit of {} KB per request",
+                limits.max_request_bytes / 1024
+            ),
+        ),
+        UploadError::TooManyFiles => (
+            HttpResponse::PayloadTooLarge(),
+            format!(
+                "upload has more than the limit of {} files per request",
+                limits.max_files
+            ),
+        ),
+        UploadError::UnsupportedFormat(name) => (
+            HttpResponse::UnsupportedMediaType(),
+            format!(
+                "{} is not a camera RAW, JPEG, PNG, TIFF or WebP image",
+                name
+            ),
+        ),
+        UploadError::Payload(e) => {
+            log::warn!("{:?}", e);
+            (HttpResponse::BadRequest(), "upload failed".to_string())
+        }
+    };
+    response.content_type("text/plain").body(message)
+}
+
 pub mod files {
     use std::io::Write;
 
     use actix_multipart::Multipart;
+    use actix_web::http::header::ContentDisposition;
     use futures::{StreamExt, TryStreamExt};
 
     use tempfile::NamedTempFile;
 
+    use crate::formats;
+
+    /// Enough of the start of a file for `formats::sniff`
+    const HEADER_LEN: usize = 16;
+
+    /// Bounds on what a single upload may store, so that one request cannot fill the disk
+    pub struct UploadLimits {
+        pub max_file_bytes: u64,
+        pub max_request_bytes: u64,
+        pub max_files: usize,
+    }
+
+    impl UploadLimits {
+        /// Reads the limits from `MAX_FILE_SIZE_KB`, `MAX_UPLOAD_SIZE_KB` and `MAX_UPLOAD_FILES`
+        pub fn from_env() -> Self {
+            fn var(name: &str) -> Option<u64> {
+                std::env::var(name)
+                    .ok()
+                    .and_then(|value| value.parse().ok())
+            }
+            let max_file_kb = var("MAX_FILE_SIZE_KB").unwrap_or(200_000);
+            let max_request_kb = var("MAX_UPLOAD_SIZE_KB").unwrap_or(max_file_kb * 10);
+            UploadLimits {
+                max_file_bytes: max_file_kb * 1024,
+                max_request_bytes: max_request_kb * 1024,
+                max_files: var("MAX_UPLOAD_FILES").unwrap_or(100) as usize,
+            }
+        }
+    }
+
+    /// Why an upload was aborted before it was stored
+    #[derive(Debug)]
+    pub enum UploadError {
+        /// The named file is larger than the per-file limit
+        FileTooLarge(String),
+        /// The files together are larger than the per-request limit
+        RequestTooLarge,
+        TooManyFiles,
+        /// The named file is not in a supported format
+        UnsupportedFormat(String),
+        Payload(actix_web::Error),
+    }
+
+    impl From<actix_web::Error> for UploadError {
+        fn from(e: actix_web::Error) -> Self {
+            UploadError::Payload(e)
+        }
+    }
+
+    impl From<actix_multipart::MultipartError> for UploadError {
+        fn from(e: actix_multipart::MultipartError) -> Self {
+            UploadError::Payload(e.into())
+        }
+    }
+
+    impl From<std::io::Error> for UploadError {
+        fn from(e: std::io::Error) -> Self {
+            UploadError::Payload(e.into())
+        }
+    }
+
+    /// Streams each field of `payload` to a temporary file. Checks the format of each file as
+    /// soon as its first bytes arrive, and stops reading as soon as a limit is exceeded.
     pub async fn save_payload(
         mut payload: Multipart,
-    ) -> actix_web::Result<Vec<(NamedTempFile, String)>> {
+        limits: &UploadLimits,
+    ) -> Result<Vec<(NamedTempFile, String)>, UploadError> {
         // iterate over multipart stream
         let mut files = vec![];
+        let mut request_bytes = 0;
         while let Some(mut field) = payload.try_next().await? {
+            if files.len() == limits.max_files {
+                return Err(UploadError::TooManyFiles);
+            }
+            let name = file_name(field.content_disposition(), field.name());
+            let extension = name
+                .rsplit_once('.')
+                .map(|(_, ext)| ext.to_ascii_lowercase());
             let mut file = NamedTempFile::new()?;
+            let mut header = Vec::with_capacity(HEADER_LEN);
+            let mut file_bytes = 0;
 
             // Field in turn is stream of *Bytes* object
             while let Some(chunk) = field.next().await {
-                file.write_all(&chunk?)?;
+                let chunk = chunk?;
+                file_bytes += chunk.len() as u64;
+                request_bytes += chunk.len() as u64;
+                if file_bytes > limits.max_file_bytes {
+                    return Err(UploadError::FileTooLarge(name));
+                }
+                if request_bytes > limits.max_request_bytes {
+                    return Err(UploadError::RequestTooLarge);
+                }
+                if header.len() < HEADER_LEN {
+                    let missing = HEADER_LEN - header.len();
+                    header.extend_from_slice(&chunk[..missing.min(chunk.len())]);
+                    if header.len() == HEADER_LEN
+                        && formats::sniff(&header, extension.as_deref()).is_none()
+                    {
+                        return Err(UploadError::UnsupportedFormat(name));
+                    }
+                }
+                file.write_all(&chunk)?;
+            }
+            // Files shorter than the header are only checked once they are complete
+            if header.len() < HEADER_LEN && formats::sniff(&header, extension.as_deref()).is_none()
+            {
+                return Err(UploadError::UnsupportedFormat(name));
             }
 
-            files.push((file, field.name().to_string()));
+            files.push((file, name));
         }
 
         Ok(files)
     }
+
+    /// The name of the uploaded file, preferring the UTF-8 `filename*` over `filename`, and
+    /// falling back to the name of the field, which older clients set to the file name.
+    fn file_name(content_disposition: &ContentDisposition, field_name: &str) -> String {
+        let name = match content_disposition.get_filename_ext() {
+            Some(ext) => String::from_utf8_lossy(&ext.value).into_owned(),
+            None => content_disposition
+                .get_filename()
+                .unwrap_or(field_name)
+                .to_string(),
+        };
+        // Some clients send the full path on their machine
+        match name.rsplit(['/', '\\']).next() {
+            Some(base_name) if !base_name.is_empty() => base_name.to_string(),
+            _ => name,
+        }
+    }
+}
+
+/// A stored image, and the md5 which identifies its contents
+pub(crate) struct StoredFile {
+    pub(crate) path: PathBuf,
+    pub(crate) md5: Vec<u8>,
+    pub(crate) original_name: Option<String>,
+    /// BLAKE3 of the full contents, if it has been recorded
+    pub(crate) blake3: Option<Vec<u8>>,
+}
+
+struct SqlxFile {
+    path: Vec<u8>,
+    md5: Vec<u8>,
+    original_name: Option<String>,
+    blake3: Option<Vec<u8>>,
+}
+
+impl From<SqlxFile> for StoredFile {
+    fn from(file: SqlxFile) -> Self {
+        use std::os::unix::ffi::OsStringExt;
+        StoredFile {
+            path: OsString::from_vec(file.path).into(),
+            md5: file.md5,
+            original_name: file.original_name,
+            blake3: file.blake3,
+        }
+    }
 }
 
 pub struct SQLiteDatabase {
@@ -210,14 +556,51 @@ pub struct SQLiteDatabase {
     image_upload_dir: PathBuf,
     path: PathBuf,
     client: reqwest::Client,
+    /// The vocabulary which new images are tagged with, if there is one
+    label_vectors: RwLock<Option<Arc<LabelVectors>>>,
+}
+
+/// Seconds since the Unix epoch
+pub(crate) fn unix_time() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+/// Hashes the full contents of `file`, without reading it into memory at once
+fn content_hash(file: &mut std::fs::File) -> std::io::Result<blake3::Hash> {
+    let mut hasher = blake3::Hasher::new();
+    file.seek(SeekFrom::Start(0))?;
+    std::io::copy(file, &mut hasher)?;
+    Ok(hasher.finalize())
+}
+
+/// What is derived from an image when it is ingested
+struct ImageMetadata {
+    preview_md5: Digest,
+    /// Perceptual hash of the preview, if it could be hashed. Images without one are only left
+    /// out of duplicate search.
+    dhash: Option<u64>,
+    /// Base64 encoded preview, which is vectorized
+    preview: String,
+    /// Size of the file in bytes
+    size: u64,
 }
 
-fn image_metadata(file: &mut std::fs::File) -> Option<(Digest, String)> {
+fn image_metadata(file: &mut std::fs::File, path: &Path) -> Option<ImageMetadata> {
+    let format = formats::sniff_file(file, formats::extension(path)).ok()??;
+    let size = file.metadata().ok()?.len();
     let mut bytes = Vec::new();
     file.seek(SeekFrom::Start(0)).ok()?;
     file.read_to_end(&mut bytes).ok()?;
-    let bytes = preview(&bytes)?;
-    Some((md5::compute(&bytes), base64::encode(&bytes)))
+    let bytes = preview(&bytes, format)?;
+    Some(ImageMetadata {
+        preview_md5: md5::compute(&bytes),
+        dhash: duplicates::dhash(&bytes),
+        preview: base64::encode(&bytes),
+        size,
+    })
 }
 
 #[derive(Debug)]
@@ -253,6 +636,131 @@ impl From<std::io::Error> for Error {
 
 type Result<T, E = Error> = std::result::Result<T, E>;
 
+/// Schema changes, in the order they were introduced. Never edit a migration once it has been
+/// released - add a new one instead.
+const MIGRATIONS: &[&[&str]] = &[
+    // Databases created before migrations were introduced already have these, hence IF NOT EXISTS
+    &[
+        "CREATE TABLE IF NOT EXISTS `files` (`id` TEXT NOT NULL UNIQUE, `md5` BLOB NOT NULL, `path` BLOB NOT NULL);",
+        "CREATE INDEX IF NOT EXISTS file_ids ON files(id);",
+        "CREATE INDEX IF NOT EXISTS hashes ON files(md5);",
+    ],
+    &[
+        "ALTER TABLE files ADD COLUMN original_name TEXT;",
+        "ALTER TABLE files ADD COLUMN uploaded_at INTEGER;",
+        "ALTER TABLE files ADD COLUMN uploader TEXT;",
+    ],
+    // Make ids the primary key, which SQLite can only do by rebuilding the table
+    &[
+        "CREATE TABLE `files_new` (`id` TEXT PRIMARY KEY NOT NULL, `md5` BLOB NOT NULL, `path` BLOB NOT NULL, `original_name` TEXT, `uploaded_at` INTEGER, `uploader` TEXT);",
+        "INSERT INTO files_new (id, md5, path, original_name, uploaded_at, uploader) SELECT id, md5, path, original_name, uploaded_at, uploader FROM files;",
+        "DROP TABLE files;",
+        "ALTER TABLE files_new RENAME TO files;",
+        "CREATE INDEX IF NOT EXISTS hashes ON files(md5);",
+    ],
+    // Hash of the full file, since different files can share a preview. Filled in for existing
+    // files in the background on startup, or when they are verified.
+    &[
+        "ALTER TABLE files ADD COLUMN blake3 BLOB;",
+        "CREATE INDEX IF NOT EXISTS content_hashes ON files(blake3);",
+    ],
+    &["ALTER TABLE files ADD COLUMN dhash INTEGER;"],
+    // Users and their API keys. Uploaders are users from here on, whose quotas count the sizes
+    // of their files.
+    &[
+        "CREATE TABLE `users` (`id` INTEGER PRIMARY KEY, `name` TEXT NOT NULL UNIQUE, `is_admin` BOOLEAN NOT NULL DEFAULT FALSE, `max_bytes` INTEGER, `max_images` INTEGER);",
+        "CREATE TABLE `api_keys` (`id` TEXT PRIMARY KEY NOT NULL, `user_id` INTEGER NOT NULL REFERENCES users(id), `hash` BLOB NOT NULL UNIQUE, `created_at` INTEGER NOT NULL, `revoked_at` INTEGER);",
+        "ALTER TABLE files ADD COLUMN size INTEGER;",
+        "CREATE INDEX IF NOT EXISTS uploaders ON files(uploader);",
+    ],
+    // Who may see each image. Mounted images and images uploaded before users existed stay
+    // visible to everyone, while images which users uploaded become private to them. The same
+    // properties are stored in weaviate to filter searches, and images whose properties have not
+    // reached weaviate yet are not synced.
+    &[
+        "ALTER TABLE users ADD COLUMN group_name TEXT;",
+        "ALTER TABLE files ADD COLUMN owner TEXT;",
+        "ALTER TABLE files ADD COLUMN owner_group TEXT;",
+        "ALTER TABLE files ADD COLUMN visibility TEXT NOT NULL DEFAULT 'public';",
+        "ALTER TABLE files ADD COLUMN properties_synced BOOLEAN NOT NULL DEFAULT FALSE;",
+        "UPDATE files SET owner = uploader;",
+        "UPDATE files SET visibility = 'private' WHERE uploader IS NOT NULL;",
+        "CREATE INDEX IF NOT EXISTS owners ON files(owner);",
+    ],
+    // Albums, whose images are ordered by position. Images leave their albums when they are
+    // removed, and the albums of each image are stored in weaviate to filter searches.
+    &[
+        "CREATE TABLE `albums` (`id` TEXT PRIMARY KEY NOT NULL, `owner` TEXT NOT NULL, `name` TEXT NOT NULL, `cover` TEXT REFERENCES files(id) ON DELETE SET NULL, `created_at` INTEGER NOT NULL);",
+        "CREATE TABLE `album_images` (`album_id` TEXT NOT NULL REFERENCES albums(id) ON DELETE CASCADE, `image_id` TEXT NOT NULL REFERENCES files(id) ON DELETE CASCADE, `position` INTEGER NOT NULL, PRIMARY KEY (`album_id`, `image_id`));",
+        "CREATE INDEX IF NOT EXISTS album_owners ON albums(owner);",
+        "CREATE INDEX IF NOT EXISTS image_albums ON album_images(image_id);",
+    ],
+    // Tags, which are shared by all users. They are stored in weaviate like albums.
+    &[
+        "CREATE TABLE `tags` (`id` INTEGER PRIMARY KEY, `name` TEXT NOT NULL UNIQUE);",
+        "CREATE TABLE `image_tags` (`image_id` TEXT NOT NULL REFERENCES files(id) ON DELETE CASCADE, `tag_id` INTEGER NOT NULL REFERENCES tags(id) ON DELETE CASCADE, PRIMARY KEY (`image_id`, `tag_id`));",
+        "CREATE INDEX IF NOT EXISTS tagged_images ON image_tags(tag_id);",
+    ],
+    // How confident auto-tagging was in the tags it assigned. Manually assigned tags have none.
+    &["ALTER TABLE image_tags ADD COLUMN confidence REAL;"],
+    // Classifiers which users trained on their own examples, and which of the tags that
+    // auto-tagging assigned came from them rather than from the vocabulary
+    &[
+        "CREATE TABLE `classifiers` (`id` TEXT PRIMARY KEY NOT NULL, `owner` TEXT NOT NULL, `name` TEXT NOT NULL, `weights` BLOB NOT NULL, `bias` REAL NOT NULL, `auto_tag` BOOLEAN NOT NULL DEFAULT FALSE, `positives` INTEGER NOT NULL, `negatives` INTEGER NOT NULL, `precision` REAL, `recall` REAL, `created_at` INTEGER NOT NULL, UNIQUE (`owner`, `name`));",
+        "ALTER TABLE image_tags ADD COLUMN classifier TEXT REFERENCES classifiers(id) ON DELETE CASCADE;",
+        "CREATE INDEX IF NOT EXISTS classifier_tags ON image_tags(classifier);",
+    ],
+    // RAW presets which users stored, alongside those in `raw_presets.ron`
+    &["CREATE TABLE `raw_presets` (`name` TEXT PRIMARY KEY NOT NULL, `owner` TEXT NOT NULL, `settings` TEXT NOT NULL, `updated_at` INTEGER NOT NULL);"],
+    // Whether each image has been added to the search index. Images which were stored before
+    // this was recorded are checked against weaviate when the library is re-indexed.
+    &["ALTER TABLE files ADD COLUMN indexed BOOLEAN NOT NULL DEFAULT FALSE;"],
+];
+
+/// Removes files which were moved into the upload directory by an upload which failed before
+/// they were added
+fn remove_stored(entries: Vec<(Id, PathBuf, blake3::Hash)>) {
+    entries
+        .into_iter()
+        .for_each(|(_, path, _)| drop(std::fs::remove_file(path)));
+}
+
+/// How many images are vectorized at once when re-indexing
+const REINDEX_BATCH_SIZE: usize = 32;
+
+/// How often search properties which failed to sync are synced again
+const SYNC_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Syncs the search properties which changed while weaviate was unreachable, on startup and then
+/// periodically, for as long as the server runs
+pub(crate) async fn retry_search_properties(db: Arc<SQLiteDatabase>) {
+    let mut interval = actix_web::rt::time::interval(SYNC_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = db.sync_search_properties().await {
+            log::warn!("Syncing search properties failed: {:?}", e);
+        }
+    }
+}
+
+/// Namespace of the UUIDv5 ids which are derived from image contents
+const ID_NAMESPACE: uuid::Uuid = uuid::Uuid::from_u128(0x5e0c_1a7e_86d4_4c5b_9f0e_3b2d_7a61_c4f8);
+
+enum ContentId {
+    /// No stored image has this id
+    New(Id),
+    /// An image with identical contents is already stored with this id
+    Existing(Id),
+}
+
+/// Where an image came from
+pub(crate) struct Origin {
+    /// The file name given by the client, or the name of the file in the mounted directory
+    pub(crate) original_name: String,
+    pub(crate) uploader: Option<String>,
+    pub(crate) access: Access,
+}
+
 impl SQLiteDatabase {
     pub(crate) async fn open<P>(file_path: P, image_upload_dir: PathBuf) -> Result<Self>
     where
@@ -269,7 +777,9 @@ impl SQLiteDatabase {
             .connect_with(
                 SqliteConnectOptions::new()
                     .filename(&file_path)
-                    .create_if_missing(true),
+                    .create_if_missing(true)
+                    // Album membership is cleaned up through cascading deletes
+                    .foreign_keys(true),
             )
             .await?;
 
@@ -278,22 +788,36 @@ impl SQLiteDatabase {
             path: file_path.as_ref().to_path_buf(),
             image_upload_dir,
             client: reqwest::Client::new(),
+            label_vectors: RwLock::new(None),
         };
 
-        for query in [
-            "CREATE TABLE IF NOT EXISTS `files` (`id` TEXT NOT NULL UNIQUE, `md5` BLOB NOT NULL, `path` BLOB NOT NULL);",
-            "CREATE INDEX IF NOT EXISTS file_ids ON files(id);",
-            "CREATE INDEX IF NOT EXISTS hashes ON files(md5);",
-        ] {
-            sqlx::query(query)
-                .execute(&db.connection)
+        db.migrate().await?;
+
+        Ok(db)
+    }
+
+    /// Applies the migrations which have not been applied yet, recording the number of applied
+    /// migrations in the database's `user_version`.
+    async fn migrate(&self) -> Result<()> {
+        let mut tx = self.connection.begin().await?;
+        let (version,): (i64,) = sqlx::query_as("PRAGMA user_version")
+            .fetch_one(&mut tx)
+            .await?;
+
+        for (index, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+            log::info!("Applying database migration {}", index + 1);
+            for query in migration.iter() {
+                sqlx::query(query).execute(&mut tx).await?;
+            }
+            sqlx::query(&format!("PRAGMA user_version = {}", index + 1))
+                .execute(&mut tx)
                 .await?;
         }
 
-        Ok(db)
+        Ok(tx.commit().await?)
     }
 
-    async fn vectorize(&self, target: VectorizerInput<'_>) -> Result<VectorizerOutput> {
+    pub(crate) async fn vectorize(&self, target: VectorizerInput<'_>) -> Result<VectorizerOutput> {
         Ok(self
             .client
             .post(VECTORIZER_URL)
@@ -304,6 +828,18 @@ impl SQLiteDatabase {
             .await?)
     }
 
+    /// The CLIP vector of `text`
+    pub(crate) async fn text_vector(&self, text: String) -> Result<Vec<f32>> {
+        Ok(self
+            .vectorize(VectorizerInput {
+                texts: vec![text],
+                images: vec![],
+            })
+            .await?
+            .text_vectors
+            .remove(0))
+    }
+
     /// Deletes all items with the corresponding paths
     pub(crate) async fn remove_paths(&self, paths: &[PathBuf]) -> Result<()> {
         // TODO: Search by hash
@@ -349,189 +885,540 @@ impl SQLiteDatabase {
         Ok(tx.commit().await?)
     }
 
-    async fn store_images(
+    pub(crate) async fn store_images(
         &self,
         files: Vec<(NamedTempFile, String)>,
-    ) -> Result<Option<HashMap<String, Option<Id>>>> {
+        user: &User,
+        visibility: Visibility,
+    ) -> Result<Option<UploadRawResponse>> {
+        // Concurrent uploads by the same user may overshoot the quota by the size of an upload
+        let mut usage = self.usage(&user.name).await?;
         let mut image_files = vec![];
-        let mut entries = vec![];
-        let mut path_map = HashMap::new();
-        for (file, name) in files.into_iter() {
-            // TODO: Handle collisions (very important, can't risk overlap)
-            let id = uuid::Uuid::new_v4().to_string();
-            let path = match name.rsplit_once(".") {
-                None => continue,
-                Some((_, ext)) => {
-                    let mut root = self.image_upload_dir.clone();
-                    root.push(format!("{}.{}", id, ext));
-                    root
+        let mut entries: Vec<(Id, PathBuf, blake3::Hash)> = vec![];
+        let mut origins = HashMap::new();
+        // The result of each file, which is filled in for stored files once they are added
+        let mut results = vec![];
+        let mut pending = HashMap::new();
+        for (mut file, name) in files.into_iter() {
+            let extension = name
+                .rsplit_once('.')
+                .map(|(_, ext)| ext.to_ascii_lowercase());
+            let access = Access::owned_by(user, visibility);
+            let identified = async {
+                let format = match formats::sniff_file(file.as_file_mut(), extension.as_deref())? {
+                    Some(format) => format,
+                    None => return Ok(None),
+                };
+                // Uploads may be large RAWs, which are hashed as they are read
+                let mut handle = file.as_file().try_clone()?;
+                let (content_hash, size) = web::block(move || {
+                    let content_hash = content_hash(&mut handle)?;
+                    Ok::<_, std::io::Error>((content_hash, handle.metadata()?.len()))
+                })
+                .await
+                .map_err(actix_web::Error::from)??;
+                let id = self.content_id(&content_hash, &access).await?;
+                Ok::<_, Error>(Some((format, content_hash, size, id)))
+            }
+            .await;
+            let (format, content_hash, size, id) = match identified {
+                Ok(Some(identified)) => identified,
+                Ok(None) => {
+                    results.push((name, Some(UploadStatus::UnsupportedFormat)));
+                    continue;
                 }
+                Err(e) => {
+                    drop(image_files);
+                    remove_stored(entries);
+                    return Err(e);
+                }
+            };
+            // Keep the original extension, unless it does not match the contents
+            let extension = extension
+                .filter(|ext| format.extensions.contains(&ext.as_str()))
+                .unwrap_or_else(|| format.extensions[0].to_string());
+
+            let id = match id {
+                // Uploaded twice in this request
+                ContentId::New(id) if entries.iter().any(|(entry_id, _, _)| *entry_id == id) => {
+                    results.push((name, Some(UploadStatus::Duplicate { existing_id: id })));
+                    continue;
+                }
+                ContentId::New(id) => id,
+                ContentId::Existing(existing_id) => {
+                    results.push((name, Some(UploadStatus::Duplicate { existing_id })));
+                    continue;
+                }
+            };
+            let size = size as i64;
+            if !usage.allows(user, size) {
+                results.push((name, Some(UploadStatus::QuotaExceeded)));
+                continue;
+            }
+            usage.images += 1;
+            usage.bytes += size;
+            let path = {
+                let mut root = self.image_upload_dir.clone();
+                root.push(format!("{}.{}", id, extension));
+                root
             };
 
             let file = match file.persist(&path) {
                 Ok(file) => file,
                 Err(_) => {
                     drop(image_files);
-                    entries
-                        .into_iter()
-                        .for_each(|(_, file_name)| drop(std::fs::remove_file(file_name)));
+                    remove_stored(entries);
                     return Ok(None);
                 }
             };
 
-            path_map.insert(path.clone(), name);
+            origins.insert(
+                path.clone(),
+                Origin {
+                    original_name: name.clone(),
+                    uploader: Some(user.name.clone()),
+                    access,
+                },
+            );
+            pending.insert(path.clone(), (results.len(), id.clone()));
+            results.push((name, None));
 
-            entries.push((id, path));
+            entries.push((id, path, content_hash));
             image_files.push(file);
         }
 
-        match self.add_files(entries, image_files).await {
-            Ok(Some(ids)) => Ok(Some(
-                ids.into_iter()
-                    .map(|(path, id)| (path_map.remove(&path).unwrap(), id.map(|(_, id)| id)))
-                    .collect(),
-            )),
+        let statuses = match self.add_files(entries, image_files, origins).await {
+            Ok(statuses) => statuses,
             Err(e) => {
-                path_map
-                    .into_iter()
-                    .for_each(|(path, _)| drop(std::fs::remove_file(path)));
+                pending
+                    .into_keys()
+                    .for_each(|path| drop(std::fs::remove_file(path)));
 
                 return Err(e);
             }
-            Ok(None) => {
-                path_map
-                    .into_iter()
-                    .for_each(|(path, _)| drop(std::fs::remove_file(path)));
+        };
+
+        for (path, status) in statuses {
+            let (index, id) = match pending.remove(&path) {
+                Some(pending) => pending,
+                None => continue,
+            };
+            // Files which were not stored are not referenced by any entry. A duplicate or conflict
+            // with the same id was stored concurrently, at the same path.
+            let unreferenced = match &status {
+                UploadStatus::DecodeError => true,
+                UploadStatus::Duplicate { existing_id } => *existing_id != id,
+                _ => false,
+            };
+            if unreferenced {
+                drop(std::fs::remove_file(&path));
+            }
+            results[index].1 = Some(status);
+        }
+
+        Ok(Some(UploadRawResponse {
+            files: results
+                .into_iter()
+                .map(|(name, status)| UploadResult {
+                    name,
+                    status: status.unwrap_or(UploadStatus::DecodeError),
+                })
+                .collect(),
+        }))
+    }
 
-                return Ok(None);
+    /// Derives the id of an image from the BLAKE3 hash of its contents, so that importing the same
+    /// file always gives the same id. Identical files which whoever imports it with `access` may
+    /// see are found by their hash, including those stored before ids were derived from it. If
+    /// the id is taken by a different file, or by the same file which they may not see, a salted
+    /// id is derived instead. Otherwise, uploads would reveal that others stored an image
+    /// privately.
+    async fn content_id(&self, content_hash: &blake3::Hash, access: &Access) -> Result<ContentId> {
+        if let Some(existing) = self.visible_duplicate(content_hash, access).await? {
+            return Ok(ContentId::Existing(existing));
+        }
+        let base = uuid::Uuid::new_v5(&ID_NAMESPACE, content_hash.as_bytes());
+        for salt in 0u32.. {
+            let id = match salt {
+                0 => base,
+                _ => uuid::Uuid::new_v5(&ID_NAMESPACE, format!("{}/{}", base, salt).as_bytes()),
+            }
+            .to_string();
+            match self.get_file(&id).await {
+                Err(sqlx::Error::RowNotFound) => return Ok(ContentId::New(id)),
+                Err(e) => return Err(e.into()),
+                // The same file, which they may not see
+                Ok(file) if file.blake3.as_deref() == Some(content_hash.as_bytes().as_slice()) => {}
+                Ok(file) => log::warn!(
+                    "Image id {} collides with {}, salting",
+                    id,
+                    file.path.display()
+                ),
             }
         }
+        unreachable!("ran out of salts for image id")
     }
 
     pub(crate) async fn add_paths(
         &self,
         paths: &[PathBuf],
-    ) -> Result<Option<HashMap<PathBuf, Option<(Digest, Id)>>>> {
+    ) -> Result<HashMap<PathBuf, UploadStatus>> {
         let mut image_files = vec![];
         let mut entries = vec![];
+        let mut origins = HashMap::new();
         for path in paths.iter() {
-            // TODO: Handle collisions (very important, can't risk overlap)
-            let id = uuid::Uuid::new_v4().to_string();
-
-            let file = match std::fs::File::open(&path) {
+            let mut file = match std::fs::File::open(&path) {
                 Ok(file) => file,
                 Err(_) => continue,
             };
+            let content_hash = match content_hash(&mut file) {
+                Ok(content_hash) => content_hash,
+                Err(_) => continue,
+            };
+            let id = match self.content_id(&content_hash, &Access::public()).await? {
+                ContentId::New(id) if !entries.iter().any(|(entry_id, _, _)| *entry_id == id) => id,
+                _ => continue,
+            };
 
-            entries.push((id, path.clone()));
+            if let Some(name) = path.file_name() {
+                origins.insert(
+                    path.clone(),
+                    Origin {
+                        original_name: name.to_string_lossy().into_owned(),
+                        uploader: None,
+                        access: Access::public(),
+                    },
+                );
+            }
+            entries.push((id, path.clone(), content_hash));
             image_files.push(file);
         }
 
-        self.add_files(entries, image_files).await
+        self.add_files(entries, image_files, origins).await
     }
 
+    /// Records the entries in the database. Entries without metadata could not be decoded.
     async fn add_entries(
         &self,
-        mut entries: HashMap<PathBuf, Option<(Digest, Id)>>,
-    ) -> Result<HashMap<PathBuf, Option<(Digest, Id)>>> {
+        entries: Vec<(Id, PathBuf, blake3::Hash)>,
+        origins: &HashMap<PathBuf, Origin>,
+        metadata: &HashMap<Id, ImageMetadata>,
+    ) -> Result<HashMap<PathBuf, UploadStatus>> {
+        let uploaded_at = unix_time();
+        let mut statuses = HashMap::new();
         let mut tx = self.connection.begin().await?;
 
-        for (path, digest_id) in entries.iter_mut() {
-            let (digest, id) = if let Some(inner) = digest_id {
-                inner
-            } else {
-                continue;
+        for (id, path, content_hash) in entries {
+            let metadata = match metadata.get(&id) {
+                Some(metadata) => metadata,
+                None => {
+                    statuses.insert(path, UploadStatus::DecodeError);
+                    continue;
+                }
             };
 
-            let digest_bytes = digest.as_ref();
-            if sqlx::query!("SELECT * FROM files WHERE md5=?", digest_bytes)
-                .fetch_optional(&mut tx)
-                .await?
-                .is_some()
+            let digest_bytes = metadata.preview_md5.as_ref();
+            let content_hash = content_hash.as_bytes().as_slice();
+            let dhash = metadata.dhash.map(|hash| hash as i64);
+            let size = metadata.size as i64;
+            let path_bytes = path.as_os_str().as_bytes();
+            let origin = origins.get(&path);
+            let original_name = origin.map(|origin| origin.original_name.as_str());
+            let uploader = origin.and_then(|origin| origin.uploader.as_deref());
+            let access = origin.map_or_else(Access::public, |origin| origin.access.clone());
+            let visibility = access.visibility.as_str();
+            // Files with the same preview may still differ, so only identical contents are
+            // duplicates, and only of images which the importer may see
+            if let Some(existing) = sqlx::query!(
+                "SELECT id FROM files WHERE blake3 = ? AND (visibility = 'public' OR owner = ? OR (visibility = 'group' AND owner_group = ?))",
+                content_hash,
+                access.owner,
+                access.group
+            )
+            .fetch_optional(&mut tx)
+            .await?
             {
-                *digest_id = None;
+                statuses.insert(
+                    path,
+                    UploadStatus::Duplicate {
+                        existing_id: existing.id,
+                    },
+                );
                 continue;
             }
 
-            let id = id.as_str();
-            let path_bytes = path.as_os_str().as_bytes();
-            sqlx::query!(
-                "INSERT INTO files (id, md5, path) VALUES(?, ?, ?);",
+            // The primary key rejects ids which were taken since they were derived, by a
+            // concurrent import of the same file
+            let inserted = sqlx::query!(
+                "INSERT OR IGNORE INTO files (id, md5, blake3, dhash, size, path, original_name, uploaded_at, uploader, owner, owner_group, visibility) VALUES(?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?);",
                 id,
                 digest_bytes,
-                path_bytes
+                content_hash,
+                dhash,
+                size,
+                path_bytes,
+                original_name,
+                uploaded_at,
+                uploader,
+                access.owner,
+                access.group,
+                visibility
             )
             .execute(&mut tx)
-            .await?;
+            .await?
+            .rows_affected();
+            let status = if inserted > 0 {
+                UploadStatus::Created { id }
+            } else if sqlx::query!(
+                "SELECT id FROM files WHERE id = ? AND (visibility = 'public' OR owner = ? OR (visibility = 'group' AND owner_group = ?))",
+                id,
+                access.owner,
+                access.group
+            )
+            .fetch_optional(&mut tx)
+            .await?
+            .is_some()
+            {
+                UploadStatus::Duplicate { existing_id: id }
+            } else {
+                UploadStatus::Conflict
+            };
+            statuses.insert(path, status);
         }
 
         tx.commit().await?;
-        Ok(entries)
+        Ok(statuses)
     }
 
-    async fn add_files(
+    /// Vectorizes the previews of the images with `ids` and adds them to the search index, along
+    /// with who may see them and the tags of the vocabulary and of their owners' classifiers which
+    /// they match, returning the ids which were added
+    async fn index_images(
         &self,
-        entries: Vec<(Id, PathBuf)>,
-        mut image_files: Vec<std::fs::File>,
-    ) -> Result<Option<HashMap<PathBuf, Option<(Digest, Id)>>>> {
-        let start = std::time::Instant::now();
-        let metadata: HashMap<Id, (Digest, String)> = entries
-            .par_iter()
-            .map(|(id, _)| id)
-            .zip(image_files.par_iter_mut().map(image_metadata))
-            .flat_map(|(id, preview)| preview.map(|preview| (id.clone(), preview)))
-            .collect();
-        let entries: HashMap<_, _> = entries
-            .into_iter()
-            .map(|(id, path)| {
-                (
-                    path,
-                    metadata.get(&id).map(|(digest, _)| (digest.clone(), id)),
-                )
-            })
-            .collect();
-
-        println!(
-            "Generated {} previews in {}s",
-            entries.len(),
-            start.elapsed().as_secs_f32()
-        );
-
-        let entries = self.add_entries(entries).await?;
-
-        // two queries - vectorize images, then upload to db
+        ids: &[(Id, Access)],
+        metadata: &HashMap<Id, ImageMetadata>,
+    ) -> Result<HashSet<Id>> {
         let vectors = self
             .vectorize(VectorizerInput {
                 texts: vec![],
-                images: metadata.values().map(|(_, im)| Cow::from(im)).collect(),
+                images: ids
+                    .iter()
+                    .map(|(id, _)| Cow::from(&metadata[id].preview))
+                    .collect(),
             })
             .await?;
 
-        let mut metadata: HashMap<_, _> = metadata
-            .into_iter()
-            .map(|(a, _)| a)
-            .zip(vectors.image_vectors.into_iter())
-            .collect();
+        let label_vectors = self.label_vectors();
+        let mut classifiers = HashMap::new();
+        for owner in ids.iter().filter_map(|(_, access)| access.owner.as_ref()) {
+            if !classifiers.contains_key(owner) {
+                classifiers.insert(owner.clone(), self.auto_tag_classifiers(owner).await?);
+            }
+        }
+        let mut objects = Vec::with_capacity(ids.len());
+        for ((id, access), vector) in ids.iter().zip(vectors.image_vectors.into_iter()) {
+            let owned = access
+                .owner
+                .as_ref()
+                .and_then(|owner| classifiers.get(owner))
+                .map_or(&[][..], Vec::as_slice);
+            let tags = match self
+                .auto_tag(id, label_vectors.as_deref(), owned, &vector)
+                .await
+            {
+                Ok(tags) => tags,
+                Err(e) => {
+                    log::warn!("Failed to tag {}: {:?}", id, e);
+                    Vec::new()
+                }
+            };
+            objects.push(
+                access
+                    .properties()
+                    .into_iter()
+                    .fold(
+                        WeaviateInput::class("ClipImage".to_string())
+                            .id(id.clone())
+                            .vector(vector),
+                        |object, (key, value)| object.property(key, value),
+                    )
+                    .property(TAGS_PROPERTY.to_string(), tags),
+            );
+        }
 
-        let objects = entries
-            .values()
-            .flatten()
-            .flat_map(|(_, id)| {
-                metadata.remove(id).map(|vector| {
-                    WeaviateInput::class("ClipImage".to_string())
-                        .id(id.clone())
-                        .vector(vector)
-                })
-            })
-            .collect();
-
-        self.client
+        let results: Vec<WeaviateBatchResult> = self
+            .client
             .post(BATCH_VECTOR_INSERT_URL)
             .json(&WeaviateBatchInput::new(objects))
             .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        Ok(results
+            .into_iter()
+            .filter(WeaviateBatchResult::is_ok)
+            .map(|result| result.id)
+            .collect())
+    }
+
+    async fn add_files(
+        &self,
+        entries: Vec<(Id, PathBuf, blake3::Hash)>,
+        mut image_files: Vec<std::fs::File>,
+        origins: HashMap<PathBuf, Origin>,
+    ) -> Result<HashMap<PathBuf, UploadStatus>> {
+        let start = std::time::Instant::now();
+        let metadata: HashMap<Id, ImageMetadata> = entries
+            .par_iter()
+            .zip(image_files.par_iter_mut())
+            .flat_map(|((id, path, _), file)| {
+                image_metadata(file, path).map(|preview| (id.clone(), preview))
+            })
+            .collect();
+
+        println!(
+            "Generated {} previews in {}s",
+            metadata.len(),
+            start.elapsed().as_secs_f32()
+        );
+
+        let mut statuses = self.add_entries(entries, &origins, &metadata).await?;
+
+        let created: Vec<(Id, Access)> = statuses
+            .iter()
+            .filter_map(|(path, status)| match status {
+                UploadStatus::Created { id } => Some((
+                    id.clone(),
+                    origins
+                        .get(path)
+                        .map_or_else(Access::public, |origin| origin.access.clone()),
+                )),
+                _ => None,
+            })
+            .collect();
+        if created.is_empty() {
+            return Ok(statuses);
+        }
+
+        // The images are already stored, and can be indexed again later
+        let indexed = match self.index_images(&created, &metadata).await {
+            Ok(indexed) => indexed,
+            Err(e) => {
+                log::warn!("Failed to index images: {:?}", e);
+                HashSet::new()
+            }
+        };
+        self.mark_synced(&indexed).await?;
+        self.mark_indexed(&indexed).await?;
+        for status in statuses.values_mut() {
+            if let UploadStatus::Created { id } = status {
+                if !indexed.contains(id) {
+                    *status = UploadStatus::VectorizationFailed { id: id.clone() };
+                }
+            }
+        }
+
+        Ok(statuses)
+    }
+
+    /// Adds images which are not in the search index to it, such as those which could not be
+    /// vectorized when they were stored, returning how many were added. Images from before this
+    /// was recorded are only vectorized if weaviate has no object for them.
+    pub(crate) async fn reindex(&self) -> Result<usize> {
+        use std::os::unix::ffi::OsStringExt;
+        let rows = sqlx::query!(
+            "SELECT id, path, owner, owner_group, visibility FROM files WHERE NOT indexed"
+        )
+        .fetch_all(&self.connection)
+        .await?;
+
+        let mut reindexed = 0;
+        for rows in rows.chunks(REINDEX_BATCH_SIZE) {
+            let mut existing = HashSet::new();
+            let mut missing = Vec::new();
+            for row in rows {
+                if self.has_object(&row.id).await? {
+                    existing.insert(row.id.clone());
+                } else {
+                    missing.push(row);
+                }
+            }
+            self.mark_indexed(&existing).await?;
+            if missing.is_empty() {
+                continue;
+            }
+
+            let files: Vec<(Id, PathBuf)> = missing
+                .iter()
+                .map(|row| (row.id.clone(), OsString::from_vec(row.path.clone()).into()))
+                .collect();
+            let metadata: HashMap<Id, ImageMetadata> = web::block(move || {
+                files
+                    .into_par_iter()
+                    .filter_map(|(id, path)| {
+                        let mut file = std::fs::File::open(&path).ok()?;
+                        image_metadata(&mut file, &path).map(|metadata| (id, metadata))
+                    })
+                    .collect()
+            })
+            .await
+            .map_err(actix_web::Error::from)?;
+            let ids: Vec<(Id, Access)> = missing
+                .into_iter()
+                .filter(|row| metadata.contains_key(&row.id))
+                .map(|row| {
+                    let access =
+                        Access::stored(row.owner.clone(), row.owner_group.clone(), &row.visibility);
+                    (row.id.clone(), access)
+                })
+                .collect();
+            if ids.is_empty() {
+                continue;
+            }
+
+            let indexed = self.index_images(&ids, &metadata).await?;
+            self.mark_indexed(&indexed).await?;
+            // The new objects only have the tags which auto-tagging assigned, and no albums
+            let mut tx = self.connection.begin().await?;
+            for id in &indexed {
+                sqlx::query!(
+                    "UPDATE files SET properties_synced = FALSE WHERE id = ?",
+                    id
+                )
+                .execute(&mut tx)
+                .await?;
+            }
+            tx.commit().await?;
+            reindexed += indexed.len();
+        }
+        self.sync_search_properties().await?;
+        Ok(reindexed)
+    }
+
+    /// Whether weaviate has an object for the image
+    async fn has_object(&self, id: &str) -> Result<bool> {
+        let response = self
+            .client
+            .head(format!("{}/{}", OBJECTS_URL, id))
+            .send()
             .await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        response.error_for_status()?;
+        Ok(true)
+    }
 
-        Ok(Some(entries))
+    async fn mark_indexed(&self, ids: &HashSet<Id>) -> sqlx::Result<()> {
+        let mut tx = self.connection.begin().await?;
+        for id in ids {
+            sqlx::query!("UPDATE files SET indexed = TRUE WHERE id = ?", id)
+                .execute(&mut tx)
+                .await?;
+        }
+        tx.commit().await
     }
 
     async fn num_rows(&self) -> sqlx::Result<u32> {
@@ -554,4 +1441,1156 @@ impl SQLiteDatabase {
             .await
             .map(|x| OsString::from_vec(x.path).into())
     }
+
+    pub(crate) async fn get_file(&self, id: &str) -> sqlx::Result<StoredFile> {
+        sqlx::query_as!(
+            SqlxFile,
+            "SELECT path, md5, original_name, blake3 FROM files WHERE id = ?",
+            id
+        )
+        .fetch_one(&self.connection)
+        .await
+        .map(StoredFile::from)
+    }
+
+    /// The file of an image which `user` may see. Images which they may not see are not found,
+    /// so that their ids do not reveal that they exist.
+    pub(crate) async fn visible_file(&self, id: &str, user: &User) -> sqlx::Result<StoredFile> {
+        sqlx::query_as!(
+            SqlxFile,
+            "SELECT path, md5, original_name, blake3 FROM files WHERE id = ? AND (visibility = 'public' OR owner = ? OR (visibility = 'group' AND owner_group = ?))",
+            id,
+            user.name,
+            user.group_name
+        )
+        .fetch_one(&self.connection)
+        .await
+        .map(StoredFile::from)
+    }
+
+    /// An image with the contents of `content_hash` which whoever imports files with `access` may
+    /// see
+    async fn visible_duplicate(
+        &self,
+        content_hash: &blake3::Hash,
+        access: &Access,
+    ) -> sqlx::Result<Option<Id>> {
+        let content_hash = content_hash.as_bytes().as_slice();
+        Ok(sqlx::query!(
+            "SELECT id FROM files WHERE blake3 = ? AND (visibility = 'public' OR owner = ? OR (visibility = 'group' AND owner_group = ?))",
+            content_hash,
+            access.owner,
+            access.group
+        )
+        .fetch_optional(&self.connection)
+        .await?
+        .map(|row| row.id))
+    }
+
+    /// Whether `user` may share the image through a share link, which they may do for images
+    /// which they own and for public images
+    pub(crate) async fn may_share(&self, id: &str, user: &User) -> sqlx::Result<bool> {
+        Ok(sqlx::query!(
+            "SELECT id FROM files WHERE id = ? AND (visibility = 'public' OR owner = ?)",
+            id,
+            user.name
+        )
+        .fetch_optional(&self.connection)
+        .await?
+        .is_some())
+    }
+
+    /// The perceptual hash of every image which has one, and which `user` may see
+    pub(crate) async fn perceptual_hashes(&self, user: &User) -> sqlx::Result<Vec<(Id, u64)>> {
+        struct SqlxHash {
+            id: Id,
+            dhash: Option<i64>,
+        }
+        Ok(sqlx::query_as!(
+            SqlxHash,
+            "SELECT id, dhash FROM files WHERE dhash IS NOT NULL AND (visibility = 'public' OR owner = ? OR (visibility = 'group' AND owner_group = ?))",
+            user.name,
+            user.group_name
+        )
+        .fetch_all(&self.connection)
+        .await?
+        .into_iter()
+        .filter_map(|row| Some((row.id, row.dhash? as u64)))
+        .collect())
+    }
+
+    /// The CLIP vector of the image, as stored in weaviate
+    pub(crate) async fn image_vector(&self, id: &str) -> Result<Option<Vec<f32>>> {
+        let response = self
+            .client
+            .get(format!("{}/{}", OBJECTS_URL, id))
+            .query(&[("include", "vector")])
+            .send()
+            .await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        Ok(response.json::<WeaviateObject>().await?.vector)
+    }
+
+    async fn set_content_hash(&self, id: &str, content_hash: &blake3::Hash) -> sqlx::Result<()> {
+        let content_hash = content_hash.as_bytes().as_slice();
+        sqlx::query!("UPDATE files SET blake3 = ? WHERE id = ?", content_hash, id)
+            .execute(&self.connection)
+            .await
+            .map(|_| ())
+    }
+
+    /// Records the BLAKE3 hashes of files which were stored before files were hashed, returning
+    /// how many were hashed. Files which can not be read are left for `verify` to report.
+    pub(crate) async fn backfill_content_hashes(&self) -> Result<usize> {
+        use std::os::unix::ffi::OsStringExt;
+        let files = sqlx::query!("SELECT id, path FROM files WHERE blake3 IS NULL")
+            .fetch_all(&self.connection)
+            .await?;
+        let mut hashed = 0;
+        for file in files {
+            let path = PathBuf::from(OsString::from_vec(file.path));
+            let content_hash = web::block(move || {
+                std::fs::File::open(path).and_then(|mut file| content_hash(&mut file))
+            })
+            .await;
+            match content_hash {
+                Ok(Ok(content_hash)) => {
+                    self.set_content_hash(&file.id, &content_hash).await?;
+                    hashed += 1;
+                }
+                Ok(Err(e)) => log::warn!("Could not hash {}: {:?}", file.id, e),
+                Err(e) => log::warn!("Could not hash {}: {:?}", file.id, e),
+            }
+        }
+        Ok(hashed)
+    }
+
+    /// The user with an unrevoked API key with the given hash
+    pub(crate) async fn user_for_key(&self, key_hash: &[u8]) -> sqlx::Result<Option<User>> {
+        sqlx::query_as!(
+            User,
+            r#"SELECT users.id, users.name, users.is_admin as "is_admin: bool", users.group_name, users.max_bytes, users.max_images
+            FROM api_keys JOIN users ON users.id = api_keys.user_id
+            WHERE api_keys.hash = ? AND api_keys.revoked_at IS NULL"#,
+            key_hash
+        )
+        .fetch_optional(&self.connection)
+        .await
+    }
+
+    pub(crate) async fn user(&self, name: &str) -> sqlx::Result<Option<User>> {
+        sqlx::query_as!(
+            User,
+            r#"SELECT id, name, is_admin as "is_admin: bool", group_name, max_bytes, max_images FROM users WHERE name = ?"#,
+            name
+        )
+        .fetch_optional(&self.connection)
+        .await
+    }
+
+    /// Every user, and what they have stored
+    pub(crate) async fn users(&self) -> sqlx::Result<Vec<UserSummary>> {
+        sqlx::query_as!(
+            UserSummary,
+            r#"SELECT users.name, users.is_admin as "is_admin: bool", users.group_name, users.max_bytes, users.max_images,
+                COUNT(files.id) as "images!: i64", COALESCE(SUM(files.size), 0) as "bytes!: i64"
+            FROM users LEFT JOIN files ON files.uploader = users.name
+            GROUP BY users.id ORDER BY users.name"#
+        )
+        .fetch_all(&self.connection)
+        .await
+    }
+
+    /// Creates a user, unless the name is taken
+    pub(crate) async fn create_user(
+        &self,
+        name: &str,
+        is_admin: bool,
+        group_name: Option<&str>,
+        max_bytes: Option<i64>,
+        max_images: Option<i64>,
+    ) -> sqlx::Result<Option<User>> {
+        let id = sqlx::query!(
+            "INSERT OR IGNORE INTO users (name, is_admin, group_name, max_bytes, max_images) VALUES(?, ?, ?, ?, ?);",
+            name,
+            is_admin,
+            group_name,
+            max_bytes,
+            max_images
+        )
+        .execute(&self.connection)
+        .await?;
+        if id.rows_affected() == 0 {
+            return Ok(None);
+        }
+        Ok(Some(User {
+            id: id.last_insert_rowid(),
+            name: name.to_string(),
+            is_admin,
+            group_name: group_name.map(str::to_string),
+            max_bytes,
+            max_images,
+        }))
+    }
+
+    /// Sets the quotas of the user, returning whether the user exists
+    pub(crate) async fn set_quota(
+        &self,
+        name: &str,
+        max_bytes: Option<i64>,
+        max_images: Option<i64>,
+    ) -> sqlx::Result<bool> {
+        sqlx::query!(
+            "UPDATE users SET max_bytes = ?, max_images = ? WHERE name = ?",
+            max_bytes,
+            max_images,
+            name
+        )
+        .execute(&self.connection)
+        .await
+        .map(|result| result.rows_affected() > 0)
+    }
+
+    /// The images which were uploaded by `uploader`, and their total size
+    pub(crate) async fn usage(&self, uploader: &str) -> sqlx::Result<Usage> {
+        sqlx::query_as!(
+            Usage,
+            r#"SELECT COUNT(id) as "images!: i64", COALESCE(SUM(size), 0) as "bytes!: i64" FROM files WHERE uploader = ?"#,
+            uploader
+        )
+        .fetch_one(&self.connection)
+        .await
+    }
+
+    /// Records an API key of the user, returning the id of the key
+    pub(crate) async fn issue_key(&self, user_id: i64, key_hash: &[u8]) -> sqlx::Result<String> {
+        let id = uuid::Uuid::new_v4().to_string();
+        let created_at = unix_time();
+        sqlx::query!(
+            "INSERT INTO api_keys (id, user_id, hash, created_at) VALUES(?, ?, ?, ?);",
+            id,
+            user_id,
+            key_hash,
+            created_at
+        )
+        .execute(&self.connection)
+        .await?;
+        Ok(id)
+    }
+
+    /// Revokes the key, returning whether it existed and was not revoked yet
+    pub(crate) async fn revoke_key(&self, id: &str) -> sqlx::Result<bool> {
+        let revoked_at = unix_time();
+        sqlx::query!(
+            "UPDATE api_keys SET revoked_at = ? WHERE id = ? AND revoked_at IS NULL",
+            revoked_at,
+            id
+        )
+        .execute(&self.connection)
+        .await
+        .map(|result| result.rows_affected() > 0)
+    }
+
+    /// The ids of the `limit` images nearest to `vector` which match `filter`, nearest first
+    pub(crate) async fn near_vector(
+        &self,
+        vector: &[f32],
+        limit: usize,
+        filter: &WeaviateWhere,
+    ) -> Result<Vec<Id>> {
+        let vector = vector.iter().map(f32::to_string).join(", ");
+        let filter = filter.to_graphql();
+        let query = format!(
+            "{{
+    Get{{
+      ClipImage(
+        limit: {limit},
+        nearVector: {{
+          vector: [{vector}]
+        }},
+        where: {filter}
+      ){{
+        _additional {{
+          id
+          certainty
+        }}
+      }}
+    }}
+  }}"
+        );
+        log::info!("sending query: {}", query);
+        let mut weaviate_request = HashMap::new();
+        weaviate_request.insert("query", query);
+        let mut response: QueryResult = self
+            .client
+            .post(VECTOR_SEARCH_URL)
+            .json(&weaviate_request)
+            .send()
+            .await?
+            .json()
+            .await?;
+        Ok(response
+            .data
+            .get
+            .remove("ClipImage")
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|info| Some(info.additional?.remove("id")?.as_str()?.to_string()))
+            .collect())
+    }
+
+    /// Changes the visibility of an image which `user` owns, returning whether there is one
+    pub(crate) async fn set_visibility(
+        &self,
+        id: &str,
+        user: &User,
+        visibility: Visibility,
+    ) -> Result<bool> {
+        let visibility = visibility.as_str();
+        let updated = sqlx::query!(
+            "UPDATE files SET visibility = ?, properties_synced = FALSE WHERE id = ? AND owner = ?",
+            visibility,
+            id,
+            user.name
+        )
+        .execute(&self.connection)
+        .await?
+        .rows_affected();
+        if updated == 0 {
+            return Ok(false);
+        }
+        self.sync_images(&[id.to_string()]).await?;
+        Ok(true)
+    }
+
+    /// Moves the user and the images they own to `group`, returning whether the user exists
+    pub(crate) async fn set_group(&self, name: &str, group_name: Option<&str>) -> Result<bool> {
+        let mut tx = self.connection.begin().await?;
+        let updated = sqlx::query!(
+            "UPDATE users SET group_name = ? WHERE name = ?",
+            group_name,
+            name
+        )
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+        if updated == 0 {
+            return Ok(false);
+        }
+        sqlx::query!(
+            "UPDATE files SET owner_group = ?, properties_synced = FALSE WHERE owner = ?",
+            group_name,
+            name
+        )
+        .execute(&mut tx)
+        .await?;
+        let ids: Vec<Id> = sqlx::query!("SELECT id FROM files WHERE owner = ?", name)
+            .fetch_all(&mut tx)
+            .await?
+            .into_iter()
+            .map(|row| row.id)
+            .collect();
+        tx.commit().await?;
+        self.sync_images(&ids).await?;
+        Ok(true)
+    }
+
+    async fn mark_synced(&self, ids: &HashSet<Id>) -> sqlx::Result<()> {
+        let mut tx = self.connection.begin().await?;
+        for id in ids {
+            sqlx::query!("UPDATE files SET properties_synced = TRUE WHERE id = ?", id)
+                .execute(&mut tx)
+                .await?;
+        }
+        tx.commit().await
+    }
+
+    /// Syncs the search properties of every image whose access, albums or tags changed, including
+    /// those whose sync failed before
+    pub(crate) async fn sync_search_properties(&self) -> Result<()> {
+        let ids: Vec<Id> = sqlx::query!("SELECT id FROM files WHERE NOT properties_synced")
+            .fetch_all(&self.connection)
+            .await?
+            .into_iter()
+            .map(|row| row.id)
+            .collect();
+        self.sync_images(&ids).await
+    }
+
+    /// Copies the owner, visibility, albums and tags of those of `ids` whose access, albums or
+    /// tags changed to their `ClipImage` properties. Until this succeeds, searches filter on the
+    /// previous properties, so failures are retried by `retry_search_properties`.
+    pub(crate) async fn sync_images(&self, ids: &[Id]) -> Result<()> {
+        struct SqlxAccess {
+            id: Id,
+            owner: Option<String>,
+            owner_group: Option<String>,
+            visibility: String,
+        }
+        let mut synced = HashSet::new();
+        for id in ids {
+            let row = match sqlx::query_as!(
+                SqlxAccess,
+                "SELECT id, owner, owner_group, visibility FROM files WHERE id = ? AND NOT properties_synced",
+                id
+            )
+            .fetch_optional(&self.connection)
+            .await?
+            {
+                Some(row) => row,
+                // Deleted, or synced in the meantime
+                None => continue,
+            };
+            let access = Access::stored(row.owner, row.owner_group, &row.visibility);
+            let albums: Vec<String> = sqlx::query!(
+                "SELECT album_id FROM album_images WHERE image_id = ?",
+                row.id
+            )
+            .fetch_all(&self.connection)
+            .await?
+            .into_iter()
+            .map(|album| album.album_id)
+            .collect();
+            let tags: Vec<String> = sqlx::query!(
+                "SELECT tags.name FROM image_tags JOIN tags ON tags.id = image_tags.tag_id WHERE image_tags.image_id = ?",
+                row.id
+            )
+            .fetch_all(&self.connection)
+            .await?
+            .into_iter()
+            .map(|tag| tag.name)
+            .collect();
+            let object = access
+                .properties()
+                .into_iter()
+                .fold(
+                    WeaviateInput::class("ClipImage".to_string()),
+                    |object, (key, value)| object.property(key, value),
+                )
+                .property(ALBUMS_PROPERTY.to_string(), albums)
+                .property(TAGS_PROPERTY.to_string(), tags);
+            let status = self
+                .client
+                .patch(format!("{}/{}", OBJECTS_URL, row.id))
+                .json(&object)
+                .send()
+                .await?
+                .status();
+            // Images which were never vectorized have no object, and are not searchable anyway
+            if status.is_success() || status == reqwest::StatusCode::NOT_FOUND {
+                synced.insert(row.id);
+            } else {
+                log::warn!("Failed to sync search properties of {}: {}", row.id, status);
+            }
+        }
+        Ok(self.mark_synced(&synced).await?)
+    }
+
+    pub(crate) async fn create_album(&self, owner: &str, name: &str) -> sqlx::Result<AlbumSummary> {
+        let id = uuid::Uuid::new_v4().to_string();
+        let created_at = unix_time();
+        sqlx::query!(
+            "INSERT INTO albums (id, owner, name, created_at) VALUES (?, ?, ?, ?)",
+            id,
+            owner,
+            name,
+            created_at
+        )
+        .execute(&self.connection)
+        .await?;
+        Ok(AlbumSummary {
+            id,
+            name: name.to_string(),
+            cover: None,
+            images: 0,
+            created_at,
+        })
+    }
+
+    /// The albums of `owner`, newest first
+    pub(crate) async fn albums(&self, owner: &str) -> sqlx::Result<Vec<AlbumSummary>> {
+        sqlx::query_as!(
+            AlbumSummary,
+            r#"SELECT id, name, cover, created_at, (SELECT COUNT(*) FROM album_images WHERE album_id = albums.id) as "images!: i64" FROM albums WHERE owner = ? ORDER BY created_at DESC"#,
+            owner
+        )
+        .fetch_all(&self.connection)
+        .await
+    }
+
+    /// The album, with the images in it which its owner may share
+    pub(crate) async fn album(&self, id: &str) -> sqlx::Result<Option<Album>> {
+        let album = match sqlx::query!("SELECT id, owner, name, cover FROM albums WHERE id = ?", id)
+            .fetch_optional(&self.connection)
+            .await?
+        {
+            Some(album) => album,
+            None => return Ok(None),
+        };
+        let images = sqlx::query!(
+            r#"SELECT files.id as "id!" FROM album_images JOIN files ON files.id = album_images.image_id WHERE album_images.album_id = ? AND (files.visibility = 'public' OR files.owner = ?) ORDER BY album_images.position"#,
+            id,
+            album.owner
+        )
+        .fetch_all(&self.connection)
+        .await?
+        .into_iter()
+        .map(|row| row.id)
+        .collect();
+        Ok(Some(Album {
+            id: album.id,
+            owner: album.owner,
+            name: album.name,
+            cover: album.cover,
+            images,
+        }))
+    }
+
+    /// The file of an image in `album`, if `owner` still owns the album and may share the image
+    pub(crate) async fn album_file(
+        &self,
+        album: &str,
+        owner: &str,
+        id: &str,
+    ) -> sqlx::Result<StoredFile> {
+        sqlx::query_as!(
+            SqlxFile,
+            r#"SELECT files.path as "path!", files.md5 as "md5!", files.original_name, files.blake3 FROM files JOIN album_images ON album_images.image_id = files.id JOIN albums ON albums.id = album_images.album_id WHERE album_images.album_id = ? AND albums.owner = ? AND files.id = ? AND (files.visibility = 'public' OR files.owner = albums.owner)"#,
+            album,
+            owner,
+            id
+        )
+        .fetch_one(&self.connection)
+        .await
+        .map(StoredFile::from)
+    }
+
+    /// Renames the album and changes its cover, leaving out what is not given. A cover of
+    /// `Some(None)` removes the cover.
+    pub(crate) async fn update_album(
+        &self,
+        id: &str,
+        name: Option<&str>,
+        cover: Option<Option<&str>>,
+    ) -> sqlx::Result<()> {
+        let change_cover = cover.is_some();
+        let cover = cover.flatten();
+        sqlx::query!(
+            "UPDATE albums SET name = COALESCE(?, name), cover = CASE WHEN ? THEN ? ELSE cover END WHERE id = ?",
+            name,
+            change_cover,
+            cover,
+            id
+        )
+        .execute(&self.connection)
+        .await
+        .map(|_| ())
+    }
+
+    /// Deletes an album which `owner` owns, returning whether there is one. Its images stay.
+    pub(crate) async fn delete_album(&self, id: &str, owner: &str) -> Result<bool> {
+        let mut tx = self.connection.begin().await?;
+        // The album is about to leave the `ClipImage` properties of its images
+        let images: Vec<Id> =
+            sqlx::query!("SELECT image_id FROM album_images WHERE album_id = ?", id)
+                .fetch_all(&mut tx)
+                .await?
+                .into_iter()
+                .map(|row| row.image_id)
+                .collect();
+        sqlx::query!(
+            "UPDATE files SET properties_synced = FALSE WHERE id IN (SELECT image_id FROM album_images WHERE album_id = ?)",
+            id
+        )
+        .execute(&mut tx)
+        .await?;
+        let deleted = sqlx::query!("DELETE FROM albums WHERE id = ? AND owner = ?", id, owner)
+            .execute(&mut tx)
+            .await?
+            .rows_affected();
+        if deleted == 0 {
+            return Ok(false);
+        }
+        tx.commit().await?;
+        self.sync_images(&images).await?;
+        Ok(true)
+    }
+
+    /// Appends the images which `user` may share to the end of the album, returning those which
+    /// were not in it yet
+    pub(crate) async fn add_to_album(
+        &self,
+        id: &str,
+        user: &User,
+        images: &[Id],
+    ) -> Result<Vec<Id>> {
+        let mut tx = self.connection.begin().await?;
+        let mut added = Vec::new();
+        for image in images {
+            let inserted = sqlx::query!(
+                "INSERT OR IGNORE INTO album_images (album_id, image_id, position) SELECT ?, id, (SELECT COALESCE(MAX(position) + 1, 0) FROM album_images WHERE album_id = ?) FROM files WHERE id = ? AND (visibility = 'public' OR owner = ?)",
+                id,
+                id,
+                image,
+                user.name
+            )
+            .execute(&mut tx)
+            .await?
+            .rows_affected();
+            if inserted > 0 {
+                sqlx::query!(
+                    "UPDATE files SET properties_synced = FALSE WHERE id = ?",
+                    image
+                )
+                .execute(&mut tx)
+                .await?;
+                added.push(image.clone());
+            }
+        }
+        tx.commit().await?;
+        self.sync_images(&added).await?;
+        Ok(added)
+    }
+
+    /// Removes an image from the album, returning whether it was in it
+    pub(crate) async fn remove_from_album(&self, id: &str, image: &str) -> Result<bool> {
+        let mut tx = self.connection.begin().await?;
+        let removed = sqlx::query!(
+            "DELETE FROM album_images WHERE album_id = ? AND image_id = ?",
+            id,
+            image
+        )
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+        if removed == 0 {
+            return Ok(false);
+        }
+        sqlx::query!(
+            "UPDATE albums SET cover = NULL WHERE id = ? AND cover = ?",
+            id,
+            image
+        )
+        .execute(&mut tx)
+        .await?;
+        sqlx::query!(
+            "UPDATE files SET properties_synced = FALSE WHERE id = ?",
+            image
+        )
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+        self.sync_images(&[image.to_string()]).await?;
+        Ok(true)
+    }
+
+    /// Moves `images`, which must be in the album, to its start in the given order. The other
+    /// images of the album keep their order after them.
+    pub(crate) async fn order_album(&self, id: &str, images: &[Id]) -> sqlx::Result<()> {
+        let mut tx = self.connection.begin().await?;
+        let members: Vec<Id> = sqlx::query!(
+            "SELECT image_id FROM album_images WHERE album_id = ? ORDER BY position",
+            id
+        )
+        .fetch_all(&mut tx)
+        .await?
+        .into_iter()
+        .map(|row| row.image_id)
+        .collect();
+
+        let order = images
+            .iter()
+            .chain(members.iter().filter(|image| !images.contains(image)));
+        for (position, image) in order.enumerate() {
+            let position = position as i64;
+            sqlx::query!(
+                "UPDATE album_images SET position = ? WHERE album_id = ? AND image_id = ?",
+                position,
+                id,
+                image
+            )
+            .execute(&mut tx)
+            .await?;
+        }
+        tx.commit().await
+    }
+
+    /// The tags which start with `prefix`, by how many of the images which `user` may see have
+    /// them, most used first
+    pub(crate) async fn tag_counts(
+        &self,
+        user: &User,
+        prefix: &str,
+        limit: u32,
+    ) -> sqlx::Result<Vec<TagCount>> {
+        let pattern = format!(
+            "{}%",
+            prefix
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_")
+        );
+        sqlx::query_as!(
+            TagCount,
+            r#"SELECT tags.name, COUNT(*) as "count!: i64" FROM tags JOIN image_tags ON image_tags.tag_id = tags.id JOIN files ON files.id = image_tags.image_id WHERE tags.name LIKE ? ESCAPE '\' AND (files.visibility = 'public' OR files.owner = ? OR (files.visibility = 'group' AND files.owner_group = ?)) GROUP BY tags.id ORDER BY COUNT(*) DESC, tags.name LIMIT ?"#,
+            pattern,
+            user.name,
+            user.group_name,
+            limit
+        )
+        .fetch_all(&self.connection)
+        .await
+    }
+
+    /// The tags of an image, in alphabetical order
+    pub(crate) async fn image_tags(&self, id: &str) -> sqlx::Result<Vec<ImageTag>> {
+        sqlx::query_as!(
+            ImageTag,
+            "SELECT tags.name, image_tags.confidence FROM image_tags JOIN tags ON tags.id = image_tags.tag_id WHERE image_tags.image_id = ? ORDER BY tags.name",
+            id
+        )
+        .fetch_all(&self.connection)
+        .await
+    }
+
+    /// Adds and removes tags on the images which `user` may tag, which are those they own, or any
+    /// image for admins. Returns the images which `user` may tag.
+    pub(crate) async fn edit_tags(
+        &self,
+        ids: &[Id],
+        user: &User,
+        add: &[String],
+        remove: &[String],
+    ) -> Result<Vec<Id>> {
+        let mut tx = self.connection.begin().await?;
+        for tag in add {
+            sqlx::query!("INSERT OR IGNORE INTO tags (name) VALUES (?)", tag)
+                .execute(&mut tx)
+                .await?;
+        }
+
+        let mut updated = Vec::new();
+        for id in ids {
+            if sqlx::query!(
+                "SELECT id FROM files WHERE id = ? AND (owner = ? OR ?)",
+                id,
+                user.name,
+                user.is_admin
+            )
+            .fetch_optional(&mut tx)
+            .await?
+            .is_none()
+            {
+                continue;
+            }
+            for tag in add {
+                sqlx::query!(
+                    "INSERT INTO image_tags (image_id, tag_id) SELECT ?, id FROM tags WHERE name = ? ON CONFLICT (image_id, tag_id) DO UPDATE SET confidence = NULL, classifier = NULL",
+                    id,
+                    tag
+                )
+                .execute(&mut tx)
+                .await?;
+            }
+            for tag in remove {
+                sqlx::query!(
+                    "DELETE FROM image_tags WHERE image_id = ? AND tag_id IN (SELECT id FROM tags WHERE name = ?)",
+                    id,
+                    tag
+                )
+                .execute(&mut tx)
+                .await?;
+            }
+            sqlx::query!(
+                "UPDATE files SET properties_synced = FALSE WHERE id = ?",
+                id
+            )
+            .execute(&mut tx)
+            .await?;
+            updated.push(id.clone());
+        }
+
+        // Tags which no image has anymore would otherwise still be suggested
+        sqlx::query!("DELETE FROM tags WHERE id NOT IN (SELECT tag_id FROM image_tags)")
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        self.sync_images(&updated).await?;
+        Ok(updated)
+    }
+
+    /// The images which `user` may see and which pass `filter`, newest first
+    pub(crate) async fn browse(
+        &self,
+        user: &User,
+        filter: &TagFilter,
+        offset: u32,
+        limit: u32,
+    ) -> sqlx::Result<Vec<Id>> {
+        // The tags are passed as JSON arrays, since SQLite has no array parameters
+        let include = serde_json::Value::from(filter.include.clone()).to_string();
+        let exclude = serde_json::Value::from(filter.exclude.clone()).to_string();
+        Ok(sqlx::query!(
+            "SELECT id FROM files WHERE (visibility = 'public' OR owner = ? OR (visibility = 'group' AND owner_group = ?)) AND (SELECT COUNT(*) FROM image_tags JOIN tags ON tags.id = image_tags.tag_id WHERE image_tags.image_id = files.id AND tags.name IN (SELECT value FROM json_each(?))) = json_array_length(?) AND NOT EXISTS (SELECT 1 FROM image_tags JOIN tags ON tags.id = image_tags.tag_id WHERE image_tags.image_id = files.id AND tags.name IN (SELECT value FROM json_each(?))) ORDER BY uploaded_at DESC, id LIMIT ? OFFSET ?",
+            user.name,
+            user.group_name,
+            include,
+            include,
+            exclude,
+            limit,
+            offset
+        )
+        .fetch_all(&self.connection)
+        .await?
+        .into_iter()
+        .map(|row| row.id)
+        .collect())
+    }
+
+    pub(crate) fn set_label_vectors(&self, label_vectors: Option<Arc<LabelVectors>>) {
+        *self.label_vectors.write().unwrap() = label_vectors;
+    }
+
+    fn label_vectors(&self) -> Option<Arc<LabelVectors>> {
+        self.label_vectors.read().unwrap().clone()
+    }
+
+    /// Replaces the tags which `classifier`, or the vocabulary if there is none, assigned to an
+    /// image with `scores`. Tags which were assigned manually or by others stay as they are.
+    async fn set_machine_tags(
+        &self,
+        id: &str,
+        classifier: Option<&str>,
+        scores: &[(String, f32)],
+    ) -> sqlx::Result<()> {
+        let mut tx = self.connection.begin().await?;
+        sqlx::query!(
+            "DELETE FROM image_tags WHERE image_id = ? AND confidence IS NOT NULL AND classifier IS ?",
+            id,
+            classifier
+        )
+        .execute(&mut tx)
+        .await?;
+        for (tag, confidence) in scores {
+            sqlx::query!("INSERT OR IGNORE INTO tags (name) VALUES (?)", tag)
+                .execute(&mut tx)
+                .await?;
+            sqlx::query!(
+                "INSERT OR IGNORE INTO image_tags (image_id, tag_id, confidence, classifier) SELECT ?, id, ?, ? FROM tags WHERE name = ?",
+                id,
+                confidence,
+                classifier,
+                tag
+            )
+            .execute(&mut tx)
+            .await?;
+        }
+        sqlx::query!("DELETE FROM tags WHERE id NOT IN (SELECT tag_id FROM image_tags)")
+            .execute(&mut tx)
+            .await?;
+        sqlx::query!(
+            "UPDATE files SET properties_synced = FALSE WHERE id = ?",
+            id
+        )
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await
+    }
+
+    /// Tags a new image with the vocabulary and with `classifiers`, returning its tags
+    async fn auto_tag(
+        &self,
+        id: &str,
+        label_vectors: Option<&LabelVectors>,
+        classifiers: &[Classifier],
+        vector: &[f32],
+    ) -> sqlx::Result<Vec<String>> {
+        let mut tags = Vec::new();
+        if let Some(label_vectors) = label_vectors {
+            let scores = label_vectors.score(vector);
+            self.set_machine_tags(id, None, &scores).await?;
+            tags.extend(scores.into_iter().map(|(label, _)| label));
+        }
+        for classifier in classifiers {
+            let scores = classifier.score(vector);
+            self.set_machine_tags(id, Some(&classifier.id), &scores)
+                .await?;
+            tags.extend(scores.into_iter().map(|(label, _)| label));
+        }
+        tags.sort();
+        tags.dedup();
+        Ok(tags)
+    }
+
+    /// Tags every image with the current vocabulary again, returning how many were tagged.
+    /// Without a vocabulary, this removes the tags which auto-tagging assigned.
+    pub(crate) async fn rescore_library(&self) -> Result<usize> {
+        let label_vectors = self.label_vectors();
+        let ids = sqlx::query!("SELECT id FROM files")
+            .fetch_all(&self.connection)
+            .await?;
+
+        let mut tagged = 0;
+        for row in ids {
+            let scores = match &label_vectors {
+                Some(label_vectors) => match self.image_vector(&row.id).await? {
+                    Some(vector) => label_vectors.score(&vector),
+                    // Images which were never vectorized are tagged when they are
+                    None => continue,
+                },
+                None => Vec::new(),
+            };
+            self.set_machine_tags(&row.id, None, &scores).await?;
+            tagged += 1;
+            // Keep search results close to the database during long runs
+            if tagged % 100 == 0 {
+                self.sync_search_properties().await?;
+            }
+        }
+        self.sync_search_properties().await?;
+        Ok(tagged)
+    }
+
+    /// Stores a classifier, replacing the classifier of `owner` with the same name, which keeps
+    /// its id
+    pub(crate) async fn save_classifier(
+        &self,
+        owner: &str,
+        name: &str,
+        probe: &LinearProbe,
+        auto_tag: bool,
+        (positives, negatives): (usize, usize),
+        validation: &Validation,
+    ) -> sqlx::Result<ClassifierSummary> {
+        let id = uuid::Uuid::new_v4().to_string();
+        let created_at = unix_time();
+        let weights = probe.weight_bytes();
+        let bias = probe.bias as f64;
+        let (positives, negatives) = (positives as i64, negatives as i64);
+        let mut tx = self.connection.begin().await?;
+        sqlx::query!(
+            "INSERT INTO classifiers (id, owner, name, weights, bias, auto_tag, positives, negatives, precision, recall, created_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?) ON CONFLICT (owner, name) DO UPDATE SET weights = excluded.weights, bias = excluded.bias, auto_tag = excluded.auto_tag, positives = excluded.positives, negatives = excluded.negatives, precision = excluded.precision, recall = excluded.recall, created_at = excluded.created_at",
+            id,
+            owner,
+            name,
+            weights,
+            bias,
+            auto_tag,
+            positives,
+            negatives,
+            validation.precision,
+            validation.recall,
+            created_at
+        )
+        .execute(&mut tx)
+        .await?;
+        let summary = sqlx::query_as!(
+            ClassifierSummary,
+            r#"SELECT id, name, auto_tag as "auto_tag: bool", positives, negatives, precision, recall, created_at FROM classifiers WHERE owner = ? AND name = ?"#,
+            owner,
+            name
+        )
+        .fetch_one(&mut tx)
+        .await?;
+        tx.commit().await?;
+        Ok(summary)
+    }
+
+    /// The classifiers of `owner`, by name
+    pub(crate) async fn classifiers(&self, owner: &str) -> sqlx::Result<Vec<ClassifierSummary>> {
+        sqlx::query_as!(
+            ClassifierSummary,
+            r#"SELECT id, name, auto_tag as "auto_tag: bool", positives, negatives, precision, recall, created_at FROM classifiers WHERE owner = ? ORDER BY name"#,
+            owner
+        )
+        .fetch_all(&self.connection)
+        .await
+    }
+
+    pub(crate) async fn classifier(&self, id: &str) -> sqlx::Result<Option<Classifier>> {
+        Ok(sqlx::query!(
+            r#"SELECT id, owner, name, weights, bias, auto_tag as "auto_tag: bool" FROM classifiers WHERE id = ?"#,
+            id
+        )
+        .fetch_optional(&self.connection)
+        .await?
+        .map(|row| Classifier {
+            id: row.id,
+            owner: row.owner,
+            name: row.name,
+            auto_tag: row.auto_tag,
+            probe: LinearProbe::from_bytes(&row.weights, row.bias),
+        }))
+    }
+
+    /// The classifiers which tag the new images of `owner`
+    async fn auto_tag_classifiers(&self, owner: &str) -> sqlx::Result<Vec<Classifier>> {
+        Ok(sqlx::query!(
+            r#"SELECT id, owner, name, weights, bias FROM classifiers WHERE owner = ? AND auto_tag"#,
+            owner
+        )
+        .fetch_all(&self.connection)
+        .await?
+        .into_iter()
+        .map(|row| Classifier {
+            id: row.id,
+            owner: row.owner,
+            name: row.name,
+            auto_tag: true,
+            probe: LinearProbe::from_bytes(&row.weights, row.bias),
+        })
+        .collect())
+    }
+
+    /// Deletes a classifier which `owner` owns along with the tags it assigned, returning whether
+    /// there is one
+    pub(crate) async fn delete_classifier(&self, id: &str, owner: &str) -> Result<bool> {
+        let mut tx = self.connection.begin().await?;
+        // The tags of the classifier are about to leave the `ClipImage` properties of its images
+        let images: Vec<Id> = sqlx::query!(
+            r#"SELECT DISTINCT image_id as "id!" FROM image_tags WHERE classifier = ?"#,
+            id
+        )
+        .fetch_all(&mut tx)
+        .await?
+        .into_iter()
+        .map(|row| row.id)
+        .collect();
+        sqlx::query!(
+            "UPDATE files SET properties_synced = FALSE WHERE id IN (SELECT image_id FROM image_tags WHERE classifier = ?)",
+            id
+        )
+        .execute(&mut tx)
+        .await?;
+        let deleted = sqlx::query!(
+            "DELETE FROM classifiers WHERE id = ? AND owner = ?",
+            id,
+            owner
+        )
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+        if deleted == 0 {
+            return Ok(false);
+        }
+        sqlx::query!("DELETE FROM tags WHERE id NOT IN (SELECT tag_id FROM image_tags)")
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        self.sync_images(&images).await?;
+        Ok(true)
+    }
+
+    /// Tags the images of the classifier's owner with it again, or removes its tags if it does
+    /// not auto-tag, returning how many images it tagged
+    pub(crate) async fn tag_with_classifier(&self, classifier: &Classifier) -> Result<usize> {
+        // Without auto-tagging, only the images which the classifier tagged before change
+        let ids: Vec<Id> = if classifier.auto_tag {
+            sqlx::query!("SELECT id FROM files WHERE owner = ?", classifier.owner)
+                .fetch_all(&self.connection)
+                .await?
+                .into_iter()
+                .map(|row| row.id)
+                .collect()
+        } else {
+            sqlx::query!(
+                r#"SELECT DISTINCT image_id as "id!" FROM image_tags WHERE classifier = ?"#,
+                classifier.id
+            )
+            .fetch_all(&self.connection)
+            .await?
+            .into_iter()
+            .map(|row| row.id)
+            .collect()
+        };
+
+        let mut tagged = 0;
+        for (index, id) in ids.into_iter().enumerate() {
+            let scores = if classifier.auto_tag {
+                match self.image_vector(&id).await? {
+                    Some(vector) => classifier.score(&vector),
+                    // Images which were never vectorized are tagged when they are
+                    None => continue,
+                }
+            } else {
+                Vec::new()
+            };
+            if !scores.is_empty() {
+                tagged += 1;
+            }
+            self.set_machine_tags(&id, Some(&classifier.id), &scores)
+                .await?;
+            // Keep search results close to the database during long runs
+            if index % 100 == 99 {
+                self.sync_search_properties().await?;
+            }
+        }
+        self.sync_search_properties().await?;
+        Ok(tagged)
+    }
+
+    /// The RAW presets which users stored, by name
+    pub(crate) async fn raw_presets(&self) -> sqlx::Result<BTreeMap<String, Development>> {
+        sqlx::query!("SELECT name, settings FROM raw_presets")
+            .fetch_all(&self.connection)
+            .await?
+            .into_iter()
+            .map(|row| Ok((row.name, parse_preset(&row.settings)?)))
+            .collect()
+    }
+
+    pub(crate) async fn raw_preset(&self, name: &str) -> sqlx::Result<Option<Development>> {
+        sqlx::query!("SELECT settings FROM raw_presets WHERE name = ?", name)
+            .fetch_optional(&self.connection)
+            .await?
+            .map(|row| parse_preset(&row.settings))
+            .transpose()
+    }
+
+    /// Stores a RAW preset, returning false if another user stored one with the same name, which
+    /// only admins may replace
+    pub(crate) async fn save_raw_preset(
+        &self,
+        name: &str,
+        user: &User,
+        development: &Development,
+    ) -> sqlx::Result<bool> {
+        let settings =
+            serde_json::to_string(development).map_err(|e| sqlx::Error::Protocol(e.to_string()))?;
+        let updated_at = unix_time();
+        let saved = sqlx::query!(
+            "INSERT INTO raw_presets (name, owner, settings, updated_at) VALUES (?, ?, ?, ?) ON CONFLICT (name) DO UPDATE SET settings = excluded.settings, updated_at = excluded.updated_at WHERE owner = excluded.owner OR ?",
+            name,
+            user.name,
+            settings,
+            updated_at,
+            user.is_admin
+        )
+        .execute(&self.connection)
+        .await?
+        .rows_affected();
+        Ok(saved > 0)
+    }
+
+    /// Deletes a RAW preset which `user` stored, or any stored preset if they are an admin,
+    /// returning whether there is one
+    pub(crate) async fn delete_raw_preset(&self, name: &str, user: &User) -> sqlx::Result<bool> {
+        let deleted = sqlx::query!(
+            "DELETE FROM raw_presets WHERE name = ? AND (owner = ? OR ?)",
+            name,
+            user.name,
+            user.is_admin
+        )
+        .execute(&self.connection)
+        .await?
+        .rows_affected();
+        Ok(deleted > 0)
+    }
+}
+
+/// Reads the settings of a stored RAW preset
+fn parse_preset(settings: &str) -> sqlx::Result<Development> {
+    serde_json::from_str(settings).map_err(|e| sqlx::Error::Decode(Box::new(e)))
 }
@@ -0,0 +1,416 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use actix_web::web::{self, Data, Json};
+use actix_web::HttpResponse;
+use image::RgbImage;
+use libraw_sys as sys;
+
+use crate::auth::User;
+use crate::SQLiteDatabase;
+
+/// How the white point of a RAW is chosen
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(try_from = "String", into = "String")]
+pub enum WhiteBalance {
+    /// The white balance recorded by the camera
+    AsShot,
+    /// Estimated from the image by libraw
+    Auto,
+    /// The color temperature of the light, in Kelvin
+    Kelvin(u32),
+}
+
+impl TryFrom<String> for WhiteBalance {
+    type Error = &'static str;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.as_str() {
+            "as-shot" => Ok(WhiteBalance::AsShot),
+            "auto" => Ok(WhiteBalance::Auto),
+            kelvin => match kelvin.parse() {
+                Ok(kelvin @ 2000..=12000) => Ok(WhiteBalance::Kelvin(kelvin)),
+                _ => Err("white balance must be as-shot, auto, or between 2000 and 12000 Kelvin"),
+            },
+        }
+    }
+}
+
+impl From<WhiteBalance> for String {
+    fn from(white_balance: WhiteBalance) -> Self {
+        match white_balance {
+            WhiteBalance::AsShot => String::from("as-shot"),
+            WhiteBalance::Auto => String::from("auto"),
+            WhiteBalance::Kelvin(kelvin) => kelvin.to_string(),
+        }
+    }
+}
+
+/// How libraw handles clipped highlights
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum HighlightMode {
+    /// Clip to white
+    Clip,
+    /// Leave unclipped, which keeps detail but may tint highlights
+    Unclip,
+    /// Blend clipped and unclipped values
+    Blend,
+    /// Reconstruct clipped channels from the others
+    Rebuild,
+}
+
+/// Demosaicing algorithms, roughly from fastest to highest quality
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Demosaic {
+    Linear,
+    Vng,
+    Ppg,
+    Ahd,
+    Dcb,
+    Dht,
+    Aahd,
+}
+
+impl HighlightMode {
+    /// libraw's `highlight` parameter
+    fn libraw_mode(self) -> i32 {
+        match self {
+            HighlightMode::Clip => 0,
+            HighlightMode::Unclip => 1,
+            HighlightMode::Blend => 2,
+            HighlightMode::Rebuild => 5,
+        }
+    }
+}
+
+impl Demosaic {
+    /// libraw's `user_qual` parameter
+    fn libraw_quality(self) -> i32 {
+        match self {
+            Demosaic::Linear => 0,
+            Demosaic::Vng => 1,
+            Demosaic::Ppg => 2,
+            Demosaic::Ahd => 3,
+            Demosaic::Dcb => 4,
+            Demosaic::Dht => 11,
+            Demosaic::Aahd => 12,
+        }
+    }
+}
+
+/// Settings which RAWs are developed with. Settings which are not given keep libraw's defaults.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(default)]
+pub struct Development {
+    pub white_balance: Option<WhiteBalance>,
+    /// Exposure compensation in stops, from -2 to +3
+    pub exposure: Option<f32>,
+    pub highlights: Option<HighlightMode>,
+    pub demosaic: Option<Demosaic>,
+    /// Develop the RAW at 16 bits per channel, and only round it to 8 bits at the end
+    pub sixteen_bit: Option<bool>,
+}
+
+impl Development {
+    pub(crate) fn validate(&self) -> Result<(), &'static str> {
+        match self.exposure {
+            Some(exposure) if !(-2. ..=3.).contains(&exposure) => {
+                Err("exposure must be between -2 and 3")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Fills in settings which are not given from `preset`
+    pub(crate) fn or(self, preset: &Development) -> Development {
+        Development {
+            white_balance: self.white_balance.or(preset.white_balance),
+            exposure: self.exposure.or(preset.exposure),
+            highlights: self.highlights.or(preset.highlights),
+            demosaic: self.demosaic.or(preset.demosaic),
+            sixteen_bit: self.sixteen_bit.or(preset.sixteen_bit),
+        }
+    }
+
+    /// Whether the RAW is developed with libraw's defaults, which is what the embedded preview
+    /// approximates
+    pub(crate) fn is_default(&self) -> bool {
+        *self == Development::default()
+    }
+
+    /// Develops the RAW in `buf` to 8-bit RGB
+    pub(crate) fn process(&self, buf: &[u8]) -> Option<RgbImage> {
+        let raw = LibRaw::new()?;
+        // SAFETY: `raw` is a LibRaw instance until it is dropped, and `buf` outlives it
+        unsafe {
+            if sys::libraw_open_buffer(raw.0, buf.as_ptr() as _, buf.len() as _) != 0 {
+                return None;
+            }
+            self.configure(&mut *raw.0);
+            if sys::libraw_unpack(raw.0) != 0 || sys::libraw_dcraw_process(raw.0) != 0 {
+                return None;
+            }
+            let mut error = 0;
+            let image = sys::libraw_dcraw_make_mem_image(raw.0, &mut error);
+            if image.is_null() {
+                return None;
+            }
+            ProcessedImage(image).to_rgb8()
+        }
+    }
+
+    /// Sets LibRaw's output params for a RAW which has been opened, but not unpacked yet
+    fn configure(&self, raw: &mut sys::libraw_data_t) {
+        let params = &mut raw.params;
+        match self.white_balance {
+            Some(WhiteBalance::AsShot) => params.use_camera_wb = 1,
+            Some(WhiteBalance::Auto) => params.use_auto_wb = 1,
+            Some(WhiteBalance::Kelvin(kelvin)) => {
+                match kelvin_multipliers(kelvin, raw.idata.colors, &raw.color) {
+                    Some(multipliers) => params.user_mul = multipliers,
+                    None => log::warn!(
+                        "Can not white balance RAW for {}K, developing it for daylight",
+                        kelvin
+                    ),
+                }
+            }
+            None => {}
+        }
+        if let Some(exposure) = self.exposure {
+            params.exp_correc = 1;
+            params.exp_shift = 2f32.powf(exposure);
+        }
+        if let Some(highlights) = self.highlights {
+            params.highlight = highlights.libraw_mode();
+        }
+        if let Some(demosaic) = self.demosaic {
+            params.user_qual = demosaic.libraw_quality();
+        }
+        if self.sixteen_bit == Some(true) {
+            params.output_bps = 16;
+        }
+    }
+}
+
+/// A LibRaw instance, which is closed when dropped
+struct LibRaw(*mut sys::libraw_data_t);
+
+impl LibRaw {
+    fn new() -> Option<Self> {
+        // SAFETY: LibRaw allocates the instance, and returns null if it can not
+        let raw = unsafe { sys::libraw_init(0) };
+        (!raw.is_null()).then(|| LibRaw(raw))
+    }
+}
+
+impl Drop for LibRaw {
+    fn drop(&mut self) {
+        // SAFETY: the instance is not used after it is closed
+        unsafe { sys::libraw_close(self.0) }
+    }
+}
+
+/// An image which LibRaw developed, which is freed when dropped
+struct ProcessedImage(*mut sys::libraw_processed_image_t);
+
+impl ProcessedImage {
+    /// Copies the image out as 8-bit RGB, rounding 16-bit images once
+    fn to_rgb8(&self) -> Option<RgbImage> {
+        // SAFETY: LibRaw allocates `data_size` bytes of pixels after the header
+        let image = unsafe { &*self.0 };
+        let data = unsafe {
+            let pixels = std::ptr::addr_of!((*self.0).data) as *const u8;
+            std::slice::from_raw_parts(pixels, image.data_size as usize)
+        };
+        if image.colors != 3 {
+            return None;
+        }
+        let pixels = match image.bits {
+            8 => data.to_vec(),
+            16 => data
+                .chunks_exact(2)
+                .map(|value| u16::from_ne_bytes([value[0], value[1]]))
+                .map(|value| ((u32::from(value) * 255 + 32767) / 65535) as u8)
+                .collect(),
+            _ => return None,
+        };
+        RgbImage::from_raw(u32::from(image.width), u32::from(image.height), pixels)
+    }
+}
+
+impl Drop for ProcessedImage {
+    fn drop(&mut self) {
+        // SAFETY: the image is not used after it is freed
+        unsafe { sys::libraw_dcraw_clear_mem(self.0) }
+    }
+}
+
+/// Linear sRGB color of a black body at `kelvin`, with a luminance of 1
+fn black_body_rgb(kelvin: u32) -> [f64; 3] {
+    // Kim et al.'s cubic spline approximation of the Planckian locus
+    let t = f64::from(kelvin);
+    let x = if t <= 4000. {
+        -0.2661239e9 / t.powi(3) - 0.2343589e6 / t.powi(2) + 0.8776956e3 / t + 0.179910
+    } else {
+        -3.0258469e9 / t.powi(3) + 2.1070379e6 / t.powi(2) + 0.2226347e3 / t + 0.240390
+    };
+    let y = if t <= 2222. {
+        -1.1063814 * x.powi(3) - 1.34811020 * x.powi(2) + 2.18555832 * x - 0.20219683
+    } else if t <= 4000. {
+        -0.9549476 * x.powi(3) - 1.37418593 * x.powi(2) + 2.09137015 * x - 0.16748867
+    } else {
+        3.0817580 * x.powi(3) - 5.87338670 * x.powi(2) + 3.75112997 * x - 0.37001483
+    };
+
+    let (x, y, z) = (x / y, 1., (1. - x - y) / y);
+    [
+        3.2404542 * x - 1.5371385 * y - 0.4985314 * z,
+        -0.9692660 * x + 1.8760108 * y + 0.0415560 * z,
+        0.0556434 * x - 0.2040259 * y + 1.0572252 * z,
+    ]
+}
+
+/// LibRaw's `user_mul` for light of the given color temperature: the camera's daylight
+/// multipliers, divided by how its white-balanced channels respond to the light. Four-color
+/// sensors and cameras which LibRaw has no daylight multipliers for are left as they are.
+fn kelvin_multipliers(
+    kelvin: u32,
+    colors: i32,
+    color: &sys::libraw_colordata_t,
+) -> Option<[f32; 4]> {
+    if colors != 3
+        || color.pre_mul[..3]
+            .iter()
+            .any(|multiplier| *multiplier <= 0.)
+    {
+        return None;
+    }
+    // The light as it appears in an image developed for daylight, in linear sRGB
+    let daylight = black_body_rgb(6500);
+    let light = black_body_rgb(kelvin);
+    let light = [0, 1, 2].map(|channel| light[channel] / daylight[channel]);
+
+    // `rgb_cam` converts white-balanced camera channels to linear sRGB
+    let rgb_cam = color
+        .rgb_cam
+        .map(|row| [0, 1, 2].map(|channel| f64::from(row[channel])));
+    let cam_rgb = invert(rgb_cam)?;
+    let response = cam_rgb.map(|row| (0..3).map(|channel| row[channel] * light[channel]).sum());
+    if response.iter().any(|response: &f64| *response <= 0.) {
+        return None;
+    }
+    let multipliers =
+        [0, 1, 2].map(|channel| f64::from(color.pre_mul[channel]) / response[channel]);
+    let [red, green, blue] = multipliers.map(|multiplier| (multiplier / multipliers[1]) as f32);
+    // Both greens of the Bayer pattern share a multiplier
+    Some([red, green, blue, green])
+}
+
+/// The inverse of `matrix`, if it has one
+fn invert(matrix: [[f64; 3]; 3]) -> Option<[[f64; 3]; 3]> {
+    // Taking the rows and columns cyclically gives the cofactors their signs
+    let cofactor = |row: usize, column: usize| {
+        let (row_1, row_2) = ((row + 1) % 3, (row + 2) % 3);
+        let (column_1, column_2) = ((column + 1) % 3, (column + 2) % 3);
+        matrix[row_1][column_1] * matrix[row_2][column_2]
+            - matrix[row_1][column_2] * matrix[row_2][column_1]
+    };
+    let determinant: f64 = (0..3)
+        .map(|column| matrix[0][column] * cofactor(0, column))
+        .sum();
+    if determinant.abs() < 1e-9 {
+        return None;
+    }
+    Some([0, 1, 2].map(|row| [0, 1, 2].map(|column| cofactor(column, row) / determinant)))
+}
+
+/// Named development settings, shared by everyone using this library. Those read from
+/// `raw_presets.ron` are set up with the library and can not be changed, while users store more
+/// in the database, which only they and admins may replace or delete.
+#[derive(Default)]
+pub struct Presets(BTreeMap<String, Development>);
+
+impl Presets {
+    /// Reads presets from the RON map of names to settings at `path`, if it exists
+    pub(crate) fn load(path: &Path) -> Result<Self, ron::Error> {
+        match std::fs::read_to_string(path) {
+            Ok(presets) => Ok(Presets(ron::from_str(&presets)?)),
+            Err(_) => Ok(Presets::default()),
+        }
+    }
+
+    /// The preset named `name`, from the file or else from the database
+    pub(crate) async fn get(
+        &self,
+        data: &SQLiteDatabase,
+        name: &str,
+    ) -> sqlx::Result<Option<Development>> {
+        match self.0.get(name) {
+            Some(preset) => Ok(Some(*preset)),
+            None => data.raw_preset(name).await,
+        }
+    }
+}
+
+fn internal_error(e: impl std::fmt::Debug) -> HttpResponse {
+    log::warn!("{:?}", e);
+    HttpResponse::InternalServerError().body("")
+}
+
+fn preset_not_found(name: &str) -> HttpResponse {
+    HttpResponse::NotFound().body(format!("preset {} not found", name))
+}
+
+pub async fn raw_presets(data: Data<Arc<SQLiteDatabase>>, presets: Data<Presets>) -> HttpResponse {
+    match data.raw_presets().await {
+        Ok(mut stored) => {
+            stored.extend(presets.0.clone());
+            HttpResponse::Ok().json(stored)
+        }
+        Err(e) => internal_error(e),
+    }
+}
+
+/// Stores a preset, or replaces one which the user stored before
+pub async fn save_raw_preset(
+    data: Data<Arc<SQLiteDatabase>>,
+    presets: Data<Presets>,
+    user: User,
+    name: web::Path<String>,
+    development: Json<Development>,
+) -> HttpResponse {
+    if presets.0.contains_key(name.as_str()) {
+        return HttpResponse::Conflict().body(format!("preset {} can not be changed", name));
+    }
+    if let Err(reason) = development.validate() {
+        return HttpResponse::BadRequest().body(reason);
+    }
+    match data.save_raw_preset(&name, &user, &development).await {
+        Ok(true) => HttpResponse::Ok().json(development.into_inner()),
+        Ok(false) => {
+            HttpResponse::Forbidden().body(format!("preset {} belongs to another user", name))
+        }
+        Err(e) => internal_error(e),
+    }
+}
+
+pub async fn delete_raw_preset(
+    data: Data<Arc<SQLiteDatabase>>,
+    presets: Data<Presets>,
+    user: User,
+    name: web::Path<String>,
+) -> HttpResponse {
+    if presets.0.contains_key(name.as_str()) {
+        return HttpResponse::Conflict().body(format!("preset {} can not be changed", name));
+    }
+    match data.delete_raw_preset(&name, &user).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => preset_not_found(&name),
+        Err(e) => internal_error(e),
+    }
+}
@@ -0,0 +1,194 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use actix_web::web::{self, Data};
+use actix_web::HttpResponse;
+use image::imageops::FilterType;
+
+use futures::StreamExt;
+
+use crate::auth::User;
+use crate::db::Id;
+use crate::SQLiteDatabase;
+
+/// Difference hash of an encoded image. Each bit records whether a pixel of a 9x8 grayscale
+/// thumbnail is brighter than its right neighbour, so that re-encoding, resizing and small edits
+/// only flip a few bits.
+pub(crate) fn dhash(encoded: &[u8]) -> Option<u64> {
+    let thumbnail = image::load_from_memory(encoded)
+        .ok()?
+        .resize_exact(9, 8, FilterType::Triangle)
+        .into_luma8();
+    let mut hash = 0;
+    for y in 0..8 {
+        for x in 0..8 {
+            let brighter = thumbnail.get_pixel(x, y)[0] > thumbnail.get_pixel(x + 1, y)[0];
+            hash = (hash << 1) | u64::from(brighter);
+        }
+    }
+    Some(hash)
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(a, b)| a * b).sum();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    dot / (norm(a) * norm(b)).max(f32::EPSILON)
+}
+
+/// How many CLIP vectors are fetched from weaviate at once
+const CONCURRENT_FETCHES: usize = 16;
+
+/// Pairs of images whose hashes differ by at most `max_distance` bits
+// TODO: index the hashes (eg. with a BK-tree) once libraries get large enough for this to hurt
+fn candidates(hashes: &[(Id, u64)], max_distance: u32) -> Vec<(usize, usize)> {
+    let mut candidates = vec![];
+    for (i, (_, a)) in hashes.iter().enumerate() {
+        for (j, (_, b)) in hashes.iter().enumerate().skip(i + 1) {
+            if (a ^ b).count_ones() <= max_distance {
+                candidates.push((i, j));
+            }
+        }
+    }
+    candidates
+}
+
+/// Union-find over indices, for grouping pairs of near-duplicates into clusters
+struct DisjointSet {
+    parents: Vec<usize>,
+}
+
+impl DisjointSet {
+    fn new(len: usize) -> Self {
+        Self {
+            parents: (0..len).collect(),
+        }
+    }
+
+    /// The root of the set of `index`. Every other node on the way points to its grandparent
+    /// afterwards, which keeps chains short without recursing down them.
+    fn find(&mut self, mut index: usize) -> usize {
+        while self.parents[index] != index {
+            let grandparent = self.parents[self.parents[index]];
+            self.parents[index] = grandparent;
+            index = grandparent;
+        }
+        index
+    }
+
+    fn union(&mut self, a: usize, b: usize) {
+        let (a, b) = (self.find(a), self.find(b));
+        self.parents[a] = b;
+    }
+}
+
+#[derive(Deserialize)]
+pub struct DuplicatesRequest {
+    /// Most bits which the perceptual hashes of near-duplicates may differ by, from 0 to 64
+    max_distance: Option<u32>,
+    /// Least cosine similarity of the CLIP vectors of near-duplicates, from -1 to 1
+    min_similarity: Option<f32>,
+}
+
+#[derive(Serialize)]
+pub struct DuplicateCluster {
+    ids: Vec<Id>,
+}
+
+#[derive(Serialize)]
+pub struct DuplicatesResponse {
+    /// Groups of near-identical images, largest first
+    clusters: Vec<DuplicateCluster>,
+}
+
+/// Finds clusters of near-identical images which the user may see, such as bursts, re-exports
+/// and edited copies. Pairs of images whose perceptual hashes are close are candidates, which
+/// are confirmed by comparing their CLIP vectors, since the hash alone matches unrelated images
+/// with similar layouts. Candidates whose vectors can not be fetched are not confirmed.
+pub async fn duplicates(
+    data: Data<Arc<SQLiteDatabase>>,
+    user: User,
+    params: web::Query<DuplicatesRequest>,
+) -> HttpResponse {
+    let max_distance = params.max_distance.unwrap_or(10);
+    let min_similarity = params.min_similarity.unwrap_or(0.9);
+    if max_distance > 64 {
+        return HttpResponse::BadRequest().body("max_distance must be between 0 and 64");
+    }
+    if !(-1. ..=1.).contains(&min_similarity) {
+        return HttpResponse::BadRequest().body("min_similarity must be between -1 and 1");
+    }
+
+    let hashes = match data.perceptual_hashes(&user).await {
+        Ok(hashes) => hashes,
+        Err(e) => {
+            log::warn!("{:?}", e);
+            return HttpResponse::InternalServerError().body("");
+        }
+    };
+
+    // Comparing every pair is quadratic, so it is kept off the worker which serves requests
+    let (hashes, candidates) = match web::block(move || {
+        let candidates = candidates(&hashes, max_distance);
+        (hashes, candidates)
+    })
+    .await
+    {
+        Ok(result) => result,
+        Err(e) => {
+            log::warn!("{:?}", e);
+            return HttpResponse::InternalServerError().body("");
+        }
+    };
+
+    // Only fetch vectors for images which have a candidate
+    let involved: BTreeSet<usize> = candidates.iter().flat_map(|&(i, j)| [i, j]).collect();
+    let vectors: HashMap<usize, Vec<f32>> = futures::stream::iter(involved)
+        .map(|index| {
+            let data = &data;
+            let id = &hashes[index].0;
+            async move {
+                match data.image_vector(id).await {
+                    Ok(Some(vector)) => Some((index, vector)),
+                    Ok(None) => {
+                        log::info!("{} was never vectorized, so it is not compared", id);
+                        None
+                    }
+                    Err(e) => {
+                        log::warn!("Failed to fetch vector of {}: {:?}", id, e);
+                        None
+                    }
+                }
+            }
+        })
+        .buffer_unordered(CONCURRENT_FETCHES)
+        .filter_map(futures::future::ready)
+        .collect()
+        .await;
+
+    let mut clusters = DisjointSet::new(hashes.len());
+    for (i, j) in candidates {
+        // Hashes alone are not enough to confirm a pair
+        let similar = match (vectors.get(&i), vectors.get(&j)) {
+            (Some(a), Some(b)) => cosine_similarity(a, b) >= min_similarity,
+            _ => false,
+        };
+        if similar {
+            clusters.union(i, j);
+        }
+    }
+
+    let mut members: HashMap<usize, Vec<Id>> = HashMap::new();
+    for (index, (id, _)) in hashes.into_iter().enumerate() {
+        members.entry(clusters.find(index)).or_default().push(id);
+    }
+    let mut clusters: Vec<_> = members
+        .into_values()
+        .filter(|ids| ids.len() > 1)
+        .map(|ids| DuplicateCluster { ids })
+        .collect();
+    clusters.sort_by(|a, b| b.ids.len().cmp(&a.ids.len()));
+
+    HttpResponse::Ok().json(DuplicatesResponse { clusters })
+}
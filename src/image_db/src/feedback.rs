@@ -0,0 +1,250 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use actix_web::web::{Data, Json};
+use actix_web::HttpResponse;
+
+use crate::auth::User;
+use crate::classifiers::{example_vectors, missing_examples, Missing};
+use crate::db::{search_filter, Id, SearchScope};
+use crate::vocabulary::normalized;
+use crate::SQLiteDatabase;
+
+/// Weights of the query, of the images marked relevant and of those marked irrelevant in the
+/// refined query, as commonly used for Rocchio's algorithm
+const QUERY_WEIGHT: f32 = 1.0;
+const RELEVANT_WEIGHT: f32 = 0.75;
+const IRRELEVANT_WEIGHT: f32 = 0.15;
+/// How long sessions last after they were last refined
+const SESSION_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+/// How many images a session may mark, which bounds how many extra results are requested to
+/// leave out the marked ones
+const MAX_MARKED: usize = 200;
+/// How many sessions each user may have, which bounds the memory that sessions take up. Users
+/// who start more lose their least recently used sessions.
+const MAX_SESSIONS_PER_USER: usize = 20;
+
+/// A search which is being refined. Marked images are kept with their vectors, so that they are
+/// only fetched once.
+struct Session {
+    owner: String,
+    query: Vec<f32>,
+    scope: SearchScope,
+    relevant: HashMap<Id, Vec<f32>>,
+    irrelevant: HashMap<Id, Vec<f32>>,
+    last_used: Instant,
+}
+
+impl Session {
+    fn is_marked(&self, id: &str) -> bool {
+        self.relevant.contains_key(id) || self.irrelevant.contains_key(id)
+    }
+
+    /// Rocchio's refined query: the original query, moved towards the centroid of the relevant
+    /// images and away from the centroid of the irrelevant ones
+    fn refined_query(&self) -> Vec<f32> {
+        let mut query: Vec<f32> = normalized(self.query.clone())
+            .into_iter()
+            .map(|x| x * QUERY_WEIGHT)
+            .collect();
+        for (vectors, weight) in [
+            (&self.relevant, RELEVANT_WEIGHT),
+            (&self.irrelevant, -IRRELEVANT_WEIGHT),
+        ] {
+            let count = vectors.len() as f32;
+            for vector in vectors.values() {
+                query
+                    .iter_mut()
+                    .zip(vector)
+                    .for_each(|(query, x)| *query += weight * x / count);
+            }
+        }
+        normalized(query)
+    }
+}
+
+/// Searches which are being refined with relevance feedback. Sessions are kept in memory, and
+/// are lost on restart.
+#[derive(Default)]
+pub struct FeedbackSessions {
+    sessions: Mutex<HashMap<uuid::Uuid, Session>>,
+}
+
+impl FeedbackSessions {
+    /// Takes the session out while it is refined, so that the lock is not held across requests
+    /// to weaviate
+    fn take(&self, id: &uuid::Uuid, user: &User) -> Option<Session> {
+        let mut sessions = self.sessions.lock().unwrap();
+        match sessions.get(id) {
+            Some(session)
+                if session.owner == user.name && session.last_used.elapsed() < SESSION_TIMEOUT =>
+            {
+                sessions.remove(id)
+            }
+            _ => None,
+        }
+    }
+
+    /// Stores the session, dropping those which timed out and the least recently used sessions
+    /// of its owner beyond `MAX_SESSIONS_PER_USER`
+    fn put(&self, id: uuid::Uuid, mut session: Session) {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, session| session.last_used.elapsed() < SESSION_TIMEOUT);
+        let mut owned: Vec<(Instant, uuid::Uuid)> = sessions
+            .iter()
+            .filter(|(other, other_session)| **other != id && other_session.owner == session.owner)
+            .map(|(other, other_session)| (other_session.last_used, *other))
+            .collect();
+        if owned.len() >= MAX_SESSIONS_PER_USER {
+            owned.sort_unstable();
+            for (_, oldest) in &owned[..=owned.len() - MAX_SESSIONS_PER_USER] {
+                sessions.remove(oldest);
+            }
+        }
+        session.last_used = Instant::now();
+        sessions.insert(id, session);
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Feedback {
+    /// The text of a new search. Either this or `session` must be given.
+    text: Option<String>,
+    /// A search which was refined before, which keeps its text, scope and marked images
+    session: Option<uuid::Uuid>,
+    #[serde(flatten)]
+    scope: SearchScope,
+    /// Results which are what the user is looking for
+    #[serde(default)]
+    relevant: Vec<Id>,
+    /// Results which are not what the user is looking for
+    #[serde(default)]
+    irrelevant: Vec<Id>,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct FeedbackOutput {
+    /// Refines the search further when given with more marked images
+    session: uuid::Uuid,
+    /// The nearest images to the refined query, leaving out those which were marked
+    ids: Vec<Id>,
+}
+
+fn internal_error(e: impl std::fmt::Debug) -> HttpResponse {
+    log::warn!("{:?}", e);
+    HttpResponse::InternalServerError().body("")
+}
+
+/// Refines a search with images which the user marked as relevant or irrelevant, using Rocchio's
+/// algorithm over their vectors. The first request gives the text of the search, and later ones
+/// give the session which it returned. Images which were marked before take their new mark.
+pub async fn near_text_feedback(
+    data: Data<Arc<SQLiteDatabase>>,
+    sessions: Data<FeedbackSessions>,
+    user: User,
+    feedback: Json<Feedback>,
+) -> HttpResponse {
+    let feedback = feedback.into_inner();
+    if feedback
+        .relevant
+        .iter()
+        .any(|id| feedback.irrelevant.contains(id))
+    {
+        return HttpResponse::BadRequest().body("images can not be both relevant and irrelevant");
+    }
+    let (relevant, irrelevant) = match (
+        example_vectors(&data, &user, &feedback.relevant).await,
+        example_vectors(&data, &user, &feedback.irrelevant).await,
+    ) {
+        (Ok(relevant), Ok(irrelevant)) => (relevant, irrelevant),
+        (Err(Missing::Images(mut missing)), Err(Missing::Images(more))) => {
+            missing.extend(more);
+            return missing_examples(&missing);
+        }
+        (Err(Missing::Images(missing)), _) | (_, Err(Missing::Images(missing))) => {
+            return missing_examples(&missing)
+        }
+        (Err(Missing::Error(e)), _) | (_, Err(Missing::Error(e))) => return internal_error(e),
+    };
+
+    // Sessions which were taken out are put back if refining them fails, while new ones are
+    // only stored once they have been searched with
+    let existing = feedback.session.is_some();
+    let restore = |id, session| {
+        if existing {
+            sessions.put(id, session);
+        }
+    };
+    let (id, mut session) = match (feedback.text, feedback.session) {
+        (Some(text), None) => {
+            let query = match data.text_vector(text).await {
+                Ok(query) => query,
+                Err(e) => return internal_error(e),
+            };
+            let session = Session {
+                owner: user.name.clone(),
+                query,
+                scope: feedback.scope,
+                relevant: HashMap::new(),
+                irrelevant: HashMap::new(),
+                last_used: Instant::now(),
+            };
+            (uuid::Uuid::new_v4(), session)
+        }
+        (None, Some(id)) => match sessions.take(&id, &user) {
+            Some(session) => (id, session),
+            None => return HttpResponse::NotFound().body(format!("session {} not found", id)),
+        },
+        _ => return HttpResponse::BadRequest().body("either text or session must be given"),
+    };
+
+    let filter = match search_filter(&data, &user, &session.scope).await {
+        Ok(filter) => filter,
+        Err(response) => {
+            restore(id, session);
+            return response;
+        }
+    };
+    let newly_marked = feedback
+        .relevant
+        .iter()
+        .chain(&feedback.irrelevant)
+        .filter(|id| !session.is_marked(id))
+        .count();
+    let marked = session.relevant.len() + session.irrelevant.len() + newly_marked;
+    if marked > MAX_MARKED {
+        restore(id, session);
+        return HttpResponse::BadRequest()
+            .body(format!("at most {} images may be marked", MAX_MARKED));
+    }
+    for (image, vector) in feedback.relevant.into_iter().zip(relevant) {
+        session.irrelevant.remove(&image);
+        session.relevant.insert(image, normalized(vector));
+    }
+    for (image, vector) in feedback.irrelevant.into_iter().zip(irrelevant) {
+        session.relevant.remove(&image);
+        session.irrelevant.insert(image, normalized(vector));
+    }
+
+    let limit = feedback.limit.unwrap_or(5).min(100);
+    let ids = match data
+        .near_vector(&session.refined_query(), limit + marked, &filter)
+        .await
+    {
+        Ok(ids) => ids
+            .into_iter()
+            .filter(|id| !session.is_marked(id))
+            .take(limit)
+            .collect(),
+        Err(e) => {
+            restore(id, session);
+            return internal_error(e);
+        }
+    };
+    sessions.put(id, session);
+    HttpResponse::Ok().json(FeedbackOutput { session: id, ids })
+}
@@ -0,0 +1,221 @@
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use serde::Serialize;
+
+use actix_web::HttpResponse;
+use image::ImageFormat;
+
+use crate::images::{output_formats, OutputFormat};
+
+/// The library which decodes a file format
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Decoder {
+    /// Camera RAWs, which are developed by libraw
+    LibRaw,
+    /// Raster images, which are decoded by the `image` crate
+    Raster(ImageFormat),
+}
+
+/// A file format which can be ingested
+pub struct Format {
+    pub name: &'static str,
+    pub decoder: Decoder,
+    /// Accepted file extensions, in lowercase. The first is used when storing uploads.
+    pub extensions: &'static [&'static str],
+    pub mime_types: &'static [&'static str],
+}
+
+const fn raw(
+    name: &'static str,
+    extensions: &'static [&'static str],
+    mime_types: &'static [&'static str],
+) -> Format {
+    Format {
+        name,
+        decoder: Decoder::LibRaw,
+        extensions,
+        mime_types,
+    }
+}
+
+const fn raster(
+    name: &'static str,
+    format: ImageFormat,
+    extensions: &'static [&'static str],
+    mime_types: &'static [&'static str],
+) -> Format {
+    Format {
+        name,
+        decoder: Decoder::Raster(format),
+        extensions,
+        mime_types,
+    }
+}
+
+/// Every format which can be ingested, and the decoder which handles it
+pub const FORMATS: &[Format] = &[
+    raw(
+        "Canon RAW",
+        &["cr2", "cr3", "crw"],
+        &[
+            "image/x-canon-cr2",
+            "image/x-canon-cr3",
+            "image/x-canon-crw",
+        ],
+    ),
+    raw(
+        "Nikon RAW",
+        &["nef", "nrw"],
+        &["image/x-nikon-nef", "image/x-nikon-nrw"],
+    ),
+    raw(
+        "Sony RAW",
+        &["arw", "srf", "sr2"],
+        &["image/x-sony-arw", "image/x-sony-srf", "image/x-sony-sr2"],
+    ),
+    raw("Fujifilm RAW", &["raf"], &["image/x-fuji-raf"]),
+    raw("Olympus RAW", &["orf"], &["image/x-olympus-orf"]),
+    raw(
+        "Panasonic RAW",
+        &["rw2", "raw"],
+        &["image/x-panasonic-rw2", "image/x-panasonic-raw"],
+    ),
+    raw("Pentax RAW", &["pef"], &["image/x-pentax-pef"]),
+    raw("Samsung RAW", &["srw"], &["image/x-samsung-srw"]),
+    raw("Leica RAW", &["rwl"], &["image/x-leica-rwl"]),
+    raw("Hasselblad RAW", &["3fr"], &["image/x-hasselblad-3fr"]),
+    raw("Phase One RAW", &["iiq"], &["image/x-phaseone-iiq"]),
+    raw("Sigma RAW", &["x3f"], &["image/x-sigma-x3f"]),
+    raw("Minolta RAW", &["mrw"], &["image/x-minolta-mrw"]),
+    raw(
+        "Kodak RAW",
+        &["dcr", "kdc"],
+        &["image/x-kodak-dcr", "image/x-kodak-kdc"],
+    ),
+    raw("Adobe Digital Negative", &["dng"], &["image/x-adobe-dng"]),
+    raster(
+        "JPEG",
+        ImageFormat::Jpeg,
+        &["jpg", "jpeg", "jpe"],
+        &["image/jpeg"],
+    ),
+    raster("PNG", ImageFormat::Png, &["png"], &["image/png"]),
+    raster("TIFF", ImageFormat::Tiff, &["tif", "tiff"], &["image/tiff"]),
+    raster("WebP", ImageFormat::WebP, &["webp"], &["image/webp"]),
+];
+
+impl Decoder {
+    fn name(&self) -> &'static str {
+        match self {
+            Decoder::LibRaw => "libraw",
+            Decoder::Raster(_) => "raster",
+        }
+    }
+}
+
+/// Returns the format with the given file extension, ignoring case
+pub fn from_extension(extension: &str) -> Option<&'static Format> {
+    let extension = extension.to_ascii_lowercase();
+    FORMATS
+        .iter()
+        .find(|format| format.extensions.contains(&extension.as_str()))
+}
+
+fn from_image_format(image_format: ImageFormat) -> Option<&'static Format> {
+    FORMATS
+        .iter()
+        .find(|format| format.decoder == Decoder::Raster(image_format))
+}
+
+/// Identifies the format of a file from its first bytes, using the extension to tell camera RAWs
+/// apart, since most of them are TIFF containers or have no reliable signature.
+pub fn sniff(header: &[u8], extension: Option<&str>) -> Option<&'static Format> {
+    let by_extension = extension.and_then(from_extension);
+
+    if header.starts_with(&[0xff, 0xd8, 0xff]) {
+        return from_image_format(ImageFormat::Jpeg);
+    }
+    if header.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return from_image_format(ImageFormat::Png);
+    }
+    if header.starts_with(b"RIFF") && header.get(8..12) == Some(&b"WEBP"[..]) {
+        return from_image_format(ImageFormat::WebP);
+    }
+    if header.starts_with(b"II*\x00") || header.starts_with(b"MM\x00*") {
+        // TIFF-based RAWs (CR2, NEF, ARW, DNG, ...) share the TIFF signature
+        return match by_extension {
+            Some(format) if format.decoder == Decoder::LibRaw => Some(format),
+            _ => from_image_format(ImageFormat::Tiff),
+        };
+    }
+
+    by_extension.filter(|format| format.decoder == Decoder::LibRaw)
+}
+
+/// Identifies the format of `file` from its first bytes and extension.
+pub fn sniff_file(
+    file: &mut std::fs::File,
+    extension: Option<&str>,
+) -> std::io::Result<Option<&'static Format>> {
+    let mut header = Vec::with_capacity(16);
+    file.seek(SeekFrom::Start(0))?;
+    file.by_ref().take(16).read_to_end(&mut header)?;
+    file.seek(SeekFrom::Start(0))?;
+    Ok(sniff(&header, extension))
+}
+
+/// Returns the extension of `path`, if it is valid UTF-8
+pub fn extension(path: &Path) -> Option<&str> {
+    path.extension().and_then(|extension| extension.to_str())
+}
+
+/// Whether files at `path` are ingested, judging by the extension alone
+pub fn is_supported_path(path: &Path) -> bool {
+    extension(path).and_then(from_extension).is_some()
+}
+
+#[derive(Serialize)]
+pub struct SupportedFormat {
+    name: &'static str,
+    extensions: &'static [&'static str],
+    mime_types: &'static [&'static str],
+}
+
+#[derive(Serialize)]
+pub struct DecoderFormats {
+    decoder: &'static str,
+    formats: Vec<SupportedFormat>,
+}
+
+#[derive(Serialize)]
+pub struct SupportedExtensions {
+    /// Accepted input formats, grouped by the decoder which handles them
+    decoders: Vec<DecoderFormats>,
+    /// Formats which renditions can be produced in
+    output_formats: Vec<OutputFormat>,
+}
+
+pub async fn supported_ext() -> HttpResponse {
+    let mut decoders: Vec<DecoderFormats> = vec![];
+    for format in FORMATS {
+        let name = format.decoder.name();
+        let supported = SupportedFormat {
+            name: format.name,
+            extensions: format.extensions,
+            mime_types: format.mime_types,
+        };
+        match decoders.iter_mut().find(|decoder| decoder.decoder == name) {
+            Some(decoder) => decoder.formats.push(supported),
+            None => decoders.push(DecoderFormats {
+                decoder: name,
+                formats: vec![supported],
+            }),
+        }
+    }
+
+    HttpResponse::Ok().json(SupportedExtensions {
+        decoders,
+        output_formats: output_formats(),
+    })
+}
@@ -0,0 +1,59 @@
+use std::fmt::Debug;
+
+use actix_web::http::header::{CacheControl, CacheDirective, ETag, EntityTag, Header, IfNoneMatch};
+use actix_web::{HttpRequest, HttpResponseBuilder};
+
+/// Identifies the version of an image in URLs, so that they can be cached forever
+pub(crate) const VERSION_HEADER: &str = "X-Image-Version";
+
+/// Long enough to be treated as forever by caches
+const ONE_YEAR_SECS: u32 = 365 * 24 * 60 * 60;
+
+/// The version of an image with the given md5, which clients pass as `v` to get responses which
+/// can be cached forever
+pub(crate) fn version(md5: &[u8]) -> String {
+    md5.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// A strong ETag for the response to a request for `id`, which is deterministic across restarts
+/// and replicas, since it only depends on the image contents and the request parameters.
+pub(crate) fn etag(id: &str, md5: &[u8], params: impl Debug) -> EntityTag {
+    let digest = md5::compute(format!("{}/{}/{:?}", id, version(md5), params));
+    EntityTag::new_strong(format!("{:x}", digest))
+}
+
+/// Responses for URLs which pin the image version never change, and may be cached forever.
+/// Otherwise, caches must revalidate them with the ETag, since the image may be replaced. Images
+/// may be private, so only the client's own cache may store them.
+pub(crate) fn cache_control(requested_version: Option<&str>, md5: &[u8]) -> CacheControl {
+    match requested_version {
+        Some(requested) if requested.eq_ignore_ascii_case(&version(md5)) => CacheControl(vec![
+            CacheDirective::Private,
+            CacheDirective::MaxAge(ONE_YEAR_SECS),
+            CacheDirective::Extension("immutable".to_string(), None),
+        ]),
+        _ => CacheControl(vec![CacheDirective::NoCache]),
+    }
+}
+
+/// Whether the client already has the response with `etag`, according to `If-None-Match`
+pub(crate) fn is_fresh(req: &HttpRequest, etag: &EntityTag) -> bool {
+    match IfNoneMatch::parse(req) {
+        Ok(IfNoneMatch::Any) => true,
+        Ok(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(etag)),
+        Err(_) => false,
+    }
+}
+
+/// Sets the headers which are sent with both full and `304 Not Modified` responses
+pub(crate) fn insert_validators<'a>(
+    response: &'a mut HttpResponseBuilder,
+    etag: EntityTag,
+    cache_control: CacheControl,
+    md5: &[u8],
+) -> &'a mut HttpResponseBuilder {
+    response
+        .insert_header(ETag(etag))
+        .insert_header(cache_control)
+        .insert_header((VERSION_HEADER, version(md5)))
+}
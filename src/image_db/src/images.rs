@@ -1,100 +1,940 @@
+use std::io::Cursor;
 use std::num::NonZeroU32;
 use std::ops::Deref;
+use std::path::Path;
 use std::sync::Arc;
+use std::time::UNIX_EPOCH;
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
+use actix_web::http::header;
 use actix_web::web::Data;
-use actix_web::{web, HttpResponse};
+use actix_web::{web, HttpRequest, HttpResponse};
 use libraw::{Processor, ThumbnailFormat};
 
-use image::codecs::jpeg::JpegEncoder;
-use image::codecs::png::PngEncoder;
-use image::ImageEncoder;
+use image::codecs::avif::AvifEncoder;
+use image::codecs::jpeg::{JpegDecoder, JpegEncoder};
+use image::codecs::png::{CompressionType, FilterType as PngFilterType, PngEncoder};
+use image::{ImageDecoder, ImageEncoder, RgbImage};
+use lcms2::Profile;
 
 use fast_image_resize as fr;
 
+use crate::cache::{Rendition, RenditionCache};
+use crate::color::{self, ColorProfile};
 use crate::db::Id;
+use crate::develop::{Demosaic, Development, HighlightMode, Presets, WhiteBalance};
+use crate::formats::{self, Decoder, Format};
+use crate::http_cache;
+use crate::share::Viewer;
 use crate::SQLiteDatabase;
 
+/// The image data which renditions are produced from
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    /// Use the embedded preview when it is at least as large as the requested rendition, and
+    /// process the full RAW otherwise
+    #[default]
+    Auto,
+    /// Always process the full RAW
+    Raw,
+}
+
+/// How the source image is fit to the requested dimensions
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ResizeMode {
+    /// Scale to fit within the given width and/or height, preserving the aspect ratio
+    #[default]
+    Fit,
+    /// Scale to cover the given width and height, preserving the aspect ratio, and crop whatever
+    /// falls outside of them
+    Fill,
+    /// Stretch to exactly the given width and height
+    Exact,
+    /// Scale to the given width or height, preserving the aspect ratio
+    Scale,
+}
+
+/// Which part of the image is kept when cropping in [`ResizeMode::Fill`]
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Gravity {
+    #[default]
+    Center,
+    North,
+    South,
+    East,
+    West,
+    NorthEast,
+    NorthWest,
+    SouthEast,
+    SouthWest,
+}
+
+impl Gravity {
+    /// The point, relative to the width and height of the image, which is kept in view
+    fn focal_point(self) -> (f32, f32) {
+        match self {
+            Gravity::Center => (0.5, 0.5),
+            Gravity::North => (0.5, 0.),
+            Gravity::South => (0.5, 1.),
+            Gravity::East => (1., 0.5),
+            Gravity::West => (0., 0.5),
+            Gravity::NorthEast => (1., 0.),
+            Gravity::NorthWest => (0., 0.),
+            Gravity::SouthEast => (1., 1.),
+            Gravity::SouthWest => (0., 1.),
+        }
+    }
+}
+
+/// The legacy routes stretched images to the requested width and height before there were resize
+/// modes, which they keep as their default
+fn legacy_mode() -> ResizeMode {
+    ResizeMode::Exact
+}
+
 #[derive(Deserialize)]
 pub struct ImageResize {
     id: Id,
-    height: NonZeroU32,
-    width: NonZeroU32,
+    height: Option<NonZeroU32>,
+    width: Option<NonZeroU32>,
+    #[serde(default = "legacy_mode")]
+    mode: ResizeMode,
+    #[serde(default)]
+    gravity: Gravity,
+    /// Horizontal position of the focal point for [`ResizeMode::Fill`], from 0 to 1.
+    /// Takes precedence over `gravity`.
+    focus_x: Option<f32>,
+    /// Vertical position of the focal point for [`ResizeMode::Fill`], from 0 to 1.
+    /// Takes precedence over `gravity`.
+    focus_y: Option<f32>,
+    #[serde(default)]
+    source: Source,
+    #[serde(default)]
+    profile: ColorProfile,
+    /// The version of the image, from the `X-Image-Version` header. Responses to requests which
+    /// give the current version are cached forever.
+    v: Option<String>,
 }
 
 #[derive(Deserialize)]
 pub struct ImageRequestJpg {
     id: Id,
-    height: NonZeroU32,
-    width: NonZeroU32,
+    height: Option<NonZeroU32>,
+    width: Option<NonZeroU32>,
+    #[serde(default = "legacy_mode")]
+    mode: ResizeMode,
+    #[serde(default)]
+    gravity: Gravity,
+    focus_x: Option<f32>,
+    focus_y: Option<f32>,
     quality: u8,
+    #[serde(default)]
+    source: Source,
+    #[serde(default)]
+    profile: ColorProfile,
+    /// The version of the image, from the `X-Image-Version` header. Responses to requests which
+    /// give the current version are cached forever.
+    v: Option<String>,
 }
 
-// TODO: more granular errors, restriction on image dimensions
-// TODO: Use image preview to reduce computation time
-async fn fetch_and_resize<'a>(
-    data: Data<Arc<SQLiteDatabase>>,
-    params: ImageResize,
-) -> Option<fast_image_resize::Image<'a>> {
-    let path = data.get_path(&params.id).await.ok()?;
-    let buf = std::fs::read(path).expect("read in");
-    resize(&buf, params.width, params.height)
+#[derive(Deserialize)]
+pub struct RenditionRequest {
+    height: Option<NonZeroU32>,
+    width: Option<NonZeroU32>,
+    #[serde(default)]
+    mode: ResizeMode,
+    #[serde(default)]
+    gravity: Gravity,
+    focus_x: Option<f32>,
+    focus_y: Option<f32>,
+    #[serde(default)]
+    source: Source,
+    /// The color profile which the rendition is converted into and tagged with
+    #[serde(default)]
+    profile: ColorProfile,
+    /// The version of the image, from the `X-Image-Version` header. Responses to requests which
+    /// give the current version are cached forever.
+    v: Option<String>,
+    /// Named development settings for RAWs, which settings given below take precedence over
+    preset: Option<String>,
+    white_balance: Option<WhiteBalance>,
+    exposure: Option<f32>,
+    highlights: Option<HighlightMode>,
+    demosaic: Option<Demosaic>,
+    sixteen_bit: Option<bool>,
+    /// The output format. If not given, it is negotiated from the `Accept` header.
+    format: Option<RenditionFormat>,
+    /// Quality settings for each output format, from 1 to 100, which only apply if that format
+    /// is chosen
+    jpeg_quality: Option<u8>,
+    webp_quality: Option<u8>,
+    avif_quality: Option<u8>,
+    /// Effort settings for each output format, from 0 (fastest) to 9 (smallest output), which
+    /// only apply if that format is chosen
+    png_effort: Option<u8>,
+    webp_effort: Option<u8>,
+    avif_effort: Option<u8>,
 }
 
-pub fn preview<'b>(buf: &[u8]) -> Option<Vec<u8>> {
-    let processor = Processor::new();
-    match processor.thumbnail(buf) {
-        Ok(thumbnail) if thumbnail.format() == ThumbnailFormat::Jpeg => {
-            Some(thumbnail.deref().to_vec())
-        }
-        thumbnail => {
-            if let Ok(thumbnail) = thumbnail {
-                if thumbnail.format() != ThumbnailFormat::Unknown {
-                    println!(
-                        "Image had unsupported thumbnail format: {:?}",
-                        thumbnail.format()
-                    );
+impl RenditionRequest {
+    /// The development settings, falling back to those of `preset`
+    fn development(&self, preset: Option<&Development>) -> Result<Development, &'static str> {
+        let development = Development {
+            white_balance: self.white_balance,
+            exposure: self.exposure,
+            highlights: self.highlights,
+            demosaic: self.demosaic,
+            sixteen_bit: self.sixteen_bit,
+        };
+        let development = match preset {
+            Some(preset) => development.or(preset),
+            None => development,
+        };
+        development.validate()?;
+        Ok(development)
+    }
+
+    fn encoding(&self, format: RenditionFormat) -> Result<Encoding, &'static str> {
+        let quality = |quality: Option<u8>, default: u8| match quality.unwrap_or(default) {
+            quality @ 1..=100 => Ok(quality),
+            _ => Err("quality must be between 1 and 100"),
+        };
+        let effort = |effort: Option<u8>| match effort.unwrap_or(4) {
+            effort @ 0..=9 => Ok(effort),
+            _ => Err("effort must be between 0 and 9"),
+        };
+
+        Ok(match format {
+            RenditionFormat::Jpeg => Encoding::Jpeg {
+                quality: quality(self.jpeg_quality, 80)?,
+            },
+            RenditionFormat::Png => Encoding::Png {
+                effort: effort(self.png_effort)?,
+            },
+            RenditionFormat::WebP => Encoding::WebP {
+                quality: quality(self.webp_quality, 75)?,
+                effort: effort(self.webp_effort)?,
+            },
+            // AVIF renditions are tagged through CICP rather than an ICC profile
+            RenditionFormat::Avif if self.profile != ColorProfile::Srgb => {
+                return Err("avif renditions can only be produced in srgb");
+            }
+            RenditionFormat::Avif => Encoding::Avif {
+                quality: quality(self.avif_quality, 60)?,
+                effort: effort(self.avif_effort)?,
+            },
+        })
+    }
+}
+
+/// Bounds on renditions, so that one request cannot make the server allocate a huge image
+pub struct RenditionLimits {
+    /// The longest edge of a rendition in pixels
+    pub max_edge: u32,
+}
+
+impl RenditionLimits {
+    /// Reads the limit from `MAX_RENDITION_EDGE`
+    pub fn from_env() -> Self {
+        RenditionLimits {
+            max_edge: std::env::var("MAX_RENDITION_EDGE")
+                .ok()
+                .and_then(|edge| edge.parse().ok())
+                .filter(|edge| *edge > 0)
+                .unwrap_or(8192),
+        }
+    }
+}
+
+/// Requested dimensions of a rendition, and how to get there from the source image
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Geometry {
+    mode: ResizeMode,
+    width: Option<NonZeroU32>,
+    height: Option<NonZeroU32>,
+    focal_point: (f32, f32),
+    /// Renditions which would be larger are scaled down to this longest edge
+    max_edge: u32,
+}
+
+/// The region of the source image which is kept, and the dimensions it is resized to
+struct Plan {
+    crop: Option<fr::CropBox>,
+    width: NonZeroU32,
+    height: NonZeroU32,
+}
+
+impl Geometry {
+    pub(crate) fn fit(width: NonZeroU32, height: NonZeroU32) -> Self {
+        Self {
+            mode: ResizeMode::Fit,
+            width: Some(width),
+            height: Some(height),
+            focal_point: Gravity::Center.focal_point(),
+            max_edge: u32::from(width).max(u32::from(height)),
+        }
+    }
+
+    fn new(
+        mode: ResizeMode,
+        width: Option<NonZeroU32>,
+        height: Option<NonZeroU32>,
+        gravity: Gravity,
+        focus_x: Option<f32>,
+        focus_y: Option<f32>,
+        limits: &RenditionLimits,
+    ) -> Result<Self, &'static str> {
+        match (mode, width, height) {
+            (ResizeMode::Fit, None, None) => {
+                return Err("fit requires a width, a height, or both");
+            }
+            (ResizeMode::Fill | ResizeMode::Exact, None, _)
+            | (ResizeMode::Fill | ResizeMode::Exact, _, None) => {
+                return Err("fill and exact require both a width and a height");
+            }
+            (ResizeMode::Scale, Some(_), Some(_)) | (ResizeMode::Scale, None, None) => {
+                return Err("scale requires exactly one of width or height");
+            }
+            _ => {}
+        }
+
+        let (gravity_x, gravity_y) = gravity.focal_point();
+        let focal_point = (focus_x.unwrap_or(gravity_x), focus_y.unwrap_or(gravity_y));
+        if !(0. ..=1.).contains(&focal_point.0) || !(0. ..=1.).contains(&focal_point.1) {
+            return Err("focus_x and focus_y must be between 0 and 1");
+        }
+
+        Ok(Self {
+            mode,
+            width,
+            height,
+            focal_point,
+            max_edge: limits.max_edge,
+        })
+    }
+
+    /// The longest edge of the box which renditions are fit into, or `None` if one of their
+    /// dimensions is unbounded
+    pub(crate) fn bounding_edge(&self) -> Option<u32> {
+        Some(u32::from(self.width?).max(u32::from(self.height?)))
+    }
+
+    fn plan(&self, src_width: u32, src_height: u32) -> Option<Plan> {
+        if src_width == 0 || src_height == 0 {
+            return None;
+        }
+        let (src_w, src_h) = (src_width as f64, src_height as f64);
+        let scale_x = self.width.map(|width| u32::from(width) as f64 / src_w);
+        let scale_y = self.height.map(|height| u32::from(height) as f64 / src_h);
+        let scaled = |scale: f64| -> Option<(NonZeroU32, NonZeroU32)> {
+            Some((
+                NonZeroU32::new((src_w * scale).round().max(1.) as u32)?,
+                NonZeroU32::new((src_h * scale).round().max(1.) as u32)?,
+            ))
+        };
+
+        let (width, height, crop) = match self.mode {
+            ResizeMode::Exact => (self.width?, self.height?, None),
+            ResizeMode::Fit | ResizeMode::Scale => {
+                let scale = match (scale_x, scale_y) {
+                    (Some(x), Some(y)) => x.min(y),
+                    (Some(scale), None) | (None, Some(scale)) => scale,
+                    (None, None) => return None,
+                };
+                let (width, height) = scaled(scale)?;
+                (width, height, None)
+            }
+            ResizeMode::Fill => {
+                let (width, height) = (self.width?, self.height?);
+                let scale = scale_x?.max(scale_y?);
+                let crop_w = (u32::from(width) as f64 / scale).round().clamp(1., src_w);
+                let crop_h = (u32::from(height) as f64 / scale).round().clamp(1., src_h);
+                let (focus_x, focus_y) = self.focal_point;
+                let left = (focus_x as f64 * src_w - crop_w / 2.).clamp(0., src_w - crop_w);
+                let top = (focus_y as f64 * src_h - crop_h / 2.).clamp(0., src_h - crop_h);
+                let crop = fr::CropBox {
+                    left: left.round() as u32,
+                    top: top.round() as u32,
+                    width: NonZeroU32::new(crop_w as u32)?,
+                    height: NonZeroU32::new(crop_h as u32)?,
+                };
+                (width, height, Some(crop))
+            }
+        };
+
+        // Scale renditions which are too large down, keeping their aspect ratio
+        let excess = u32::from(width).max(u32::from(height)) as f64 / self.max_edge as f64;
+        let (width, height) = if excess > 1. {
+            (
+                NonZeroU32::new((u32::from(width) as f64 / excess).round().max(1.) as u32)?,
+                NonZeroU32::new((u32::from(height) as f64 / excess).round().max(1.) as u32)?,
+            )
+        } else {
+            (width, height)
+        };
+
+        Some(Plan {
+            crop,
+            width,
+            height,
+        })
+    }
+}
+
+/// Formats which renditions can be encoded as
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum RenditionFormat {
+    Jpeg,
+    Png,
+    WebP,
+    Avif,
+}
+
+impl RenditionFormat {
+    /// Every output format, in order of preference when negotiating with the `Accept` header
+    const ALL: [RenditionFormat; 4] = [
+        RenditionFormat::Avif,
+        RenditionFormat::WebP,
+        RenditionFormat::Jpeg,
+        RenditionFormat::Png,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            RenditionFormat::Jpeg => "jpeg",
+            RenditionFormat::Png => "png",
+            RenditionFormat::WebP => "webp",
+            RenditionFormat::Avif => "avif",
+        }
+    }
+
+    fn mime_type(self) -> &'static str {
+        match self {
+            RenditionFormat::Jpeg => "image/jpeg",
+            RenditionFormat::Png => "image/png",
+            RenditionFormat::WebP => "image/webp",
+            RenditionFormat::Avif => "image/avif",
+        }
+    }
+
+    /// Whether the format may be chosen for `*/*` or `image/*`. WebP and AVIF are only served to
+    /// clients which list them explicitly, since older browsers send wildcards for images too.
+    fn matches_wildcard(self) -> bool {
+        matches!(self, RenditionFormat::Jpeg | RenditionFormat::Png)
+    }
+
+    /// Whether renditions in `profile` can be encoded in this format, which AVIF can only for
+    /// sRGB, since it is tagged through CICP rather than an ICC profile
+    fn supports(self, profile: ColorProfile) -> bool {
+        self != RenditionFormat::Avif || profile == ColorProfile::Srgb
+    }
+
+    /// Picks the output format which the client prefers, from the value of its `Accept` header,
+    /// among those which can carry `profile`.
+    fn negotiate(accept: &str, profile: ColorProfile) -> Option<Self> {
+        let ranges: Vec<(&str, f32)> = accept
+            .split(',')
+            .filter_map(|range| {
+                let mut parts = range.split(';');
+                let media_range = parts.next()?.trim();
+                let q = parts
+                    .find_map(|param| param.trim().strip_prefix("q="))
+                    .and_then(|q| q.parse().ok())
+                    .unwrap_or(1.);
+                Some((media_range, q))
+            })
+            .collect();
+
+        let mut best: Option<(RenditionFormat, f32)> = None;
+        for format in Self::ALL
+            .into_iter()
+            .filter(|format| format.supports(profile))
+        {
+            // The most specific matching media range determines the quality value
+            let q = ranges
+                .iter()
+                .filter_map(|&(range, q)| {
+                    let specificity = if range.eq_ignore_ascii_case(format.mime_type()) {
+                        2
+                    } else if format.matches_wildcard() && range.eq_ignore_ascii_case("image/*") {
+                        1
+                    } else if format.matches_wildcard() && range == "*/*" {
+                        0
+                    } else {
+                        return None;
+                    };
+                    Some((specificity, q))
+                })
+                .max_by_key(|&(specificity, _)| specificity)
+                .map(|(_, q)| q);
+
+            if let Some(q) = q {
+                let preferred = match best {
+                    Some((_, best_q)) => q > best_q,
+                    None => true,
+                };
+                if q > 0. && preferred {
+                    best = Some((format, q));
                 }
             }
-            let image = resize(
-                buf,
-                NonZeroU32::try_from(1200).unwrap(),
-                NonZeroU32::try_from(800).unwrap(),
-            )?;
+        }
+        best.map(|(format, _)| format)
+    }
+}
+
+#[derive(Serialize)]
+pub struct OutputFormat {
+    /// The value of the `format` parameter of `/images/{id}/rendition` which selects the format
+    name: &'static str,
+    mime_type: &'static str,
+}
+
+/// Formats which renditions can be encoded as
+pub fn output_formats() -> Vec<OutputFormat> {
+    RenditionFormat::ALL
+        .iter()
+        .map(|format| OutputFormat {
+            name: format.name(),
+            mime_type: format.mime_type(),
+        })
+        .collect()
+}
+
+/// Output encoding of a rendition. `effort` ranges from 0 (fastest) to 9 (smallest output).
+#[derive(Debug)]
+enum Encoding {
+    Jpeg { quality: u8 },
+    Png { effort: u8 },
+    WebP { quality: u8, effort: u8 },
+    Avif { quality: u8, effort: u8 },
+}
+
+impl Encoding {
+    fn content_type(&self) -> &'static str {
+        self.format().mime_type()
+    }
 
-            let mut buf = Vec::new();
-            JpegEncoder::new_with_quality(&mut buf, 70)
-                .write_image(
+    fn format(&self) -> RenditionFormat {
+        match self {
+            Encoding::Jpeg { .. } => RenditionFormat::Jpeg,
+            Encoding::Png { .. } => RenditionFormat::Png,
+            Encoding::WebP { .. } => RenditionFormat::WebP,
+            Encoding::Avif { .. } => RenditionFormat::Avif,
+        }
+    }
+
+    /// Encodes `image`, which is in `profile`, and embeds the profile in the output
+    fn encode(&self, image: &fr::Image, profile: ColorProfile) -> Option<Vec<u8>> {
+        let encoded = self.encode_untagged(image)?;
+        let (width, height) = (u32::from(image.width()), u32::from(image.height()));
+        let icc = profile.icc()?;
+        match self {
+            Encoding::Jpeg { .. } => color::embed_icc_jpeg(&encoded, &icc),
+            Encoding::Png { .. } => color::embed_icc_png(&encoded, &icc),
+            Encoding::WebP { .. } => color::embed_icc_webp(&encoded, width, height, &icc),
+            // ravif tags its output as sRGB
+            Encoding::Avif { .. } => Some(encoded),
+        }
+    }
+
+    fn encode_untagged(&self, image: &fr::Image) -> Option<Vec<u8>> {
+        let mut buf = Vec::new();
+        let (width, height) = (u32::from(image.width()), u32::from(image.height()));
+        match *self {
+            Encoding::Jpeg { quality } => JpegEncoder::new_with_quality(&mut buf, quality)
+                .write_image(image.buffer(), width, height, image::ColorType::Rgb8),
+            Encoding::Png { effort } => {
+                let compression = match effort {
+                    0..=3 => CompressionType::Fast,
+                    4..=6 => CompressionType::Default,
+                    _ => CompressionType::Best,
+                };
+                PngEncoder::new_with_quality(&mut buf, compression, PngFilterType::Adaptive)
+                    .write_image(image.buffer(), width, height, image::ColorType::Rgb8)
+            }
+            Encoding::WebP { quality, effort } => {
+                let mut config = webp::WebPConfig::new().ok()?;
+                config.quality = f32::from(quality);
+                // libwebp's method ranges from 0 (fastest) to 6 (slowest)
+                config.method = i32::from(effort) * 6 / 9;
+                let encoded = webp::Encoder::from_rgb(image.buffer(), width, height)
+                    .encode_advanced(&config)
+                    .ok()?;
+                return Some(encoded.to_vec());
+            }
+            Encoding::Avif { quality, effort } => {
+                // ravif's speed ranges from 1 (slowest) to 10 (fastest)
+                AvifEncoder::new_with_speed_quality(&mut buf, 10 - effort, quality).write_image(
                     image.buffer(),
-                    u32::from(image.width()),
-                    u32::from(image.height()),
+                    width,
+                    height,
                     image::ColorType::Rgb8,
                 )
-                .ok()?;
-            Some(buf)
+            }
         }
+        .ok()?;
+        Some(buf)
     }
 }
 
+/// Everything which determines the contents of a rendition, apart from the source image
+#[derive(Debug)]
+struct RenditionSpec {
+    geometry: Geometry,
+    source: Source,
+    development: Development,
+    profile: ColorProfile,
+    encoding: Encoding,
+}
+
+enum RenditionError {
+    NotFound,
+    /// The share link does not allow renditions of this size
+    SizeNotShared,
+    InvalidParameters(&'static str),
+    Render,
+}
+
+impl RenditionError {
+    fn into_response(self) -> HttpResponse {
+        match self {
+            RenditionError::NotFound => HttpResponse::NotFound().body("image with id not found"),
+            RenditionError::SizeNotShared => {
+                HttpResponse::Forbidden().body("the share link does not allow this size")
+            }
+            RenditionError::InvalidParameters(reason) => HttpResponse::BadRequest().body(reason),
+            RenditionError::Render => {
+                HttpResponse::InternalServerError().body("image could not be rendered")
+            }
+        }
+    }
+}
+
+/// Identifies the current version of the file at `path`, so that renditions of a source which has
+/// since been modified or replaced are never served from the cache.
+fn source_stamp(path: &Path) -> Option<String> {
+    let metadata = std::fs::metadata(path).ok()?;
+    let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some(format!(
+        "{}.{:09}-{}",
+        modified.as_secs(),
+        modified.subsec_nanos(),
+        metadata.len()
+    ))
+}
+
+fn fetch_and_resize<'a>(
+    path: &Path,
+    spec: &RenditionSpec,
+) -> Result<fast_image_resize::Image<'a>, RenditionError> {
+    let buf = std::fs::read(path).map_err(|_| RenditionError::NotFound)?;
+    let format = formats::sniff(&buf, formats::extension(path)).ok_or(RenditionError::Render)?;
+    // The embedded preview is developed by the camera, so it can only stand in for the defaults
+    if spec.source == Source::Auto
+        && format.decoder == Decoder::LibRaw
+        && spec.development.is_default()
+    {
+        if let Some(image) = resize_preview(&buf, &spec.geometry, spec.profile) {
+            return Ok(image);
+        }
+    }
+    resize(
+        &buf,
+        format,
+        &spec.development,
+        &spec.geometry,
+        spec.profile,
+    )
+    .ok_or(RenditionError::Render)
+}
+
+/// Fetches the encoded rendition from the cache, rendering and caching it if it is not present.
+fn fetch_rendition(
+    cache: &RenditionCache,
+    id: &str,
+    path: &Path,
+    spec: &RenditionSpec,
+) -> Result<Rendition, RenditionError> {
+    let key = source_stamp(path).map(|stamp| format!("{}/{}/{:?}", id, stamp, spec));
+
+    if let Some(rendition) = key.as_deref().and_then(|key| cache.get(key)) {
+        return Ok(rendition);
+    }
+
+    let image = fetch_and_resize(path, spec)?;
+    let rendition = Rendition {
+        width: u32::from(image.width()),
+        height: u32::from(image.height()),
+        bytes: spec
+            .encoding
+            .encode(&image, spec.profile)
+            .ok_or(RenditionError::Render)?,
+    };
+
+    if let Some(key) = key {
+        if let Err(e) = cache.insert(&key, &rendition) {
+            log::warn!("Failed to cache rendition of {}: {:?}", id, e);
+        }
+    }
+
+    Ok(rendition)
+}
+
+/// Responds with the rendition of `id` described by `spec`, or with `304 Not Modified` if the
+/// client already has it. `version` is the image version which the client asked for, if any.
+async fn respond_with_rendition(
+    data: Data<Arc<SQLiteDatabase>>,
+    cache: Data<RenditionCache>,
+    req: &HttpRequest,
+    viewer: &Viewer,
+    id: Id,
+    version: Option<&str>,
+    spec: Result<RenditionSpec, &'static str>,
+) -> HttpResponse {
+    let spec = match spec {
+        Ok(spec) => spec,
+        Err(reason) => return RenditionError::InvalidParameters(reason).into_response(),
+    };
+    let file = match viewer.file(&data, &id).await {
+        Ok(file) => file,
+        Err(_) => return RenditionError::NotFound.into_response(),
+    };
+    if !viewer.may_render(&spec.geometry) {
+        return RenditionError::SizeNotShared.into_response();
+    }
+
+    let etag = http_cache::etag(&id, &file.md5, &spec);
+    let cache_control = http_cache::cache_control(version, &file.md5);
+    if http_cache::is_fresh(req, &etag) {
+        return http_cache::insert_validators(
+            &mut HttpResponse::NotModified(),
+            etag,
+            cache_control,
+            &file.md5,
+        )
+        .finish();
+    }
+
+    let content_type = spec.encoding.content_type();
+    let path = file.path.clone();
+    // Decoding, developing RAWs and encoding would hold up the other requests of this worker
+    let rendered = match web::block(move || fetch_rendition(&cache, &id, &path, &spec)).await {
+        Ok(rendered) => rendered,
+        Err(e) => {
+            log::warn!("Rendering failed: {:?}", e);
+            Err(RenditionError::Render)
+        }
+    };
+    match rendered {
+        Ok(rendition) => {
+            let mut response = HttpResponse::Ok();
+            http_cache::insert_validators(&mut response, etag, cache_control, &file.md5)
+                .content_type(content_type)
+                .insert_header(("X-Image-Width", rendition.width.to_string()))
+                .insert_header(("X-Image-Height", rendition.height.to_string()));
+            if let Ok(modified) = std::fs::metadata(&file.path).and_then(|meta| meta.modified()) {
+                response.insert_header(header::LastModified(modified.into()));
+            }
+            response.body(rendition.bytes)
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+pub fn preview(buf: &[u8], format: &Format) -> Option<Vec<u8>> {
+    let geometry = Geometry::fit(
+        NonZeroU32::try_from(1200).unwrap(),
+        NonZeroU32::try_from(800).unwrap(),
+    );
+    let mut image = None;
+    if format.decoder == Decoder::LibRaw {
+        let processor = Processor::new();
+        match processor.thumbnail(buf) {
+            Ok(thumbnail) if thumbnail.format() == ThumbnailFormat::Jpeg => {
+                // Thumbnails which are upright and sRGB can be used as-is
+                let (orientation, profile) = preview_metadata(&thumbnail, buf);
+                if orientation == 1 && profile.is_none() {
+                    return Some(thumbnail.deref().to_vec());
+                }
+                // Others are rotated and converted as renditions are, which is still much
+                // cheaper than processing the RAW
+                image = JpegDecoder::new(Cursor::new(thumbnail.deref()))
+                    .and_then(image::DynamicImage::from_decoder)
+                    .ok()
+                    .and_then(|decoded| {
+                        Decoded {
+                            image: decoded.into_rgb8(),
+                            orientation,
+                            profile,
+                        }
+                        .normalize(ColorProfile::Srgb)
+                    })
+                    .and_then(|image| {
+                        let plan = geometry.plan(image.width(), image.height())?;
+                        resize_rgb(image.width(), image.height(), image.into_raw(), plan)
+                    });
+            }
+            Ok(thumbnail) if thumbnail.format() != ThumbnailFormat::Unknown => {
+                println!(
+                    "Image had unsupported thumbnail format: {:?}",
+                    thumbnail.format()
+                );
+            }
+            _ => {}
+        }
+    }
+
+    // RAWs without a usable thumbnail are processed in full
+    let image = match image {
+        Some(image) => image,
+        None => resize(
+            buf,
+            format,
+            &Development::default(),
+            &geometry,
+            ColorProfile::Srgb,
+        )?,
+    };
+
+    let mut buf = Vec::new();
+    JpegEncoder::new_with_quality(&mut buf, 70)
+        .write_image(
+            image.buffer(),
+            u32::from(image.width()),
+            u32::from(image.height()),
+            image::ColorType::Rgb8,
+        )
+        .ok()?;
+    Some(buf)
+}
+
+/// A decoded image, along with the metadata needed to display it correctly
+struct Decoded {
+    image: RgbImage,
+    /// EXIF orientation, which has not been applied to `image` yet
+    orientation: u32,
+    /// Color profile of `image`, or `None` if it is sRGB
+    profile: Option<Profile>,
+}
+
+impl Decoded {
+    /// Rotates the image upright and converts it into `profile`
+    fn normalize(self, profile: ColorProfile) -> Option<RgbImage> {
+        let mut image = color::orient(self.image, self.orientation);
+        color::convert(&mut image, self.profile.as_ref(), profile)?;
+        Some(image)
+    }
+}
+
+/// Decodes the image in `buf` to 8-bit RGB, developing RAWs with `development`
+fn decode(buf: &[u8], format: &Format, development: &Development) -> Option<Decoded> {
+    match format.decoder {
+        // libraw rotates its output and converts it to sRGB
+        Decoder::LibRaw => Some(Decoded {
+            image: development.process(buf)?,
+            orientation: 1,
+            profile: None,
+        }),
+        Decoder::Raster(image_format) => {
+            let image = image::load_from_memory_with_format(buf, image_format)
+                .ok()?
+                .into_rgb8();
+            let exif = color::read_exif(buf);
+            Some(Decoded {
+                image,
+                orientation: exif.as_ref().and_then(color::orientation).unwrap_or(1),
+                profile: color::source_profile(buf, exif.as_ref()),
+            })
+        }
+    }
+}
+
+/// Reads the orientation and color profile of the embedded preview of the RAW in `raw`. Previews
+/// often carry no EXIF of their own, in which case the RAW's is used.
+fn preview_metadata(thumbnail: &[u8], raw: &[u8]) -> (u32, Option<Profile>) {
+    let exif = color::read_exif(thumbnail).or_else(|| color::read_exif(raw));
+    (
+        exif.as_ref().and_then(color::orientation).unwrap_or(1),
+        color::source_profile(thumbnail, exif.as_ref()),
+    )
+}
+
 pub fn resize<'a>(
     buf: &[u8],
-    width: NonZeroU32,
-    height: NonZeroU32,
+    format: &Format,
+    development: &Development,
+    geometry: &Geometry,
+    profile: ColorProfile,
+) -> Option<fast_image_resize::Image<'a>> {
+    let image = decode(buf, format, development)?.normalize(profile)?;
+    let plan = geometry.plan(image.width(), image.height())?;
+    resize_rgb(image.width(), image.height(), image.into_raw(), plan)
+}
+
+/// Resizes the embedded JPEG preview of the RAW in `buf`, if there is one and it has enough pixels
+/// for the requested rendition, since decoding it is much cheaper than processing the full RAW.
+fn resize_preview<'a>(
+    buf: &[u8],
+    geometry: &Geometry,
+    profile: ColorProfile,
 ) -> Option<fast_image_resize::Image<'a>> {
     let processor = Processor::new();
-    let decoded = processor.process_8bit(buf).ok()?;
+    let thumbnail = processor.thumbnail(buf).ok()?;
+    if thumbnail.format() != ThumbnailFormat::Jpeg {
+        return None;
+    }
+
+    // Check the dimensions from the header before committing to a full decode
+    let decoder = JpegDecoder::new(Cursor::new(thumbnail.deref())).ok()?;
+    let (orientation, source_profile) = preview_metadata(&thumbnail, buf);
+    let (preview_width, preview_height) = match decoder.dimensions() {
+        (width, height) if color::swaps_dimensions(orientation) => (height, width),
+        dimensions => dimensions,
+    };
+    let plan = geometry.plan(preview_width, preview_height)?;
+    let (region_width, region_height) = match &plan.crop {
+        Some(crop) => (u32::from(crop.width), u32::from(crop.height)),
+        None => (preview_width, preview_height),
+    };
+    if region_width < u32::from(plan.width) || region_height < u32::from(plan.height) {
+        return None;
+    }
+
+    let decoded = Decoded {
+        image: image::DynamicImage::from_decoder(decoder).ok()?.into_rgb8(),
+        orientation,
+        profile: source_profile,
+    };
+    let image = decoded.normalize(profile)?;
+    resize_rgb(image.width(), image.height(), image.into_raw(), plan)
+}
 
+fn resize_rgb<'a>(
+    src_width: u32,
+    src_height: u32,
+    pixels: Vec<u8>,
+    plan: Plan,
+) -> Option<fast_image_resize::Image<'a>> {
     let src_image = fr::Image::from_vec_u8(
-        NonZeroU32::try_from(decoded.width()).expect("zero width"),
-        NonZeroU32::try_from(decoded.height()).expect("zero height"),
-        decoded.deref().to_vec(),
+        NonZeroU32::try_from(src_width).ok()?,
+        NonZeroU32::try_from(src_height).ok()?,
+        pixels,
         fr::PixelType::U8x3,
     )
     .ok()?;
 
-    let mut dst_image = fast_image_resize::Image::new(width, height, src_image.pixel_type());
+    let mut src_view = src_image.view();
+    if let Some(crop) = plan.crop {
+        src_view.set_crop_box(crop).ok()?;
+    }
+
+    let mut dst_image =
+        fast_image_resize::Image::new(plan.width, plan.height, src_image.pixel_type());
 
     // Get mutable view of destination image data
     let mut dst_view = dst_image.view_mut();
@@ -102,58 +942,166 @@ pub fn resize<'a>(
     // Create Resizer instance and resize source image
     // into buffer of destination image
     let mut resizer = fr::Resizer::new(fr::ResizeAlg::Convolution(fr::FilterType::CatmullRom));
-    resizer.resize(&src_image.view(), &mut dst_view).ok()?;
+    resizer.resize(&src_view, &mut dst_view).ok()?;
     Some(dst_image)
 }
 
 pub async fn fetch_png(
     data: Data<Arc<SQLiteDatabase>>,
+    cache: Data<RenditionCache>,
+    limits: Data<RenditionLimits>,
+    viewer: Viewer,
     params: web::Query<ImageResize>,
+    req: HttpRequest,
 ) -> HttpResponse {
-    match fetch_and_resize(data, params.into_inner()).await {
-        None => HttpResponse::NotFound().body("image with id not found"),
-        Some(resized_im) => {
-            let mut buf = Vec::new();
-            PngEncoder::new(&mut buf)
-                .write_image(
-                    resized_im.buffer(),
-                    u32::from(resized_im.width()),
-                    u32::from(resized_im.height()),
-                    image::ColorType::Rgb8,
-                )
-                .unwrap();
-            HttpResponse::Ok().content_type("image/png").body(buf)
-        }
-    }
+    let params = params.into_inner();
+    let geometry = Geometry::new(
+        params.mode,
+        params.width,
+        params.height,
+        params.gravity,
+        params.focus_x,
+        params.focus_y,
+        &limits,
+    );
+    let spec = geometry.map(|geometry| RenditionSpec {
+        geometry,
+        source: params.source,
+        development: Development::default(),
+        profile: params.profile,
+        encoding: Encoding::Png { effort: 4 },
+    });
+    respond_with_rendition(
+        data,
+        cache,
+        &req,
+        &viewer,
+        params.id,
+        params.v.as_deref(),
+        spec,
+    )
+    .await
 }
 
 pub async fn fetch_jpg(
     data: Data<Arc<SQLiteDatabase>>,
+    cache: Data<RenditionCache>,
+    limits: Data<RenditionLimits>,
+    viewer: Viewer,
     params: web::Query<ImageRequestJpg>,
+    req: HttpRequest,
 ) -> HttpResponse {
     let params = params.into_inner();
-    match fetch_and_resize(
+    let geometry = Geometry::new(
+        params.mode,
+        params.width,
+        params.height,
+        params.gravity,
+        params.focus_x,
+        params.focus_y,
+        &limits,
+    );
+    let spec = geometry.and_then(|geometry| match params.quality {
+        quality @ 1..=100 => Ok(RenditionSpec {
+            geometry,
+            source: params.source,
+            development: Development::default(),
+            profile: params.profile,
+            encoding: Encoding::Jpeg { quality },
+        }),
+        _ => Err("quality must be between 1 and 100"),
+    });
+    respond_with_rendition(
         data,
-        ImageResize {
-            id: params.id,
-            width: params.width,
-            height: params.height,
-        },
+        cache,
+        &req,
+        &viewer,
+        params.id,
+        params.v.as_deref(),
+        spec,
     )
     .await
-    {
-        None => HttpResponse::NotFound().body("image with id not found"),
-        Some(resized_im) => {
-            let mut buf = Vec::new();
-            JpegEncoder::new_with_quality(&mut buf, params.quality)
-                .write_image(
-                    resized_im.buffer(),
-                    u32::from(resized_im.width()),
-                    u32::from(resized_im.height()),
-                    image::ColorType::Rgb8,
-                )
-                .unwrap();
-            HttpResponse::Ok().content_type("image/jpeg").body(buf)
-        }
+}
+
+pub async fn fetch_image_rendition(
+    data: Data<Arc<SQLiteDatabase>>,
+    cache: Data<RenditionCache>,
+    limits: Data<RenditionLimits>,
+    presets: Data<Presets>,
+    viewer: Viewer,
+    id: web::Path<Id>,
+    params: web::Query<RenditionRequest>,
+    req: HttpRequest,
+) -> HttpResponse {
+    let params = params.into_inner();
+    let accept = req
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok());
+    let format = match (params.format, accept) {
+        (Some(format), _) => format,
+        (None, Some(accept)) => match RenditionFormat::negotiate(accept, params.profile) {
+            Some(format) => format,
+            None => {
+                return HttpResponse::NotAcceptable()
+                    .body("none of the accepted types can be produced")
+            }
+        },
+        (None, None) => RenditionFormat::Jpeg,
+    };
+    let encoding = match params.encoding(format) {
+        Ok(encoding) => encoding,
+        Err(reason) => return RenditionError::InvalidParameters(reason).into_response(),
+    };
+
+    let preset = match &params.preset {
+        Some(name) => match presets.get(&data, name).await {
+            Ok(Some(preset)) => Some(preset),
+            Ok(None) => {
+                return RenditionError::InvalidParameters("unknown preset").into_response();
+            }
+            Err(e) => {
+                log::warn!("{:?}", e);
+                return HttpResponse::InternalServerError().body("");
+            }
+        },
+        None => None,
+    };
+    let development = match params.development(preset.as_ref()) {
+        Ok(development) => development,
+        Err(reason) => return RenditionError::InvalidParameters(reason).into_response(),
+    };
+
+    let geometry = Geometry::new(
+        params.mode,
+        params.width,
+        params.height,
+        params.gravity,
+        params.focus_x,
+        params.focus_y,
+        &limits,
+    );
+    let spec = geometry.map(|geometry| RenditionSpec {
+        geometry,
+        source: params.source,
+        development,
+        profile: params.profile,
+        encoding,
+    });
+    let mut response = respond_with_rendition(
+        data,
+        cache,
+        &req,
+        &viewer,
+        id.into_inner(),
+        params.v.as_deref(),
+        spec,
+    )
+    .await;
+    if params.format.is_none() {
+        response
+            .headers_mut()
+            .insert(header::VARY, header::HeaderValue::from_static("Accept"));
     }
+    response
 }
@@ -3,9 +3,25 @@
 #![deny(unused_attributes)]
 #![deny(unused_mut)]
 
+mod albums;
+mod auth;
+mod cache;
+mod classifiers;
+mod color;
+mod compose;
 mod db;
+mod develop;
+mod duplicates;
+mod feedback;
+mod formats;
 mod fs;
+mod http_cache;
 mod images;
+mod share;
+mod tags;
+mod tus;
+mod visibility;
+mod vocabulary;
 mod weaviate_graphql;
 
 use actix_cors::Cors;
@@ -14,13 +30,42 @@ use std::ffi::OsString;
 use std::path::PathBuf;
 use std::sync::Arc;
 
-use crate::db::{fetch_raw, near_text, upload_raw, SQLiteDatabase};
-use crate::images::{fetch_jpg, fetch_png};
+use crate::albums::{
+    add_album_images, create_album, delete_album, get_album, list_albums, order_album_images,
+    remove_album_image, update_album, ALBUMS_PROPERTY,
+};
+use crate::auth::{
+    admin_key_from_env, bootstrap_admin, create_user, issue_key, list_users, revoke_key, set_group,
+    set_quota, Authentication,
+};
+use crate::cache::{cache_stats, RenditionCache};
+use crate::classifiers::{
+    classifier_search, delete_classifier, list_classifiers, train_classifier,
+};
+use crate::compose::near_composed;
+use crate::db::files::UploadLimits;
+use crate::db::{
+    fetch_raw, near_text, retry_search_properties, upload_raw, verify, SQLiteDatabase,
+};
+use crate::develop::{delete_raw_preset, raw_presets, save_raw_preset, Presets};
+use crate::duplicates::duplicates;
+use crate::feedback::{near_text_feedback, FeedbackSessions};
+use crate::formats::supported_ext;
+use crate::images::{fetch_image_rendition, fetch_jpg, fetch_png, RenditionLimits};
+use crate::share::{create_album_share_link, create_share_link, ShareLinks};
+use crate::tags::{browse, edit_tags, image_tags, list_tags, TAGS_PROPERTY};
+use crate::tus::{
+    expire_uploads, tus_append, tus_create, tus_offset, tus_options, tus_terminate, TusUploads,
+};
+use crate::visibility::{set_visibility, GROUP_PROPERTY, OWNER_PROPERTY, VISIBILITY_PROPERTY};
+use crate::vocabulary::{rescore, VocabularyFiles};
 use crate::weaviate_graphql::{MultiOperator, Operator, WeaviateWhere, WhereValue};
+use actix_web::http::Method;
 use actix_web::middleware::Logger;
 use actix_web::{get, web, App, HttpResponse, HttpServer};
 
-// TODO: GET /supported_ext: get supported file formats
+/// Where the front end is served from
+const STATIC_DIR: &str = "/static";
 
 #[get("/health")]
 pub async fn health() -> HttpResponse {
@@ -47,16 +92,21 @@ async fn mount_images(
         let after = fs::FileSystem::deep_scan(&mount_dir).unwrap();
 
         let diff = before.diff(&after, PathBuf::from(&mount_dir).parent().unwrap());
+        let added: Vec<_> = diff
+            .added
+            .into_iter()
+            .filter(|path| formats::is_supported_path(path))
+            .collect();
 
         database.remove_paths(&diff.removed).await.unwrap();
 
-        for chunk in diff.added.chunks(100) {
+        for chunk in added.chunks(100) {
             database.add_paths(chunk).await.unwrap();
         }
         println!(
             "Removed {} images, added {} images.",
             diff.removed.len(),
-            diff.added.len()
+            added.len()
         );
 
         let _ = std::fs::create_dir_all(&fs_fingerprint_path.parent().unwrap());
@@ -89,6 +139,10 @@ async fn main() -> std::io::Result<()> {
     let data_dir = std::env::var_os("DATA_DIR").expect("Missing DATA_DIR");
     let mount_dir = std::env::var_os("MOUNTED_IMAGE_DIR").unwrap_or_else(|| OsString::from(""));
     let upload_dir = std::env::var_os("UPLOAD_DIR").expect("Missing UPLOAD_DIR");
+    let rendition_cache_size_mb = std::env::var("RENDITION_CACHE_SIZE_MB")
+        .ok()
+        .and_then(|size| size.parse::<u64>().ok())
+        .unwrap_or(1024);
 
     env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
 
@@ -96,6 +150,12 @@ async fn main() -> std::io::Result<()> {
         let _ = std::fs::create_dir_all(dir);
     }
 
+    let tus_uploads = web::Data::new(
+        TusUploads::open(PathBuf::from(&upload_dir).join("tus"))
+            .expect("Opening resumable upload directory failed"),
+    );
+    actix_web::rt::spawn(expire_uploads(tus_uploads.clone()));
+
     let data = web::Data::new(Arc::new(
         SQLiteDatabase::open(db_url, upload_dir.into())
             .await
@@ -104,6 +164,32 @@ async fn main() -> std::io::Result<()> {
 
     println!("Database opened.");
 
+    if let Some(admin_key) = admin_key_from_env() {
+        bootstrap_admin(&data, &admin_key)
+            .await
+            .expect("Registering ADMIN_API_KEY failed");
+    }
+
+    let rendition_cache = web::Data::new(
+        RenditionCache::open(
+            PathBuf::from(&data_dir).join("renditions"),
+            rendition_cache_size_mb * 1024 * 1024,
+        )
+        .expect("Opening rendition cache failed"),
+    );
+
+    let upload_limits = web::Data::new(UploadLimits::from_env());
+    let rendition_limits = web::Data::new(RenditionLimits::from_env());
+    let share_links = web::Data::new(ShareLinks::from_env());
+    let feedback_sessions = web::Data::new(FeedbackSessions::default());
+
+    let vocabulary_files = web::Data::new(VocabularyFiles::new(&PathBuf::from(&data_dir)));
+
+    let presets = web::Data::new(
+        Presets::load(&PathBuf::from(&data_dir).join("raw_presets.ron"))
+            .expect("Parsing RAW presets failed"),
+    );
+
     wait_until_weaviate_ready().await;
 
     {
@@ -125,7 +211,49 @@ async fn main() -> std::io::Result<()> {
             .await;
 
         log::info!("{:?}", resp);
+
+        // Classes which were created before images had owners, albums or tags lack these
+        for (property, data_type) in [
+            (OWNER_PROPERTY, "string"),
+            (GROUP_PROPERTY, "string"),
+            (VISIBILITY_PROPERTY, "string"),
+            (ALBUMS_PROPERTY, "string[]"),
+            (TAGS_PROPERTY, "string[]"),
+        ] {
+            let resp = reqwest::Client::new()
+                .post("http://weaviate:8080/v1/schema/ClipImage/properties")
+                .json(&serde_json::json!({ "name": property, "dataType": [data_type] }))
+                .send()
+                .await
+                .map(|resp| resp.status());
+            log::info!("{:?}", resp);
+        }
     }
+    actix_web::rt::spawn(retry_search_properties(data.get_ref().clone()));
+
+    match vocabulary::load(&data, &vocabulary_files).await {
+        Ok(label_vectors) => data.set_label_vectors(label_vectors),
+        Err(e) => log::warn!(
+            "Loading the vocabulary failed, images are not auto-tagged: {:?}",
+            e
+        ),
+    }
+    // Files stored before they were hashed are hashed, and images which are missing from the
+    // search index are added to it, without holding up startup
+    let db = data.get_ref().clone();
+    actix_web::rt::spawn(async move {
+        match db.backfill_content_hashes().await {
+            Ok(0) => {}
+            Ok(count) => log::info!("Recorded BLAKE3 hashes of {} files", count),
+            Err(e) => log::warn!("Hashing stored files failed: {:?}", e),
+        }
+        match db.reindex().await {
+            Ok(0) => {}
+            Ok(count) => log::info!("Added {} images to the search index", count),
+            Err(e) => log::warn!("Re-indexing images failed: {:?}", e),
+        }
+    });
+
     // tokio::spawn(mount_images(
     //     data.deref().deref().clone(),
     //     mount_dir.clone(),
@@ -136,37 +264,233 @@ async fn main() -> std::io::Result<()> {
 
     HttpServer::new(move || {
         App::new()
-            .wrap(Cors::permissive().expose_headers(["Content-Disposition"]))
+            .wrap(Authentication::new(
+                data.get_ref().clone(),
+                share_links.clone(),
+                STATIC_DIR,
+            ))
+            .wrap(Cors::permissive().expose_headers([
+                "Content-Disposition",
+                "X-Image-Width",
+                "X-Image-Height",
+                "X-Image-Version",
+                "Location",
+                "Upload-Offset",
+                "Upload-Length",
+                "Upload-Expires",
+                "Tus-Resumable",
+                "Tus-Version",
+                "Tus-Extension",
+                "Tus-Max-Size",
+                "Tus-Checksum-Algorithm",
+            ]))
             .wrap(Logger::default())
             .service(health)
+            .service(web::resource("/supported_ext").route(web::get().to(supported_ext)))
             .service(
                 web::resource("/near_text")
                     .app_data(data.clone())
                     .route(web::get().to(near_text)),
             )
+            .service(
+                web::resource("/near_text/feedback")
+                    .app_data(data.clone())
+                    .app_data(feedback_sessions.clone())
+                    .route(web::post().to(near_text_feedback)),
+            )
+            .service(
+                web::resource("/near_text/compose")
+                    .app_data(data.clone())
+                    .route(web::post().to(near_composed)),
+            )
             .service(
                 web::resource("/upload_raw")
                     .app_data(data.clone())
+                    .app_data(upload_limits.clone())
                     .route(web::post().to(upload_raw)),
             )
+            .service(
+                web::resource("/uploads")
+                    .app_data(tus_uploads.clone())
+                    .app_data(upload_limits.clone())
+                    .route(web::method(Method::OPTIONS).to(tus_options))
+                    .route(web::post().to(tus_create)),
+            )
+            .service(
+                web::resource("/uploads/{id}")
+                    .app_data(data.clone())
+                    .app_data(tus_uploads.clone())
+                    .route(web::head().to(tus_offset))
+                    .route(web::patch().to(tus_append))
+                    .route(web::delete().to(tus_terminate)),
+            )
+            .service(
+                web::resource("/images")
+                    .app_data(data.clone())
+                    .route(web::get().to(browse)),
+            )
+            .service(
+                web::resource("/images/tags")
+                    .app_data(data.clone())
+                    .route(web::patch().to(edit_tags)),
+            )
+            .service(
+                web::resource("/images/{id}/tags")
+                    .app_data(data.clone())
+                    .route(web::get().to(image_tags)),
+            )
+            .service(
+                web::resource("/tags")
+                    .app_data(data.clone())
+                    .route(web::get().to(list_tags)),
+            )
+            .service(
+                web::resource("/classifiers")
+                    .app_data(data.clone())
+                    .route(web::get().to(list_classifiers))
+                    .route(web::post().to(train_classifier)),
+            )
+            .service(
+                web::resource("/classifiers/{id}")
+                    .app_data(data.clone())
+                    .route(web::delete().to(delete_classifier)),
+            )
+            .service(
+                web::resource("/classifiers/{id}/search")
+                    .app_data(data.clone())
+                    .route(web::get().to(classifier_search)),
+            )
             .service(
                 web::resource("/fetch_jpg")
                     .app_data(data.clone())
+                    .app_data(rendition_cache.clone())
+                    .app_data(rendition_limits.clone())
                     .route(web::get().to(fetch_jpg)),
             )
             .service(
                 web::resource("/fetch_png")
                     .app_data(data.clone())
+                    .app_data(rendition_cache.clone())
+                    .app_data(rendition_limits.clone())
                     .route(web::get().to(fetch_png)),
             )
+            .service(
+                web::resource("/images/{id}/rendition")
+                    .app_data(data.clone())
+                    .app_data(rendition_cache.clone())
+                    .app_data(rendition_limits.clone())
+                    .app_data(presets.clone())
+                    .route(web::get().to(fetch_image_rendition)),
+            )
+            .service(
+                web::resource("/raw_presets")
+                    .app_data(data.clone())
+                    .app_data(presets.clone())
+                    .route(web::get().to(raw_presets)),
+            )
+            .service(
+                web::resource("/raw_presets/{name}")
+                    .app_data(data.clone())
+                    .app_data(presets.clone())
+                    .route(web::put().to(save_raw_preset))
+                    .route(web::delete().to(delete_raw_preset)),
+            )
+            .service(
+                web::resource("/rendition_cache")
+                    .app_data(rendition_cache.clone())
+                    .route(web::get().to(cache_stats)),
+            )
+            .service(
+                web::resource("/images/{id}/verify")
+                    .app_data(data.clone())
+                    .route(web::post().to(verify)),
+            )
+            .service(
+                web::resource("/images/{id}/visibility")
+                    .app_data(data.clone())
+                    .route(web::put().to(set_visibility)),
+            )
+            .service(
+                web::resource("/images/{id}/share")
+                    .app_data(data.clone())
+                    .app_data(share_links.clone())
+                    .route(web::post().to(create_share_link)),
+            )
+            .service(
+                web::resource("/albums")
+                    .app_data(data.clone())
+                    .route(web::get().to(list_albums))
+                    .route(web::post().to(create_album)),
+            )
+            .service(
+                web::resource("/albums/{id}")
+                    .app_data(data.clone())
+                    .route(web::get().to(get_album))
+                    .route(web::patch().to(update_album))
+                    .route(web::delete().to(delete_album)),
+            )
+            .service(
+                web::resource("/albums/{id}/images")
+                    .app_data(data.clone())
+                    .route(web::post().to(add_album_images))
+                    .route(web::put().to(order_album_images)),
+            )
+            .service(
+                web::resource("/albums/{id}/images/{image}")
+                    .app_data(data.clone())
+                    .route(web::delete().to(remove_album_image)),
+            )
+            .service(
+                web::resource("/albums/{id}/share")
+                    .app_data(data.clone())
+                    .app_data(share_links.clone())
+                    .route(web::post().to(create_album_share_link)),
+            )
+            .service(
+                web::resource("/duplicates")
+                    .app_data(data.clone())
+                    .route(web::get().to(duplicates)),
+            )
             .service(
                 web::resource("/fetch_raw")
                     .app_data(data.clone())
                     .route(web::get().to(fetch_raw)),
             )
             .service(
-                actix_files::Files::new("/", "/static")
-                    .index_file("/static/index.html")
+                web::resource("/admin/users")
+                    .app_data(data.clone())
+                    .route(web::get().to(list_users))
+                    .route(web::post().to(create_user)),
+            )
+            .service(
+                web::resource("/admin/users/{name}")
+                    .app_data(data.clone())
+                    .route(web::put().to(set_quota)),
+            )
+            .service(
+                web::resource("/admin/users/{name}/group")
+                    .app_data(data.clone())
+                    .route(web::put().to(set_group)),
+            )
+            .service(
+                web::resource("/admin/users/{name}/keys")
+                    .app_data(data.clone())
+                    .route(web::post().to(issue_key)),
+            )
+            .service(
+                web::resource("/admin/vocabulary")
+                    .app_data(data.clone())
+                    .app_data(vocabulary_files.clone())
+                    .route(web::post().to(rescore)),
+            )
+            .service(
+                web::resource("/admin/keys/{id}")
+                    .app_data(data.clone())
+                    .route(web::delete().to(revoke_key)),
+            )
+            .service(
+                actix_files::Files::new("/", STATIC_DIR)
+                    .index_file(format!("{}/index.html", STATIC_DIR))
                     .show_files_listing(),
             )
     })
@@ -0,0 +1,286 @@
+use std::sync::Arc;
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use actix_web::dev::Payload;
+use actix_web::error::ErrorUnauthorized;
+use actix_web::web::{self, Data, Json};
+use actix_web::{FromRequest, HttpMessage, HttpRequest, HttpResponse};
+use futures::future::{ready, Ready};
+
+use crate::auth::User;
+use crate::db::{unix_time, Id, StoredFile};
+use crate::images::Geometry;
+use crate::SQLiteDatabase;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// How long share links last if the request does not say
+const DEFAULT_EXPIRY_SECS: i64 = 7 * 24 * 60 * 60;
+/// How long share links may last at most
+const MAX_EXPIRY_SECS: i64 = 365 * 24 * 60 * 60;
+
+/// What a share link is for
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Shared {
+    Image(Id),
+    /// The images of the album which its owner may share
+    Album(Id),
+}
+
+/// What a share link lets its holder see. Share links are not stored, so they can not be revoked
+/// one by one: they stop working when they expire, when `SHARE_LINK_SECRET` changes, or for the
+/// images which their signer may no longer share.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ShareGrant {
+    #[serde(flatten)]
+    pub(crate) shared: Shared,
+    /// The user who created the link, whose access is checked again whenever it is used
+    pub(crate) signer: String,
+    /// The sizes, as the longest edge of the requested box, which renditions may be requested
+    /// at. Renditions of any size may be requested if there are none.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) sizes: Vec<u32>,
+    /// Whether the original file may be downloaded
+    #[serde(default)]
+    pub(crate) original: bool,
+    /// Unix time after which the link stops working
+    pub(crate) expires: i64,
+}
+
+/// Signs and checks share links with `SHARE_LINK_SECRET`. Without it, share links can not be
+/// created, and none are accepted.
+pub struct ShareLinks {
+    secret: Option<Vec<u8>>,
+}
+
+impl ShareLinks {
+    pub(crate) fn from_env() -> Self {
+        let secret = std::env::var("SHARE_LINK_SECRET")
+            .ok()
+            .filter(|secret| !secret.is_empty())
+            .map(String::into_bytes);
+        if secret.is_none() {
+            log::info!("SHARE_LINK_SECRET is not set, share links are disabled");
+        }
+        ShareLinks { secret }
+    }
+
+    fn mac(&self, payload: &[u8]) -> Option<HmacSha256> {
+        let mut mac = HmacSha256::new_from_slice(self.secret.as_ref()?).ok()?;
+        mac.update(payload);
+        Some(mac)
+    }
+
+    /// The token of a link granting `grant`, which is the grant and its signature, both in
+    /// URL-safe base64
+    fn sign(&self, grant: &ShareGrant) -> Option<String> {
+        let payload =
+            base64::encode_config(serde_json::to_vec(grant).ok()?, base64::URL_SAFE_NO_PAD);
+        let signature = self.mac(payload.as_bytes())?.finalize().into_bytes();
+        Some(format!(
+            "{}.{}",
+            payload,
+            base64::encode_config(signature, base64::URL_SAFE_NO_PAD)
+        ))
+    }
+
+    /// The grant of `token`, if it was signed with our secret and has not expired
+    pub(crate) fn verify(&self, token: &str) -> Option<ShareGrant> {
+        let (payload, signature) = token.split_once('.')?;
+        let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD).ok()?;
+        self.mac(payload.as_bytes())?
+            .verify_slice(&signature)
+            .ok()?;
+        let grant: ShareGrant =
+            serde_json::from_slice(&base64::decode_config(payload, base64::URL_SAFE_NO_PAD).ok()?)
+                .ok()?;
+        if grant.expires > unix_time() {
+            Some(grant)
+        } else {
+            None
+        }
+    }
+}
+
+/// Whoever is looking at an image: a user with an API key, or the holder of a share link.
+/// Handlers which serve images take this instead of `User`, so that share links work for them
+/// and nowhere else.
+pub enum Viewer {
+    User(User),
+    Share(ShareGrant),
+}
+
+impl Viewer {
+    /// The file of `id`, if the viewer may see it. Share links only show images which their
+    /// signer may still share.
+    pub(crate) async fn file(&self, db: &SQLiteDatabase, id: &str) -> sqlx::Result<StoredFile> {
+        match self {
+            Viewer::User(user) => db.visible_file(id, user).await,
+            Viewer::Share(grant) => {
+                let signer = db
+                    .user(&grant.signer)
+                    .await?
+                    .ok_or(sqlx::Error::RowNotFound)?;
+                match &grant.shared {
+                    Shared::Image(image) if image == id && db.may_share(id, &signer).await? => {
+                        db.get_file(id).await
+                    }
+                    Shared::Image(_) => Err(sqlx::Error::RowNotFound),
+                    Shared::Album(album) => db.album_file(album, &signer.name, id).await,
+                }
+            }
+        }
+    }
+
+    /// Whether the viewer may download the original file
+    pub(crate) fn may_download_original(&self) -> bool {
+        match self {
+            Viewer::User(_) => true,
+            Viewer::Share(grant) => grant.original,
+        }
+    }
+
+    /// Whether the viewer may request renditions with `geometry`
+    pub(crate) fn may_render(&self, geometry: &Geometry) -> bool {
+        match self {
+            Viewer::Share(grant) if !grant.sizes.is_empty() => geometry
+                .bounding_edge()
+                .map_or(false, |edge| grant.sizes.contains(&edge)),
+            _ => true,
+        }
+    }
+}
+
+impl FromRequest for Viewer {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let extensions = req.extensions();
+        ready(if let Some(user) = extensions.get::<User>() {
+            Ok(Viewer::User(user.clone()))
+        } else if let Some(grant) = extensions.get::<ShareGrant>() {
+            Ok(Viewer::Share(grant.clone()))
+        } else {
+            Err(ErrorUnauthorized("missing API key or share link"))
+        })
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ShareRequest {
+    /// Seconds until the link expires
+    expires_in: Option<i64>,
+    #[serde(default)]
+    sizes: Vec<u32>,
+    #[serde(default)]
+    original: bool,
+}
+
+#[derive(Serialize)]
+pub struct ShareLink {
+    /// The `share` parameter of the shared URLs
+    token: String,
+    expires: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rendition_url: Option<String>,
+    /// Only given if the original file may be downloaded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    original_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    album_url: Option<String>,
+}
+
+/// Signs a link to `shared` for `signer` as described by `request`
+fn share(
+    share_links: &ShareLinks,
+    signer: &User,
+    shared: Shared,
+    request: ShareRequest,
+) -> HttpResponse {
+    let expires_in = request.expires_in.unwrap_or(DEFAULT_EXPIRY_SECS);
+    if !(1..=MAX_EXPIRY_SECS).contains(&expires_in) {
+        return HttpResponse::BadRequest().body(format!(
+            "expires_in must be between 1 and {} seconds",
+            MAX_EXPIRY_SECS
+        ));
+    }
+    if request.sizes.contains(&0) {
+        return HttpResponse::BadRequest().body("sizes must not be 0");
+    }
+
+    let grant = ShareGrant {
+        shared,
+        signer: signer.name.clone(),
+        sizes: request.sizes,
+        original: request.original,
+        expires: unix_time() + expires_in,
+    };
+    let token = match share_links.sign(&grant) {
+        Some(token) => token,
+        None => return HttpResponse::ServiceUnavailable().body("share links are disabled"),
+    };
+    let (rendition_url, original_url, album_url) = match &grant.shared {
+        Shared::Image(id) => (
+            Some(format!("/images/{}/rendition?share={}", id, token)),
+            grant
+                .original
+                .then(|| format!("/fetch_raw?id={}&share={}", id, token)),
+            None,
+        ),
+        Shared::Album(id) => (None, None, Some(format!("/albums/{}?share={}", id, token))),
+    };
+    HttpResponse::Created().json(ShareLink {
+        token,
+        expires: grant.expires,
+        rendition_url,
+        original_url,
+        album_url,
+    })
+}
+
+/// Creates a link to an image which works without an API key, for the owner of the image or for
+/// anyone if it is public
+pub async fn create_share_link(
+    data: Data<Arc<SQLiteDatabase>>,
+    share_links: Data<ShareLinks>,
+    user: User,
+    id: web::Path<Id>,
+    request: Json<ShareRequest>,
+) -> HttpResponse {
+    let id = id.into_inner();
+    match data.may_share(&id, &user).await {
+        Ok(true) => share(&share_links, &user, Shared::Image(id), request.into_inner()),
+        Ok(false) => HttpResponse::NotFound().body(format!("image with id {} not found", id)),
+        Err(e) => {
+            log::warn!("{:?}", e);
+            HttpResponse::InternalServerError().body("")
+        }
+    }
+}
+
+/// Creates a link to an album which works without an API key, for the owner of the album. The
+/// link lists the album, and serves the images in it.
+pub async fn create_album_share_link(
+    data: Data<Arc<SQLiteDatabase>>,
+    share_links: Data<ShareLinks>,
+    user: User,
+    id: web::Path<Id>,
+    request: Json<ShareRequest>,
+) -> HttpResponse {
+    let id = id.into_inner();
+    match data.album(&id).await {
+        Ok(Some(album)) if album.owner == user.name => {
+            share(&share_links, &user, Shared::Album(id), request.into_inner())
+        }
+        Ok(_) => HttpResponse::NotFound().body(format!("album with id {} not found", id)),
+        Err(e) => {
+            log::warn!("{:?}", e);
+            HttpResponse::InternalServerError().body("")
+        }
+    }
+}
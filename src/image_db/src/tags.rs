@@ -0,0 +1,214 @@
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use actix_web::web::{self, Data, Json};
+use actix_web::HttpResponse;
+
+use crate::auth::User;
+use crate::db::Id;
+use crate::visibility;
+use crate::weaviate_graphql::{MultiOperator, Operator, WeaviateWhere, WhereValue};
+use crate::SQLiteDatabase;
+
+/// `ClipImage` property which lists the tags of an image
+pub(crate) const TAGS_PROPERTY: &str = "tags";
+
+/// Tags are compared in lowercase, with words joined by hyphens, since weaviate splits `string`
+/// properties into words and would otherwise match `new york` on any image tagged both `new` and
+/// `york`. Commas separate tags in query strings, so tags can not contain them.
+pub(crate) fn normalize(tag: &str) -> Result<String, &'static str> {
+    let tag = tag
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join("-")
+        .to_lowercase();
+    if tag.is_empty() {
+        Err("tags must not be empty")
+    } else if tag.contains(',') {
+        Err("tags must not contain commas")
+    } else {
+        Ok(tag)
+    }
+}
+
+/// Which tags images must have, and which they must not have
+pub(crate) struct TagFilter {
+    pub(crate) include: Vec<String>,
+    pub(crate) exclude: Vec<String>,
+}
+
+impl TagFilter {
+    /// Parses comma separated lists of tags. Tags which are given twice, perhaps in another case,
+    /// are only kept once, since `browse` counts the distinct tags which images have.
+    pub(crate) fn parse(
+        include: Option<&str>,
+        exclude: Option<&str>,
+    ) -> Result<Self, &'static str> {
+        let parse = |list: Option<&str>| -> Result<Vec<String>, &'static str> {
+            let mut tags = list.map_or(Ok(Vec::new()), |list| {
+                list.split(',')
+                    .map(normalize)
+                    .collect::<Result<Vec<_>, _>>()
+            })?;
+            tags.sort();
+            tags.dedup();
+            Ok(tags)
+        };
+        Ok(TagFilter {
+            include: parse(include)?,
+            exclude: parse(exclude)?,
+        })
+    }
+
+    /// Matches the images which pass the filter, or `None` if every image passes
+    pub(crate) fn search_filter(&self) -> Option<WeaviateWhere> {
+        let mut operands: Vec<_> = self
+            .include
+            .iter()
+            .map(|tag| visibility::equals(TAGS_PROPERTY, tag))
+            .collect();
+        // Lists are not equal to a value if none of their elements are
+        operands.extend(self.exclude.iter().map(|tag| WeaviateWhere::Single {
+            path: vec![TAGS_PROPERTY.to_string()],
+            operator: Operator::NotEqual,
+            value: WhereValue::String(tag.clone()),
+        }));
+        if operands.is_empty() {
+            None
+        } else {
+            Some(WeaviateWhere::Multiple {
+                operator: MultiOperator::And,
+                operands,
+            })
+        }
+    }
+}
+
+/// A tag, and how many of the images which the user may see have it
+#[derive(Serialize)]
+pub struct TagCount {
+    pub(crate) name: String,
+    pub(crate) count: i64,
+}
+
+/// A tag of an image
+#[derive(Serialize)]
+pub struct ImageTag {
+    pub(crate) name: String,
+    /// How similar the image is to the tag, for tags which auto-tagging assigned
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) confidence: Option<f64>,
+}
+
+#[derive(Deserialize)]
+pub struct TagQuery {
+    /// Only lists tags which start with this, for autocompletion
+    prefix: Option<String>,
+    limit: Option<u32>,
+}
+
+#[derive(Deserialize)]
+pub struct TagEdit {
+    ids: Vec<Id>,
+    #[serde(default)]
+    add: Vec<String>,
+    #[serde(default)]
+    remove: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct TaggedImages {
+    /// The images whose tags were changed, leaving out those which the user may not tag
+    updated: Vec<Id>,
+}
+
+#[derive(Deserialize)]
+pub struct Browse {
+    /// Comma separated tags which every image must have
+    tags: Option<String>,
+    /// Comma separated tags which no image may have
+    exclude_tags: Option<String>,
+    #[serde(default)]
+    offset: u32,
+    limit: Option<u32>,
+}
+
+#[derive(Serialize)]
+pub struct BrowseOutput {
+    ids: Vec<Id>,
+}
+
+fn internal_error(e: impl std::fmt::Debug) -> HttpResponse {
+    log::warn!("{:?}", e);
+    HttpResponse::InternalServerError().body("")
+}
+
+/// Lists tags by how many of the images which the user may see have them, most used first
+pub async fn list_tags(
+    data: Data<Arc<SQLiteDatabase>>,
+    user: User,
+    params: web::Query<TagQuery>,
+) -> HttpResponse {
+    let limit = params.limit.unwrap_or(10).min(100);
+    let prefix = params.prefix.as_deref().unwrap_or("").trim().to_lowercase();
+    match data.tag_counts(&user, &prefix, limit).await {
+        Ok(tags) => HttpResponse::Ok().json(tags),
+        Err(e) => internal_error(e),
+    }
+}
+
+/// Adds and removes tags on images which the user owns, or on any image for admins. Adding a tag
+/// which auto-tagging assigned confirms it, so that re-tagging keeps it.
+pub async fn edit_tags(
+    data: Data<Arc<SQLiteDatabase>>,
+    user: User,
+    edit: Json<TagEdit>,
+) -> HttpResponse {
+    let normalized = |tags: &[String]| -> Result<Vec<String>, &'static str> {
+        tags.iter().map(|tag| normalize(tag)).collect()
+    };
+    let (add, remove): (Vec<String>, Vec<String>) =
+        match (normalized(&edit.add), normalized(&edit.remove)) {
+            (Ok(add), Ok(remove)) => (add, remove),
+            (Err(reason), _) | (_, Err(reason)) => return HttpResponse::BadRequest().body(reason),
+        };
+    if add.iter().any(|tag| remove.contains(tag)) {
+        return HttpResponse::BadRequest().body("tags can not be added and removed at once");
+    }
+    match data.edit_tags(&edit.ids, &user, &add, &remove).await {
+        Ok(updated) => HttpResponse::Ok().json(TaggedImages { updated }),
+        Err(e) => internal_error(e),
+    }
+}
+
+pub async fn image_tags(
+    data: Data<Arc<SQLiteDatabase>>,
+    user: User,
+    id: web::Path<Id>,
+) -> HttpResponse {
+    if data.visible_file(&id, &user).await.is_err() {
+        return HttpResponse::NotFound().body(format!("image with id {} not found", id));
+    }
+    match data.image_tags(&id).await {
+        Ok(tags) => HttpResponse::Ok().json(tags),
+        Err(e) => internal_error(e),
+    }
+}
+
+/// Lists the images which the user may see, newest first
+pub async fn browse(
+    data: Data<Arc<SQLiteDatabase>>,
+    user: User,
+    params: web::Query<Browse>,
+) -> HttpResponse {
+    let filter = match TagFilter::parse(params.tags.as_deref(), params.exclude_tags.as_deref()) {
+        Ok(filter) => filter,
+        Err(reason) => return HttpResponse::BadRequest().body(reason),
+    };
+    let limit = params.limit.unwrap_or(100).min(1000);
+    match data.browse(&user, &filter, params.offset, limit).await {
+        Ok(ids) => HttpResponse::Ok().json(BrowseOutput { ids }),
+        Err(e) => internal_error(e),
+    }
+}
@@ -0,0 +1,554 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::OpenOptions;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use actix_web::http::header::{CacheControl, CacheDirective, HttpDate, CONTENT_TYPE, LOCATION};
+use actix_web::http::StatusCode;
+use actix_web::web::{self, Data};
+use actix_web::{HttpRequest, HttpResponse, HttpResponseBuilder};
+use futures::StreamExt;
+use sha1::{Digest, Sha1};
+use tempfile::NamedTempFile;
+
+use crate::auth::User;
+use crate::db::files::UploadLimits;
+use crate::formats;
+use crate::visibility::Visibility;
+use crate::SQLiteDatabase;
+
+const TUS_VERSION: &str = "1.0.0";
+const TUS_EXTENSIONS: &str = "creation,checksum,termination,expiration";
+const CHECKSUM_ALGORITHMS: &str = "md5,sha1";
+/// Sent when a chunk does not match its `Upload-Checksum`, as the checksum extension specifies
+const CHECKSUM_MISMATCH: u16 = 460;
+/// Enough of the start of a file for `formats::sniff`
+const HEADER_LEN: u64 = 16;
+/// How long uploads are kept after they last received a chunk
+const UPLOAD_EXPIRY: Duration = Duration::from_secs(24 * 60 * 60);
+/// How often expired uploads are removed
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// How many unfinished uploads each user may have at once, which bounds the disk space that
+/// they can reserve without finishing an upload
+const MAX_UPLOADS_PER_USER: usize = 10;
+
+/// An upload which has been created, and which may have been partially received
+#[derive(Serialize, Deserialize)]
+struct TusUpload {
+    /// Size of the complete file in bytes
+    length: u64,
+    /// The file name from the `Upload-Metadata`
+    name: String,
+    /// The user who created the upload, who is the only one who may continue it
+    uploader: String,
+    visibility: Visibility,
+}
+
+/// Uploads which are received with the tus resumable upload protocol, so that large RAWs do not
+/// need to be sent again after a connection drops. Each upload is stored as `{id}`, which holds
+/// the bytes received so far, and `{id}.json`, so that uploads can be resumed across restarts.
+/// Uploads which receive no chunks for `UPLOAD_EXPIRY` are abandoned, and removed.
+pub struct TusUploads {
+    dir: PathBuf,
+    /// Uploads which are currently receiving a chunk
+    locked: Mutex<HashSet<uuid::Uuid>>,
+}
+
+/// Releases the upload when dropped
+struct UploadLock<'a> {
+    uploads: &'a TusUploads,
+    id: uuid::Uuid,
+}
+
+impl Drop for UploadLock<'_> {
+    fn drop(&mut self) {
+        self.uploads.locked.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl TusUploads {
+    pub(crate) fn open(dir: PathBuf) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        Ok(TusUploads {
+            dir,
+            locked: Mutex::new(HashSet::new()),
+        })
+    }
+
+    fn data_path(&self, id: uuid::Uuid) -> PathBuf {
+        self.dir.join(id.to_string())
+    }
+
+    fn info_path(&self, id: uuid::Uuid) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+
+    fn create(&self, upload: &TusUpload) -> std::io::Result<uuid::Uuid> {
+        let id = uuid::Uuid::new_v4();
+        std::fs::File::create(self.data_path(id))?;
+        std::fs::write(self.info_path(id), serde_json::to_vec(upload)?)?;
+        Ok(id)
+    }
+
+    /// The upload, if it was created by `user` and has not expired
+    fn get(&self, id: uuid::Uuid, user: &User) -> Option<TusUpload> {
+        let info = std::fs::read(self.info_path(id)).ok()?;
+        serde_json::from_slice::<TusUpload>(&info)
+            .ok()
+            .filter(|upload| upload.uploader == user.name && !self.is_expired(id))
+    }
+
+    /// The ids of the uploads which have been created
+    fn ids(&self) -> std::io::Result<Vec<uuid::Uuid>> {
+        Ok(std::fs::read_dir(&self.dir)?
+            .filter_map(|entry| {
+                let name = entry.ok()?.file_name();
+                uuid::Uuid::parse_str(name.to_str()?.strip_suffix(".json")?).ok()
+            })
+            .collect())
+    }
+
+    /// How many unfinished uploads `user` has
+    fn pending(&self, user: &User) -> std::io::Result<usize> {
+        Ok(self
+            .ids()?
+            .into_iter()
+            .filter(|id| self.get(*id, user).is_some())
+            .count())
+    }
+
+    /// When the upload expires, which every chunk that it receives pushes back
+    fn expires(&self, id: uuid::Uuid) -> std::io::Result<SystemTime> {
+        Ok(std::fs::metadata(self.data_path(id))?.modified()? + UPLOAD_EXPIRY)
+    }
+
+    fn is_expired(&self, id: uuid::Uuid) -> bool {
+        self.expires(id)
+            .map_or(true, |expires| expires <= SystemTime::now())
+    }
+
+    /// Removes the uploads which expired, except those which are receiving a chunk, returning
+    /// how many were removed
+    fn remove_expired(&self) -> std::io::Result<usize> {
+        let mut removed = 0;
+        for id in self.ids()? {
+            if let Some(_lock) = self.lock(id) {
+                if self.is_expired(id) {
+                    self.remove(id);
+                    removed += 1;
+                }
+            }
+        }
+        Ok(removed)
+    }
+
+    /// The number of bytes of the upload which have been received
+    fn offset(&self, id: uuid::Uuid) -> std::io::Result<u64> {
+        Ok(std::fs::metadata(self.data_path(id))?.len())
+    }
+
+    fn lock(&self, id: uuid::Uuid) -> Option<UploadLock> {
+        if self.locked.lock().unwrap().insert(id) {
+            Some(UploadLock { uploads: self, id })
+        } else {
+            None
+        }
+    }
+
+    fn remove(&self, id: uuid::Uuid) {
+        drop(std::fs::remove_file(self.data_path(id)));
+        drop(std::fs::remove_file(self.info_path(id)));
+    }
+
+    /// Turns a complete upload into a temporary file, which is deleted unless it is stored
+    fn take(&self, id: uuid::Uuid) -> std::io::Result<NamedTempFile> {
+        let (_, temp_path) = NamedTempFile::new_in(&self.dir)?.into_parts();
+        std::fs::rename(self.data_path(id), &temp_path)?;
+        let file = std::fs::File::open(&temp_path)?;
+        drop(std::fs::remove_file(self.info_path(id)));
+        Ok(NamedTempFile::from_parts(file, temp_path))
+    }
+}
+
+/// A running checksum of a chunk, and the checksum the client sent
+enum Checksum {
+    Md5(md5::Context, Vec<u8>),
+    Sha1(Sha1, Vec<u8>),
+}
+
+impl Checksum {
+    /// Parses `Upload-Checksum`, which is the algorithm and the base64 encoded checksum
+    fn parse(header: &str) -> Option<Self> {
+        let (algorithm, checksum) = header.split_once(' ')?;
+        let checksum = base64::decode(checksum.trim()).ok()?;
+        match algorithm {
+            "md5" => Some(Checksum::Md5(md5::Context::new(), checksum)),
+            "sha1" => Some(Checksum::Sha1(Sha1::new(), checksum)),
+            _ => None,
+        }
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        match self {
+            Checksum::Md5(context, _) => context.consume(bytes),
+            Checksum::Sha1(hasher, _) => hasher.update(bytes),
+        }
+    }
+
+    fn matches(self) -> bool {
+        match self {
+            Checksum::Md5(context, expected) => context.compute().as_ref() == expected.as_slice(),
+            Checksum::Sha1(hasher, expected) => hasher.finalize().as_slice() == expected.as_slice(),
+        }
+    }
+}
+
+/// Parses `Upload-Metadata`, which is a list of keys and base64 encoded values
+fn parse_metadata(header: &str) -> HashMap<String, String> {
+    header
+        .split(',')
+        .filter_map(|pair| {
+            let mut pair = pair.trim().splitn(2, ' ');
+            let key = pair.next().filter(|key| !key.is_empty())?;
+            let value = base64::decode(pair.next().unwrap_or("")).ok()?;
+            Some((
+                key.to_string(),
+                String::from_utf8_lossy(&value).into_owned(),
+            ))
+        })
+        .collect()
+}
+
+fn header<'a>(req: &'a HttpRequest, name: &str) -> Option<&'a str> {
+    req.headers().get(name)?.to_str().ok()
+}
+
+fn tus_response(status: StatusCode) -> HttpResponseBuilder {
+    let mut response = HttpResponse::build(status);
+    response
+        .insert_header(("Tus-Resumable", TUS_VERSION))
+        .insert_header(CacheControl(vec![CacheDirective::NoStore]));
+    response
+}
+
+/// Rejects requests for other versions of the protocol
+fn check_version(req: &HttpRequest) -> Result<(), HttpResponse> {
+    match header(req, "Tus-Resumable") {
+        Some(TUS_VERSION) => Ok(()),
+        _ => Err(tus_response(StatusCode::PRECONDITION_FAILED)
+            .insert_header(("Tus-Version", TUS_VERSION))
+            .finish()),
+    }
+}
+
+fn parse_id(id: &str) -> Result<uuid::Uuid, HttpResponse> {
+    uuid::Uuid::parse_str(id).map_err(|_| tus_response(StatusCode::NOT_FOUND).finish())
+}
+
+fn internal_error(e: impl std::fmt::Debug) -> HttpResponse {
+    log::warn!("{:?}", e);
+    tus_response(StatusCode::INTERNAL_SERVER_ERROR).finish()
+}
+
+/// The `Upload-Expires` header of the upload
+fn expires_header(uploads: &TusUploads, id: uuid::Uuid) -> std::io::Result<(&'static str, String)> {
+    Ok((
+        "Upload-Expires",
+        HttpDate::from(uploads.expires(id)?).to_string(),
+    ))
+}
+
+/// Removes expired uploads periodically, for as long as the server runs
+pub(crate) async fn expire_uploads(uploads: Data<TusUploads>) {
+    let mut interval = actix_web::rt::time::interval(EXPIRY_INTERVAL);
+    loop {
+        interval.tick().await;
+        match uploads.remove_expired() {
+            Ok(0) => {}
+            Ok(removed) => log::info!("Removed {} expired uploads", removed),
+            Err(e) => log::warn!("Removing expired uploads failed: {:?}", e),
+        }
+    }
+}
+
+/// Describes what this server supports
+pub async fn tus_options(limits: Data<UploadLimits>) -> HttpResponse {
+    HttpResponse::NoContent()
+        .insert_header(("Tus-Resumable", TUS_VERSION))
+        .insert_header(("Tus-Version", TUS_VERSION))
+        .insert_header(("Tus-Extension", TUS_EXTENSIONS))
+        .insert_header(("Tus-Max-Size", limits.max_file_bytes.to_string()))
+        .insert_header(("Tus-Checksum-Algorithm", CHECKSUM_ALGORITHMS))
+        .finish()
+}
+
+/// Creates an upload of `Upload-Length` bytes. The file name is read from the `filename` key of
+/// `Upload-Metadata`, and who may see the image from `visibility`, which defaults to private.
+/// Users may only have `MAX_UPLOADS_PER_USER` unfinished uploads at once.
+pub async fn tus_create(
+    uploads: Data<TusUploads>,
+    limits: Data<UploadLimits>,
+    user: User,
+    req: HttpRequest,
+) -> HttpResponse {
+    if let Err(response) = check_version(&req) {
+        return response;
+    }
+    let length = match header(&req, "Upload-Length").and_then(|length| length.parse::<u64>().ok()) {
+        Some(length) => length,
+        None => {
+            return tus_response(StatusCode::BAD_REQUEST).body("Upload-Length must be given");
+        }
+    };
+    if length > limits.max_file_bytes {
+        return tus_response(StatusCode::PAYLOAD_TOO_LARGE).body(format!(
+            "upload is larger than the limit of {} KB per file",
+            limits.max_file_bytes / 1024
+        ));
+    }
+
+    let mut metadata = header(&req, "Upload-Metadata")
+        .map(parse_metadata)
+        .unwrap_or_default();
+    let name = metadata
+        .remove("filename")
+        .or_else(|| metadata.remove("name"))
+        .unwrap_or_default();
+    let visibility = match metadata.remove("visibility") {
+        Some(visibility) => match Visibility::try_from(visibility.as_str()) {
+            Ok(visibility) => visibility,
+            Err(reason) => return tus_response(StatusCode::BAD_REQUEST).body(reason),
+        },
+        None => Visibility::Private,
+    };
+    // Concurrent creations by the same user may overshoot the limit
+    match uploads.pending(&user) {
+        Ok(pending) if pending >= MAX_UPLOADS_PER_USER => {
+            return tus_response(StatusCode::TOO_MANY_REQUESTS).body(format!(
+                "at most {} uploads may be unfinished at once",
+                MAX_UPLOADS_PER_USER
+            ));
+        }
+        Ok(_) => {}
+        Err(e) => return internal_error(e),
+    }
+    let upload = TusUpload {
+        length,
+        name,
+        uploader: user.name,
+        visibility,
+    };
+
+    match uploads
+        .create(&upload)
+        .and_then(|id| Ok((id, expires_header(&uploads, id)?)))
+    {
+        Ok((id, expires)) => tus_response(StatusCode::CREATED)
+            .insert_header((LOCATION, format!("{}/{}", req.path(), id)))
+            .insert_header(expires)
+            .finish(),
+        Err(e) => internal_error(e),
+    }
+}
+
+/// Reports how much of the upload has been received, so that the client knows where to resume
+pub async fn tus_offset(
+    uploads: Data<TusUploads>,
+    user: User,
+    id: web::Path<String>,
+) -> HttpResponse {
+    let id = match parse_id(&id) {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    let upload = match uploads.get(id, &user) {
+        Some(upload) => upload,
+        None => return tus_response(StatusCode::NOT_FOUND).finish(),
+    };
+    match uploads
+        .offset(id)
+        .and_then(|offset| Ok((offset, expires_header(&uploads, id)?)))
+    {
+        Ok((offset, expires)) => tus_response(StatusCode::OK)
+            .insert_header(("Upload-Offset", offset.to_string()))
+            .insert_header(("Upload-Length", upload.length.to_string()))
+            .insert_header(expires)
+            .finish(),
+        Err(e) => internal_error(e),
+    }
+}
+
+/// Appends a chunk at `Upload-Offset`. Chunks which do not match their `Upload-Checksum` are
+/// discarded. Once the last chunk is received, the file is stored like a file sent to
+/// `upload_raw`, and the response has its `UploadRawResponse`.
+pub async fn tus_append(
+    data: Data<Arc<SQLiteDatabase>>,
+    uploads: Data<TusUploads>,
+    user: User,
+    req: HttpRequest,
+    id: web::Path<String>,
+    mut payload: web::Payload,
+) -> HttpResponse {
+    if let Err(response) = check_version(&req) {
+        return response;
+    }
+    let id = match parse_id(&id) {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    if header(&req, CONTENT_TYPE.as_str()) != Some("application/offset+octet-stream") {
+        return tus_response(StatusCode::UNSUPPORTED_MEDIA_TYPE).finish();
+    }
+    let upload = match uploads.get(id, &user) {
+        Some(upload) => upload,
+        None => return tus_response(StatusCode::NOT_FOUND).finish(),
+    };
+    let _lock = match uploads.lock(id) {
+        Some(lock) => lock,
+        None => return tus_response(StatusCode::LOCKED).body("upload is receiving another chunk"),
+    };
+
+    let start = match uploads.offset(id) {
+        Ok(offset) => offset,
+        Err(e) => return internal_error(e),
+    };
+    if header(&req, "Upload-Offset").and_then(|offset| offset.parse::<u64>().ok()) != Some(start) {
+        return tus_response(StatusCode::CONFLICT)
+            .insert_header(("Upload-Offset", start.to_string()))
+            .finish();
+    }
+    let mut checksum = match header(&req, "Upload-Checksum") {
+        Some(checksum) => match Checksum::parse(checksum) {
+            Some(checksum) => Some(checksum),
+            None => {
+                return tus_response(StatusCode::BAD_REQUEST).body(format!(
+                    "Upload-Checksum must use one of {}",
+                    CHECKSUM_ALGORITHMS
+                ))
+            }
+        },
+        None => None,
+    };
+
+    let mut file = match OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(uploads.data_path(id))
+    {
+        Ok(file) => file,
+        Err(e) => return internal_error(e),
+    };
+    let extension = upload
+        .name
+        .rsplit_once('.')
+        .map(|(_, ext)| ext.to_ascii_lowercase());
+    let mut offset = start;
+    while let Some(chunk) = payload.next().await {
+        // Keep what was received before the connection dropped, so that the client can resume
+        // from there, unless it has to be checked against a checksum
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(_) if checksum.is_none() => break,
+            Err(e) => {
+                drop(file.set_len(start));
+                return internal_error(e);
+            }
+        };
+        if offset + chunk.len() as u64 > upload.length {
+            drop(file.set_len(start));
+            return tus_response(StatusCode::PAYLOAD_TOO_LARGE).body("chunk exceeds Upload-Length");
+        }
+        if let Err(e) = file
+            .seek(SeekFrom::Start(offset))
+            .and_then(|_| file.write_all(&chunk))
+        {
+            drop(file.set_len(start));
+            return internal_error(e);
+        }
+        if let Some(checksum) = &mut checksum {
+            checksum.update(&chunk);
+        }
+        let previous = offset;
+        offset += chunk.len() as u64;
+
+        // Check the format as soon as its first bytes arrive
+        if previous < HEADER_LEN && (offset >= HEADER_LEN || offset == upload.length) {
+            match formats::sniff_file(&mut file, extension.as_deref()) {
+                Ok(Some(_)) => {}
+                Ok(None) => {
+                    uploads.remove(id);
+                    return tus_response(StatusCode::UNSUPPORTED_MEDIA_TYPE).body(format!(
+                        "{} is not a camera RAW, JPEG, PNG, TIFF or WebP image",
+                        upload.name
+                    ));
+                }
+                Err(e) => return internal_error(e),
+            }
+        }
+    }
+
+    if let Some(checksum) = checksum {
+        if !checksum.matches() {
+            drop(file.set_len(start));
+            return tus_response(StatusCode::from_u16(CHECKSUM_MISMATCH).unwrap())
+                .insert_header(("Upload-Offset", start.to_string()))
+                .body("chunk does not match Upload-Checksum");
+        }
+    }
+    drop(file);
+
+    if offset < upload.length {
+        return match expires_header(&uploads, id) {
+            Ok(expires) => tus_response(StatusCode::NO_CONTENT)
+                .insert_header(("Upload-Offset", offset.to_string()))
+                .insert_header(expires)
+                .finish(),
+            Err(e) => internal_error(e),
+        };
+    }
+
+    let file = match uploads.take(id) {
+        Ok(file) => file,
+        Err(e) => return internal_error(e),
+    };
+    match data
+        .store_images(vec![(file, upload.name)], &user, upload.visibility)
+        .await
+    {
+        Ok(Some(response)) => tus_response(StatusCode::OK)
+            .insert_header(("Upload-Offset", offset.to_string()))
+            .json(response),
+        Ok(None) => internal_error("storing upload failed"),
+        Err(e) => internal_error(e),
+    }
+}
+
+/// Discards an upload which will not be completed
+pub async fn tus_terminate(
+    uploads: Data<TusUploads>,
+    user: User,
+    req: HttpRequest,
+    id: web::Path<String>,
+) -> HttpResponse {
+    if let Err(response) = check_version(&req) {
+        return response;
+    }
+    let id = match parse_id(&id) {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    if uploads.get(id, &user).is_none() {
+        return tus_response(StatusCode::NOT_FOUND).finish();
+    }
+    match uploads.lock(id) {
+        Some(_lock) => {
+            uploads.remove(id);
+            tus_response(StatusCode::NO_CONTENT).finish()
+        }
+        None => tus_response(StatusCode::LOCKED).body("upload is receiving a chunk"),
+    }
+}
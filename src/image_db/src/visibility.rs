@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use actix_web::web::{self, Data, Json};
+use actix_web::HttpResponse;
+
+use crate::auth::User;
+use crate::db::Id;
+use crate::weaviate_graphql::{MultiOperator, Operator, WeaviateWhere, WhereValue};
+use crate::SQLiteDatabase;
+
+/// `ClipImage` properties which search results are filtered by
+pub(crate) const OWNER_PROPERTY: &str = "owner";
+pub(crate) const GROUP_PROPERTY: &str = "ownerGroup";
+pub(crate) const VISIBILITY_PROPERTY: &str = "visibility";
+
+/// Who may see an image
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Visibility {
+    /// Only the owner
+    Private,
+    /// The owner, and the users in the owner's group
+    Group,
+    /// Everyone
+    Public,
+}
+
+impl Visibility {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Visibility::Private => "private",
+            Visibility::Group => "group",
+            Visibility::Public => "public",
+        }
+    }
+}
+
+impl TryFrom<&str> for Visibility {
+    type Error = &'static str;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "private" => Ok(Visibility::Private),
+            "group" => Ok(Visibility::Group),
+            "public" => Ok(Visibility::Public),
+            _ => Err("visibility must be private, group or public"),
+        }
+    }
+}
+
+/// Who owns an image, and who else may see it. Images without an owner, such as those in the
+/// mounted directory, are public.
+#[derive(Clone, Debug)]
+pub(crate) struct Access {
+    pub(crate) owner: Option<String>,
+    /// The group of the owner
+    pub(crate) group: Option<String>,
+    pub(crate) visibility: Visibility,
+}
+
+impl Access {
+    /// Images in the mounted directory, which everyone may see
+    pub(crate) fn public() -> Self {
+        Access {
+            owner: None,
+            group: None,
+            visibility: Visibility::Public,
+        }
+    }
+
+    /// An image which is uploaded by `user`
+    pub(crate) fn owned_by(user: &User, visibility: Visibility) -> Self {
+        Access {
+            owner: Some(user.name.clone()),
+            group: user.group_name.clone(),
+            visibility,
+        }
+    }
+
+    /// The access recorded for a stored image. Unknown visibilities are as restrictive as
+    /// possible.
+    pub(crate) fn stored(owner: Option<String>, group: Option<String>, visibility: &str) -> Self {
+        Access {
+            owner,
+            group,
+            visibility: Visibility::try_from(visibility).unwrap_or(Visibility::Private),
+        }
+    }
+
+    /// The `ClipImage` properties which `search_filter` matches
+    pub(crate) fn properties(&self) -> HashMap<String, String> {
+        let mut properties = HashMap::new();
+        properties.insert(
+            VISIBILITY_PROPERTY.to_string(),
+            self.visibility.as_str().to_string(),
+        );
+        if let Some(owner) = &self.owner {
+            properties.insert(OWNER_PROPERTY.to_string(), owner.clone());
+        }
+        if let Some(group) = &self.group {
+            properties.insert(GROUP_PROPERTY.to_string(), group.clone());
+        }
+        properties
+    }
+}
+
+/// Matches the images whose `property` is `value`, or contains it if it is a list
+pub(crate) fn equals(property: &str, value: &str) -> WeaviateWhere {
+    WeaviateWhere::Single {
+        path: vec![property.to_string()],
+        operator: Operator::Equal,
+        value: WhereValue::String(value.to_string()),
+    }
+}
+
+/// Matches the images which `user` may see. Every vector query must be filtered by this.
+pub(crate) fn search_filter(user: &User) -> WeaviateWhere {
+    let mut operands = vec![
+        equals(VISIBILITY_PROPERTY, Visibility::Public.as_str()),
+        equals(OWNER_PROPERTY, &user.name),
+    ];
+    if let Some(group) = &user.group_name {
+        operands.push(WeaviateWhere::Multiple {
+            operator: MultiOperator::And,
+            operands: vec![
+                equals(VISIBILITY_PROPERTY, Visibility::Group.as_str()),
+                equals(GROUP_PROPERTY, group),
+            ],
+        });
+    }
+    WeaviateWhere::Multiple {
+        operator: MultiOperator::Or,
+        operands,
+    }
+}
+
+#[derive(Deserialize)]
+pub struct VisibilityRequest {
+    visibility: Visibility,
+}
+
+/// Changes who may see an image, which only its owner may do
+pub async fn set_visibility(
+    data: Data<Arc<SQLiteDatabase>>,
+    user: User,
+    id: web::Path<Id>,
+    request: Json<VisibilityRequest>,
+) -> HttpResponse {
+    match data.set_visibility(&id, &user, request.visibility).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().body(format!("image with id {} not found", id)),
+        Err(e) => {
+            log::warn!("{:?}", e);
+            HttpResponse::InternalServerError().body("")
+        }
+    }
+}
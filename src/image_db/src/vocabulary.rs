@@ -0,0 +1,214 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use actix_web::web::Data;
+use actix_web::HttpResponse;
+
+use crate::auth::User;
+use crate::db::Error;
+use crate::tags;
+use crate::weaviate_graphql::VectorizerInput;
+use crate::SQLiteDatabase;
+
+fn default_templates() -> Vec<String> {
+    vec![String::from("a photo of {}.")]
+}
+
+fn default_threshold() -> f32 {
+    0.25
+}
+
+/// Labels which new images are tagged with when they are similar enough to them, read from
+/// `vocabulary.ron` in the data directory
+#[derive(Deserialize, Serialize)]
+pub struct Vocabulary {
+    /// Prompts which the labels are compared through, where `{}` is replaced by the label.
+    /// Comparing through several prompts evens out the quirks of each one.
+    #[serde(default = "default_templates")]
+    templates: Vec<String>,
+    /// Lowest cosine similarity between an image and a label for the image to be tagged with it
+    #[serde(default = "default_threshold")]
+    threshold: f32,
+    labels: Vec<String>,
+}
+
+impl Vocabulary {
+    /// Reads the vocabulary at `path`, if it exists
+    fn load(path: &Path) -> Result<Option<Self>, ron::Error> {
+        match std::fs::read_to_string(path) {
+            Ok(vocabulary) => Ok(Some(ron::from_str(&vocabulary)?)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Identifies the vocabulary, so that cached vectors of another vocabulary are not used. The
+    /// version changes when labels are turned into tags differently.
+    fn fingerprint(&self) -> String {
+        let serialized = format!("2:{}", ron::to_string(self).unwrap_or_default());
+        blake3::hash(serialized.as_bytes()).to_hex().to_string()
+    }
+}
+
+/// The text vectors of the labels of a vocabulary
+#[derive(Deserialize, Serialize)]
+pub struct LabelVectors {
+    fingerprint: String,
+    threshold: f32,
+    labels: Vec<(String, Vec<f32>)>,
+}
+
+/// `vector`, scaled to unit length
+pub(crate) fn normalized(mut vector: Vec<f32>) -> Vec<f32> {
+    let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0. {
+        vector.iter_mut().for_each(|x| *x /= norm);
+    }
+    vector
+}
+
+impl LabelVectors {
+    /// The labels which the image with `vector` is tagged with, and how similar it is to them
+    pub(crate) fn score(&self, vector: &[f32]) -> Vec<(String, f32)> {
+        let vector = normalized(vector.to_vec());
+        self.labels
+            .iter()
+            .map(|(label, label_vector)| {
+                let similarity = label_vector.iter().zip(&vector).map(|(a, b)| a * b).sum();
+                (label.clone(), similarity)
+            })
+            .filter(|(_, similarity)| *similarity >= self.threshold)
+            .collect()
+    }
+}
+
+/// Where the vocabulary is read from, and where the vectors of its labels are cached
+pub struct VocabularyFiles {
+    vocabulary: PathBuf,
+    vectors: PathBuf,
+}
+
+impl VocabularyFiles {
+    pub(crate) fn new(data_dir: &Path) -> Self {
+        VocabularyFiles {
+            vocabulary: data_dir.join("vocabulary.ron"),
+            vectors: data_dir.join("vocabulary_vectors.ron"),
+        }
+    }
+}
+
+/// Vectorizes the labels of `vocabulary`, averaging the vectors of their prompts
+async fn vectorize(db: &SQLiteDatabase, vocabulary: &Vocabulary) -> Result<LabelVectors, Error> {
+    // Labels are tagged in their normalized form, but prompted as they were written
+    let labels = vocabulary
+        .labels
+        .iter()
+        .filter_map(|label| match tags::normalize(label) {
+            Ok(tag) => Some((tag, label.trim())),
+            Err(reason) => {
+                log::warn!("Skipping label {:?} of the vocabulary: {}", label, reason);
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+    let texts = labels
+        .iter()
+        .flat_map(|(_, label)| {
+            vocabulary
+                .templates
+                .iter()
+                .map(move |template| template.replace("{}", label))
+        })
+        .collect();
+    let vectors = db
+        .vectorize(VectorizerInput {
+            texts,
+            images: vec![],
+        })
+        .await?
+        .text_vectors;
+
+    let labels = labels
+        .into_iter()
+        .zip(vectors.chunks(vocabulary.templates.len().max(1)))
+        .map(|((tag, _), prompts)| {
+            let mut sum = vec![0.; prompts.first().map_or(0, Vec::len)];
+            for prompt in prompts {
+                let prompt = normalized(prompt.clone());
+                sum.iter_mut().zip(prompt).for_each(|(sum, x)| *sum += x);
+            }
+            (tag, normalized(sum))
+        })
+        .collect();
+    Ok(LabelVectors {
+        fingerprint: vocabulary.fingerprint(),
+        threshold: vocabulary.threshold,
+        labels,
+    })
+}
+
+/// Loads the vocabulary and the vectors of its labels, which are only computed if the cached
+/// vectors belong to another vocabulary. Returns `None` if there is no vocabulary.
+pub(crate) async fn load(
+    db: &SQLiteDatabase,
+    files: &VocabularyFiles,
+) -> Result<Option<Arc<LabelVectors>>, Error> {
+    let vocabulary = match Vocabulary::load(&files.vocabulary) {
+        Ok(Some(vocabulary)) => vocabulary,
+        Ok(None) => return Ok(None),
+        Err(e) => {
+            return Err(Error::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                e,
+            )))
+        }
+    };
+
+    let fingerprint = vocabulary.fingerprint();
+    if let Some(cached) = std::fs::read_to_string(&files.vectors)
+        .ok()
+        .and_then(|cached| ron::from_str::<LabelVectors>(&cached).ok())
+        .filter(|cached| cached.fingerprint == fingerprint)
+    {
+        return Ok(Some(Arc::new(cached)));
+    }
+
+    let vectors = vectorize(db, &vocabulary).await?;
+    if let Ok(serialized) = ron::to_string(&vectors) {
+        if let Err(e) = std::fs::write(&files.vectors, serialized) {
+            log::warn!("Failed to cache vocabulary vectors: {:?}", e);
+        }
+    }
+    Ok(Some(Arc::new(vectors)))
+}
+
+/// Reloads the vocabulary and re-tags the whole library with it in the background, which is
+/// needed after the vocabulary changes. Tags which were assigned manually are kept.
+pub async fn rescore(
+    data: Data<Arc<SQLiteDatabase>>,
+    files: Data<VocabularyFiles>,
+    user: User,
+) -> HttpResponse {
+    if let Err(response) = user.require_admin() {
+        return response;
+    }
+    let labels = match load(&data, &files).await {
+        Ok(labels) => labels,
+        Err(e) => {
+            log::warn!("{:?}", e);
+            return HttpResponse::InternalServerError().body("vocabulary could not be loaded");
+        }
+    };
+    let label_count = labels.as_ref().map_or(0, |labels| labels.labels.len());
+    data.set_label_vectors(labels);
+
+    let db = data.get_ref().clone();
+    actix_web::rt::spawn(async move {
+        match db.rescore_library().await {
+            Ok(count) => log::info!("Re-tagged {} images", count),
+            Err(e) => log::warn!("Re-tagging images failed: {:?}", e),
+        }
+    });
+    HttpResponse::Accepted().json(serde_json::json!({ "labels": label_count }))
+}
@@ -9,8 +9,10 @@ use std::collections::HashMap;
 #[derive(Serialize)]
 pub struct WeaviateInput {
     class: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     vector: Option<Vec<f32>>,
-    properties: HashMap<String, String>,
+    properties: HashMap<String, Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     id: Option<Id>,
 }
 
@@ -29,8 +31,8 @@ impl WeaviateInput {
         self
     }
 
-    pub(crate) fn property(mut self, key: String, value: String) -> Self {
-        self.properties.insert(key, value);
+    pub(crate) fn property(mut self, key: String, value: impl Into<Value>) -> Self {
+        self.properties.insert(key, value.into());
         self
     }
 
@@ -51,13 +53,13 @@ impl WeaviateBatchInput {
     }
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Debug)]
 pub enum MultiOperator {
     And,
     Or,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Debug)]
 pub enum Operator {
     And,
     Or,
@@ -86,6 +88,21 @@ pub enum WhereValue {
     Number(f64),
 }
 
+impl WhereValue {
+    /// Renders the value as a GraphQL argument, such as `valueString: "id"`
+    fn to_graphql(&self) -> String {
+        // JSON string literals are valid GraphQL string literals
+        let quote = |value: &str| serde_json::to_string(value).unwrap_or_default();
+        match self {
+            WhereValue::Int(value) => format!("valueInt: {}", value),
+            WhereValue::Boolean(value) => format!("valueBoolean: {}", value),
+            WhereValue::String(value) => format!("valueString: {}", quote(value)),
+            WhereValue::Text(value) => format!("valueText: {}", quote(value)),
+            WhereValue::Number(value) => format!("valueNumber: {}", value),
+        }
+    }
+}
+
 /// where { operator: Or { operands: [ {path: ["id"], operator: "Equal", valueString: id }, .. ] } }
 
 #[derive(Serialize)]
@@ -103,6 +120,34 @@ pub enum WeaviateWhere {
     },
 }
 
+impl WeaviateWhere {
+    /// Renders the filter as the `where` argument of a GraphQL query. The REST API takes the
+    /// serialized filter instead.
+    pub(crate) fn to_graphql(&self) -> String {
+        match self {
+            WeaviateWhere::Single {
+                path,
+                operator,
+                value,
+            } => format!(
+                "{{path: {}, operator: {:?}, {}}}",
+                serde_json::to_string(path).unwrap_or_default(),
+                operator,
+                value.to_graphql()
+            ),
+            WeaviateWhere::Multiple { operator, operands } => format!(
+                "{{operator: {:?}, operands: [{}]}}",
+                operator,
+                operands
+                    .iter()
+                    .map(WeaviateWhere::to_graphql)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+}
+
 #[derive(Serialize)]
 pub struct WeaviateMatch {
     pub(crate) class: String,
@@ -151,6 +196,33 @@ pub struct VectorizerOutput {
     pub image_vectors: Vec<Vec<f32>>,
 }
 
+/// The outcome of adding one object of a batch
+#[derive(Deserialize)]
+pub struct WeaviateBatchResult {
+    pub id: Id,
+    pub result: Option<WeaviateBatchErrors>,
+}
+
+#[derive(Deserialize)]
+pub struct WeaviateBatchErrors {
+    pub errors: Option<Value>,
+}
+
+impl WeaviateBatchResult {
+    pub(crate) fn is_ok(&self) -> bool {
+        matches!(
+            &self.result,
+            None | Some(WeaviateBatchErrors { errors: None })
+        )
+    }
+}
+
+/// An object fetched through the REST API with `include=vector`
+#[derive(Deserialize)]
+pub struct WeaviateObject {
+    pub vector: Option<Vec<f32>>,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct QueryResult {
     pub data: Get,
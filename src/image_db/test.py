@@ -1,6 +1,11 @@
-import requests
+import os
 from pathlib import Path
 
+import requests
+
+# An API key issued through /admin/users/{name}/keys, or ADMIN_API_KEY
+HEADERS = {"Authorization": f"Bearer {os.environ['API_KEY']}"}
+
 if __name__ == '__main__':
     images = [(image.name, image.read_bytes()) for image in Path("./sample_images").iterdir() if image.is_file()]
-    print(requests.post("http://127.0.0.1:8081/upload_raw", files=images).json())
+    print(requests.post("http://127.0.0.1:8081/upload_raw", files=images, headers=HEADERS).json())